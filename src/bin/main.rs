@@ -1,5 +1,3 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -12,7 +10,7 @@ fn main() -> Result<(), YeeError> {
     let mut res: Vec<Light> = loop {
         let lights = client.find_lights(Duration::from_secs(1));
         // sometimes, it doesn't find anything, so rerun
-        if lights.len() == 0 {
+        if lights.is_empty() {
             println!("zero");
         } else {
             break lights;
@@ -44,14 +42,17 @@ fn main() -> Result<(), YeeError> {
         sleep(Duration::from_secs(4));
 
         println!("2700");
-        light.set_ct_abx(2700, Transition::sudden());
+        let result = light.set_ct_abx(2700, Transition::sudden());
+        println!("{:?}", result);
         sleep(Duration::from_secs(4));
 
         println!("6500");
-        light.set_ct_abx(6600, Transition::sudden());
+        let result = light.set_ct_abx(6600, Transition::sudden());
+        println!("{:?}", result);
         sleep(Duration::from_secs(4));
 
-        light.set_rgb(Rgb::new(30, 40, 50), Transition::Sudden);
+        let result = light.set_rgb(Rgb::new(30, 40, 50), Transition::Sudden);
+        println!("{:?}", result);
         sleep(Duration::from_secs(3));
         println!("rgb");
         let result = light.set_rgb(Rgb::new(240, 40, 180), Transition::Sudden);
@@ -61,23 +62,4 @@ fn main() -> Result<(), YeeError> {
         println!("{}", light.power());
         sleep(Duration::from_secs(4));
     }
-
-    // let bright = "{\"id\":23,\"method\":\"set_ct_abx\",\"params\":[2700,\"smooth\",400]}\r\n";
-    // let dark = "{\"id\":23,\"method\":\"set_ct_abx\",\"params\":[6500,\"smooth\",400]}\r\n";
-    // let mut ceiling_light = TcpStream::connect("192.168.2.24:55443")?;
-    // loop {
-    //     let mut buf = [0u8; 128];
-    //     ceiling_light.write(bright.as_bytes())?;
-    //     ceiling_light.read(&mut buf)?;
-    //     println!("{}", String::from_utf8(buf.to_vec()).unwrap());
-    //     sleep(Duration::from_secs(5));
-    //
-    //     let mut buf = [0u8; 128];
-    //     ceiling_light.write(dark.as_bytes())?;
-    //     ceiling_light.read(&mut buf)?;
-    //     println!("{}", String::from_utf8(buf.to_vec()).unwrap());
-    //     sleep(Duration::from_secs(5));
-    // }
-
-    Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,165 @@
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::err::YeeError;
+use crate::{parse_response, SEARCH_MSG};
+
+/// How long to wait when no interface has anything to receive.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What [`YeeClient::diagnose`](crate::YeeClient::diagnose) found on each local IPv4 interface.
+#[derive(Debug)]
+pub struct DiagnosticReport {
+    pub interfaces: Vec<InterfaceReport>,
+}
+
+/// The outcome of searching for lights from one local interface.
+#[derive(Debug)]
+pub struct InterfaceReport {
+    pub name: String,
+    pub addr: Ipv4Addr,
+    /// Whether the interface could join the multicast group. Lights answer searches directly,
+    /// so they may still answer if not.
+    pub joined_multicast: bool,
+    /// Why the search couldn't be sent from this interface.
+    pub error: Option<YeeError>,
+    /// Lights that answered the search, without duplicates.
+    pub replies: Vec<SocketAddr>,
+}
+
+impl DiagnosticReport {
+    /// The interfaces lights answered on, the ones to bind a [`YeeClient`](crate::YeeClient) to.
+    pub fn answered(&self) -> Vec<&InterfaceReport> {
+        self.interfaces.iter().filter(|i| !i.replies.is_empty()).collect()
+    }
+}
+
+/// One line per interface, e.g. `eth0 (192.168.1.2): 2 light(s) answered`.
+impl Display for DiagnosticReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.interfaces.is_empty() {
+            return writeln!(f, "no IPv4 interfaces found");
+        }
+        for interface in self.interfaces.iter() {
+            write!(f, "{} ({}): ", interface.name, interface.addr)?;
+            match &interface.error {
+                Some(e) => writeln!(f, "{}", e)?,
+                None => {
+                    match interface.replies.len() {
+                        0 => write!(f, "no lights answered")?,
+                        n => write!(f, "{} light(s) answered", n)?
+                    }
+                    if !interface.joined_multicast {
+                        write!(f, ", couldn't join multicast group")?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Search for lights at `multicast_addr` from every interface in `interfaces` at once, listening for `window`.
+pub(crate) fn diagnose(interfaces: Vec<(String, Ipv4Addr)>, multicast_addr: SocketAddrV4,
+                       window: Duration) -> DiagnosticReport {
+    let mut searches: Vec<(InterfaceReport, Option<UdpSocket>)> = interfaces.into_iter()
+        .map(|(name, addr)| {
+            let mut report = InterfaceReport { name, addr, joined_multicast: false, error: None, replies: vec![] };
+            match search_from(addr, multicast_addr) {
+                Ok((socket, joined_multicast)) => {
+                    report.joined_multicast = joined_multicast;
+                    (report, Some(socket))
+                }
+                Err(e) => {
+                    report.error = Some(e);
+                    (report, None)
+                }
+            }
+        })
+        .collect();
+
+    let deadline = Instant::now() + window;
+    while Instant::now() < deadline {
+        let mut received = false;
+        for (report, socket) in searches.iter_mut() {
+            let socket = match socket {
+                Some(socket) => socket,
+                None => continue
+            };
+            let mut buf = [0u8; 1024];
+            if let Ok((size, from)) = socket.recv_from(&mut buf) {
+                received = true;
+                if parse_response(&buf[..size]).is_ok() && !report.replies.contains(&from) {
+                    report.replies.push(from);
+                }
+            }
+        }
+        if !received {
+            sleep(POLL_INTERVAL);
+        }
+    }
+    DiagnosticReport { interfaces: searches.into_iter().map(|(report, _)| report).collect() }
+}
+
+/// Send a search from `addr`, returning the socket to listen on and whether it joined the multicast group.
+fn search_from(addr: Ipv4Addr, multicast_addr: SocketAddrV4) -> Result<(UdpSocket, bool), YeeError> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(addr, 0))?;
+    let joined_multicast = socket.join_multicast_v4(multicast_addr.ip(), &addr).is_ok();
+    socket.set_nonblocking(true)?;
+    socket.send_to(SEARCH_MSG.as_bytes(), multicast_addr)?;
+    Ok((socket, joined_multicast))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn report_replies_per_interface() -> anyhow::Result<()> {
+        // given
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41233);
+        let fake_light = UdpSocket::bind(fake_multicast_addr)?;
+        fake_light.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let light = thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (_, from) = fake_light.recv_from(&mut buf).unwrap();
+            let fake_msg = "HTTP/1.1 200 OK\r
+Location: yeelight://127.0.0.1:41233\r
+id: 0x12345abcde\r
+model: color\r
+fw_ver: 20\r
+support: get_prop set_power\r
+power: on\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: light_one\r\n";
+            fake_light.send_to(fake_msg.as_bytes(), from).unwrap();
+        });
+        // not a local address, so binding fails
+        let interfaces = vec![("lo".to_string(), Ipv4Addr::LOCALHOST), ("vpn0".to_string(), Ipv4Addr::new(192, 0, 2, 1))];
+
+        // when
+        let report = diagnose(interfaces, fake_multicast_addr, Duration::from_millis(300));
+        light.join().unwrap();
+
+        // then
+        assert_eq!(report.interfaces.len(), 2);
+        assert_eq!(report.interfaces[0].replies, vec![SocketAddr::V4(fake_multicast_addr)]);
+        assert!(report.interfaces[1].error.is_some());
+        assert_eq!(report.answered().len(), 1);
+        // the fake multicast address is a unicast one, so it can't be joined
+        assert!(!report.interfaces[0].joined_multicast);
+        assert!(report.to_string().starts_with("lo (127.0.0.1): 1 light(s) answered, couldn't join multicast group\n\
+            vpn0 (192.0.2.1): IoError"));
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
 use std::num::ParseIntError;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum YeeError {
@@ -9,7 +10,59 @@ pub enum YeeError {
     IoError { source: std::io::Error },
     MethodNotSupported { method_name: &'static str },
     InvalidValue { field_name: &'static str, value: String },
-    ChangeFailed { message: String },
+    ChangeFailed { code: BulbErrorCode, message: String },
+    InvalidResponse { message: String },
+    JsonError { source: serde_json::Error },
+    InvalidStep { index: usize, source: Box<YeeError> },
+    /// The light accepted the connection, but didn't answer within `timeout`.
+    Unresponsive { timeout: Duration },
+    /// The light accepted the change, but reading `field_name` back showed a different value.
+    NotApplied { field_name: &'static str, expected: String, actual: String },
+}
+
+/// Error codes sent by the light when a command fails.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BulbErrorCode {
+    /// Code -1 with a quota message: too many commands were sent in a short time.
+    QuotaExceeded,
+    /// Code -1: the light doesn't support the method.
+    MethodNotSupported,
+    /// Code -5001: a parameter was invalid or out of range.
+    ValueOutOfRange,
+    /// Code -5000: the light failed to execute the command, e.g. because it is off.
+    GeneralFailure,
+    Other(i32),
+}
+
+impl BulbErrorCode {
+    /// Map a code sent by the light, using the message to tell apart the errors sharing code -1.
+    pub fn from_response(code: i32, message: &str) -> BulbErrorCode {
+        match code {
+            -1 if message.contains("quota") => Self::QuotaExceeded,
+            -1 => Self::MethodNotSupported,
+            -5000 => Self::GeneralFailure,
+            -5001 => Self::ValueOutOfRange,
+            other => Self::Other(other)
+        }
+    }
+}
+
+impl YeeError {
+    /// Whether the error may not happen again if the command is retried: connection failures
+    /// (reset, aborted, broken pipe, timed out, closed) and [`BulbErrorCode::QuotaExceeded`].
+    ///
+    /// Errors in the command itself, like [`YeeError::MethodNotSupported`] or [`YeeError::InvalidValue`],
+    /// and other errors sent by the light are never transient.
+    pub fn is_transient(&self) -> bool {
+        use std::io::ErrorKind;
+        match self {
+            YeeError::IoError { source } => matches!(source.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut | ErrorKind::UnexpectedEof),
+            YeeError::ChangeFailed { code: BulbErrorCode::QuotaExceeded, .. } => true,
+            _ => false
+        }
+    }
 }
 
 impl Display for YeeError {
@@ -20,14 +73,25 @@ impl Display for YeeError {
             YeeError::IoError { .. } => "IoError",
             YeeError::MethodNotSupported { .. } => "MethodNotSupported",
             YeeError::InvalidValue { .. } => "InvalidValue",
-            YeeError::ChangeFailed { .. } => "ChangeFailed"
+            YeeError::ChangeFailed { .. } => "ChangeFailed",
+            YeeError::InvalidResponse { .. } => "InvalidResponse",
+            YeeError::JsonError { .. } => "JsonError",
+            YeeError::InvalidStep { .. } => "InvalidStep",
+            YeeError::Unresponsive { .. } => "Unresponsive",
+            YeeError::NotApplied { .. } => "NotApplied"
         }, match self {
             YeeError::ParseFieldFailed { field_name, .. } => format!("failed to parse required field: {}", field_name),
             YeeError::FieldNotFound { field_name } => format!("did not find the required field: {}", field_name),
             YeeError::IoError { source } => format!("IO error: {}", source),
             YeeError::MethodNotSupported { method_name } => format!("cannot use method: {}", method_name),
             YeeError::InvalidValue { field_name, value } => format!("invalid value for {}: {}", field_name, value),
-            YeeError::ChangeFailed { message } => format!("changing param failed: {}", message)
+            YeeError::ChangeFailed { code, message } => format!("changing param failed: {} ({:?})", message, code),
+            YeeError::InvalidResponse { message } => format!("invalid response from light: {}", message),
+            YeeError::JsonError { source } => format!("invalid request: {}", source),
+            YeeError::InvalidStep { index, source } => format!("invalid step {}: {}", index, source),
+            YeeError::Unresponsive { timeout } => format!("light didn't answer within {:?}", timeout),
+            YeeError::NotApplied { field_name, expected, actual } =>
+                format!("set {} to {} but light has {}", field_name, expected, actual)
         })
     }
 }
@@ -37,11 +101,43 @@ impl Error for YeeError {
         match self {
             YeeError::ParseFieldFailed { source, .. } => source.as_ref().map(|v| v as _),
             YeeError::IoError { source } => Some(source),
+            YeeError::JsonError { source } => Some(source),
+            YeeError::InvalidStep { source, .. } => Some(source.as_ref()),
             _ => None
         }
     }
 }
 
+/// Compares the fields of each variant, except for the sources that can't be compared directly:
+/// `IoError`s are equal if their [`kind`](std::io::Error::kind) and message are, and `JsonError`s if their
+/// [`classify`](serde_json::Error::classify) and message are.
+impl PartialEq for YeeError {
+    fn eq(&self, other: &Self) -> bool {
+        use YeeError::*;
+        match (self, other) {
+            (ParseFieldFailed { field_name: a, source: a_source }, ParseFieldFailed { field_name: b, source: b_source }) =>
+                a == b && a_source == b_source,
+            (FieldNotFound { field_name: a }, FieldNotFound { field_name: b }) => a == b,
+            (IoError { source: a }, IoError { source: b }) => a.kind() == b.kind() && a.to_string() == b.to_string(),
+            (MethodNotSupported { method_name: a }, MethodNotSupported { method_name: b }) => a == b,
+            (InvalidValue { field_name: a, value: a_value }, InvalidValue { field_name: b, value: b_value }) =>
+                a == b && a_value == b_value,
+            (ChangeFailed { code: a, message: a_message }, ChangeFailed { code: b, message: b_message }) =>
+                a == b && a_message == b_message,
+            (InvalidResponse { message: a }, InvalidResponse { message: b }) => a == b,
+            (JsonError { source: a }, JsonError { source: b }) =>
+                a.classify() == b.classify() && a.to_string() == b.to_string(),
+            (InvalidStep { index: a, source: a_source }, InvalidStep { index: b, source: b_source }) =>
+                a == b && a_source == b_source,
+            (Unresponsive { timeout: a }, Unresponsive { timeout: b }) => a == b,
+            (NotApplied { field_name: a, expected: a_expected, actual: a_actual },
+                NotApplied { field_name: b, expected: b_expected, actual: b_actual }) =>
+                a == b && a_expected == b_expected && a_actual == b_actual,
+            _ => false
+        }
+    }
+}
+
 impl From<std::io::Error> for YeeError {
     fn from(e: std::io::Error) -> Self {
         YeeError::IoError { source: e }
@@ -50,6 +146,69 @@ impl From<std::io::Error> for YeeError {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {}
+
+    #[test]
+    fn classify_transient_errors() {
+        // given
+        let reset = YeeError::IoError { source: std::io::Error::from(std::io::ErrorKind::ConnectionReset) };
+        let quota = YeeError::ChangeFailed { code: BulbErrorCode::QuotaExceeded, message: String::new() };
+        let refused = YeeError::IoError { source: std::io::Error::from(std::io::ErrorKind::ConnectionRefused) };
+        let unsupported = YeeError::MethodNotSupported { method_name: "set_rgb" };
+        let failed = YeeError::ChangeFailed { code: BulbErrorCode::GeneralFailure, message: String::new() };
+
+        // then
+        assert!(reset.is_transient());
+        assert!(quota.is_transient());
+        assert!(!refused.is_transient());
+        assert!(!unsupported.is_transient());
+        assert!(!failed.is_transient());
+    }
+
+    #[test]
+    fn compare_errors() {
+        // given
+        let unsupported = YeeError::MethodNotSupported { method_name: "set_hsv" };
+        let reset = || YeeError::IoError { source: std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset") };
+        let refused = YeeError::IoError { source: std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "reset") };
+        let parse_failed = |s: &str| YeeError::ParseFieldFailed { field_name: "bright", source: s.parse::<u8>().err() };
+
+        // then
+        assert_eq!(unsupported, YeeError::MethodNotSupported { method_name: "set_hsv" });
+        assert_ne!(unsupported, YeeError::MethodNotSupported { method_name: "set_rgb" });
+        assert_eq!(reset(), reset());
+        assert_ne!(reset(), refused);
+        assert_eq!(parse_failed("abc"), parse_failed("xyz"));
+        assert_ne!(parse_failed("abc"), parse_failed("300"));
+        assert_ne!(unsupported, YeeError::FieldNotFound { field_name: "set_hsv" });
+    }
+
+    #[test]
+    fn map_bulb_error_codes() {
+        // given
+        let responses = [
+            (-1, "client quota exceeded"),
+            (-1, "unsupported method"),
+            (-5000, "general error"),
+            (-5001, "invalid params"),
+            (-42, "???"),
+        ];
+
+        // when
+        let codes: Vec<BulbErrorCode> = responses.iter()
+            .map(|(code, message)| BulbErrorCode::from_response(*code, message))
+            .collect();
+
+        // then
+        assert_eq!(codes, vec![
+            BulbErrorCode::QuotaExceeded,
+            BulbErrorCode::MethodNotSupported,
+            BulbErrorCode::GeneralFailure,
+            BulbErrorCode::ValueOutOfRange,
+            BulbErrorCode::Other(-42),
+        ]);
+    }
 }
@@ -1,12 +1,19 @@
 use std::fmt::{self, Display, Formatter};
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 
+use bitflags::bitflags;
+#[cfg(any(test, feature = "serde"))]
+use serde::{Deserialize, Serialize};
+
 pub use crate::req::Transition;
 use crate::err::YeeError;
 
 const HEX_FFFFFF: u32 = 16777215;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+#[cfg_attr(any(test, feature = "serde"), serde(rename_all = "lowercase"))]
 pub enum PowerStatus {
     On,
     Off,
@@ -44,6 +51,8 @@ impl FromStr for PowerStatus {
 
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+#[cfg_attr(any(test, feature = "serde"), serde(rename_all = "snake_case"))]
 pub enum ColorMode {
     Color,
     ColorTemperature,
@@ -74,7 +83,96 @@ impl FromStr for ColorMode {
 }
 
 
+/// Which light source of a moonlight-capable lamp is active.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ActiveMode {
+    Daylight,
+    Moonlight,
+}
+
+impl Display for ActiveMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "({})", match self {
+            Self::Daylight => "daylight, id=0",
+            Self::Moonlight => "moonlight, id=1"
+        })
+    }
+}
+
+impl FromStr for ActiveMode {
+    type Err = YeeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(ActiveMode::Daylight),
+            "1" => Ok(ActiveMode::Moonlight),
+            _ => Err(YeeError::ParseFieldFailed { field_name: "active_mode", source: None })
+        }
+    }
+}
+
+/// The `Server` header of a discovery response, e.g. `POSIX UPnP/1.0 YGLC/1`,
+/// which identifies the firmware generation together with the firmware version.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ServerInfo {
+    /// The platform, e.g. `POSIX`.
+    pub vendor: String,
+    /// The UPnP version, e.g. `1.0`.
+    pub upnp_version: String,
+    /// The revision of the Yeelight protocol stack.
+    pub yglc_revision: u32,
+}
+
+impl FromStr for ServerInfo {
+    type Err = YeeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || YeeError::ParseFieldFailed { field_name: "server", source: None };
+        let mut parts = s.split_whitespace();
+        let vendor = parts.next().ok_or_else(err)?.to_string();
+        let upnp_version = parts.next().and_then(|p| p.strip_prefix("UPnP/")).ok_or_else(err)?.to_string();
+        let yglc_revision = parts.next()
+            .and_then(|p| p.strip_prefix("YGLC/"))
+            .ok_or_else(err)?
+            .parse::<u32>()
+            .map_err(|e| YeeError::ParseFieldFailed { field_name: "server", source: Some(e) })?;
+        Ok(ServerInfo { vendor, upnp_version, yglc_revision })
+    }
+}
+
+bitflags! {
+    /// The groups of methods a light supports, derived from its `support` list, for quick checks
+    /// and intersections, e.g. with [`supported_capabilities`](crate::light::supported_capabilities).
+    pub struct Capabilities: u16 {
+        const SET_RGB = 1 << 0;
+        const SET_CT = 1 << 1;
+        const SET_HSV = 1 << 2;
+        const COLOR_FLOW = 1 << 3;
+        const MUSIC = 1 << 4;
+        const CRON = 1 << 5;
+        /// Any `bg_` method, for lights with a background light.
+        const BACKGROUND = 1 << 6;
+    }
+}
+
+impl Capabilities {
+    /// The flags covered by `methods`, ignoring methods without a flag.
+    pub fn from_methods<'a, I: IntoIterator<Item=&'a str>>(methods: I) -> Capabilities {
+        methods.into_iter().fold(Capabilities::empty(), |caps, method| caps | match method {
+            "set_rgb" => Capabilities::SET_RGB,
+            "set_ct_abx" => Capabilities::SET_CT,
+            "set_hsv" => Capabilities::SET_HSV,
+            "start_cf" => Capabilities::COLOR_FLOW,
+            "set_music" => Capabilities::MUSIC,
+            "cron_add" => Capabilities::CRON,
+            bg if bg.starts_with("bg_") => Capabilities::BACKGROUND,
+            _ => Capabilities::empty(),
+        })
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
 pub struct Rgb {
     pub red: u8,
     pub blue: u8,
@@ -90,6 +188,77 @@ impl Rgb {
         Rgb { red, green, blue }
     }
 
+    /// Convert a linear color with channels in 0.0-1.0 to sRGB, clamping out of range channels.
+    pub fn from_linear(red: f32, green: f32, blue: f32) -> Self {
+        let encode = |c: f32| {
+            let c = c.clamp(0.0, 1.0);
+            let srgb = if c <= 0.0031308 { 12.92 * c } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+            (srgb * 255.0).round() as u8
+        };
+        Rgb::new(encode(red), encode(green), encode(blue))
+    }
+
+    /// Convert a CIE 1931 xy chromaticity at a `brightness` of 0-255 to sRGB, e.g. a color from Philips Hue.
+    ///
+    /// Colors outside the sRGB gamut are clamped to it, and scaled down so no channel exceeds full brightness.
+    /// Coordinates are clamped to 0.0-1.0, and a `y` of 0 is black.
+    pub fn from_xy(x: f32, y: f32, brightness: u8) -> Self {
+        let (x, y) = (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0));
+        if y == 0.0 {
+            return Rgb::empty();
+        }
+        let luminance = brightness as f32 / 255.0;
+        let big_x = luminance / y * x;
+        let big_z = luminance / y * (1.0 - x - y);
+        let linear = [
+            3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+            -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+            0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+        ];
+        let linear = linear.map(|c| c.max(0.0));
+        let max = linear.iter().cloned().fold(1.0, f32::max);
+        Rgb::from_linear(linear[0] / max, linear[1] / max, linear[2] / max)
+    }
+
+    /// Convert a hue in degrees and saturation in percent to a fully bright color.
+    pub fn from_hsv(hue: u16, sat: u8) -> Self {
+        let hue = (hue % 360) as f32 / 60.0;
+        let sat = sat.min(100) as f32 / 100.0;
+        let fade = |n: f32| {
+            let k = (n + hue) % 6.0;
+            let c = 1.0 - sat * k.min(4.0 - k).clamp(0.0, 1.0);
+            (c * 255.0).round() as u8
+        };
+        Rgb::new(fade(5.0), fade(3.0), fade(1.0))
+    }
+
+    /// The hue in degrees and saturation in percent of this color, the inverse of [`from_hsv`](Self::from_hsv)
+    /// for fully bright colors.
+    pub fn to_hsv(&self) -> (u16, u8) {
+        let (red, green, blue) = (self.red as f32, self.green as f32, self.blue as f32);
+        let max = red.max(green).max(blue);
+        let delta = max - red.min(green).min(blue);
+        if delta == 0.0 {
+            return (0, 0);
+        }
+        let sector = if max == red {
+            ((green - blue) / delta).rem_euclid(6.0)
+        } else if max == green {
+            (blue - red) / delta + 2.0
+        } else {
+            (red - green) / delta + 4.0
+        };
+        ((sector * 60.0).round() as u16 % 360, (delta / max * 100.0).round() as u8)
+    }
+
+    /// The color on the opposite side of the color wheel, with the same saturation and lightness.
+    pub fn complement(&self) -> Rgb {
+        let max = self.red.max(self.green).max(self.blue) as u16;
+        let min = self.red.min(self.green).min(self.blue) as u16;
+        let opposite = |c: u8| (max + min - c as u16) as u8;
+        Rgb::new(opposite(self.red), opposite(self.green), opposite(self.blue))
+    }
+
     pub fn get_num(&self) -> u32 {
         self.red as u32 * 65536 + self.green as u32 * 256 + self.blue as u32
     }
@@ -113,15 +282,123 @@ impl FromStr for Rgb {
         if !(0..=HEX_FFFFFF).contains(&val) {
             Err(YeeError::ParseFieldFailed { field_name: "rgb", source: None })
         } else {
-            // https://math.stackexchange.com/questions/1635999/algorithm-to-convert-integer-to-3-variables-rgb
-            let blue = (val % 256) as u8;
-            let green = (((val - blue as u32) / 256) % 256) as u8;
-            let red = ((((val - blue as u32) / 256) - green as u32) / 256) as u8;
-            Ok(Rgb { red, green, blue })
+            Ok(Rgb::from(val))
         }
     }
 }
 
+/// Decode a `0xRRGGBB` value, ignoring anything above `0xFFFFFF`.
+impl From<u32> for Rgb {
+    fn from(val: u32) -> Self {
+        let val = val & HEX_FFFFFF;
+        // https://math.stackexchange.com/questions/1635999/algorithm-to-convert-integer-to-3-variables-rgb
+        let blue = (val % 256) as u8;
+        let green = (((val - blue as u32) / 256) % 256) as u8;
+        let red = ((((val - blue as u32) / 256) - green as u32) / 256) as u8;
+        Rgb { red, green, blue }
+    }
+}
+
+/// Color temperatures accepted by `set_ct_abx`.
+// SPEC IS WRONG: temperature bounds should be 2700-6500
+pub const CT_RANGE: RangeInclusive<u16> = 2700..=6500;
+/// Color temperatures accepted by `set_ct_abx` while a lamp is in moonlight mode, the widest of any mode,
+/// so also the ones accepted where the mode isn't known, e.g. in a flow.
+pub const MOONLIGHT_CT_RANGE: RangeInclusive<u16> = 1700..=6500;
+
+/// A color in any of the modes a light can be set to.
+///
+/// Serializes as `{"rgb": {"red": 255, "green": 0, "blue": 0}}`, `{"ct": 2700}` or `{"hsv": {"hue": 120, "sat": 50}}`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+#[cfg_attr(any(test, feature = "serde"), serde(rename_all = "lowercase"))]
+pub enum Color {
+    Rgb(Rgb),
+    /// Color temperature in kelvin.
+    Ct(u16),
+    Hsv { hue: u16, sat: u8 },
+}
+
+/// Approximate the color of a blackbody at `kelvin`, clamped to 1000-40000K, e.g. to preview a color temperature.
+///
+/// Uses [Tanner Helland's approximation](https://tannerhelland.com/2012/09/18/convert-temperature-rgb-algorithm-code.html).
+pub fn kelvin_to_rgb(kelvin: u16) -> Rgb {
+    let temp = kelvin.clamp(1000, 40000) as f64 / 100.0;
+    let channel = |v: f64| v.clamp(0.0, 255.0) as u8;
+
+    let red = if temp <= 66.0 {
+        255
+    } else {
+        channel(329.698727446 * (temp - 60.0).powf(-0.1332047592))
+    };
+    let green = if temp <= 66.0 {
+        channel(99.4708025861 * temp.ln() - 161.1195681661)
+    } else {
+        channel(288.1221695283 * (temp - 60.0).powf(-0.0755148492))
+    };
+    let blue = if temp >= 66.0 {
+        255
+    } else if temp <= 19.0 {
+        0
+    } else {
+        channel(138.5177312231 * (temp - 10.0).ln() - 305.0447927307)
+    };
+    Rgb::new(red, green, blue)
+}
+
+
+/// Colors [`parse_color`] accepts by name.
+const NAMED_COLORS: [(&str, Color); 12] = [
+    ("red", Color::Rgb(Rgb { red: 255, green: 0, blue: 0 })),
+    ("orange", Color::Rgb(Rgb { red: 255, green: 136, blue: 0 })),
+    ("yellow", Color::Rgb(Rgb { red: 255, green: 255, blue: 0 })),
+    ("green", Color::Rgb(Rgb { red: 0, green: 255, blue: 0 })),
+    ("cyan", Color::Rgb(Rgb { red: 0, green: 255, blue: 255 })),
+    ("blue", Color::Rgb(Rgb { red: 0, green: 0, blue: 255 })),
+    ("purple", Color::Rgb(Rgb { red: 128, green: 0, blue: 255 })),
+    ("magenta", Color::Rgb(Rgb { red: 255, green: 0, blue: 255 })),
+    ("pink", Color::Rgb(Rgb { red: 255, green: 105, blue: 180 })),
+    ("warmwhite", Color::Ct(2700)),
+    ("white", Color::Ct(4000)),
+    ("coolwhite", Color::Ct(6500)),
+];
+
+/// Parse a color written by a user, e.g. in a config file or on the command line, as one of
+/// `#ff8800` or `ff8800`, a name like `warmwhite`, `ct:3500` or `hsv:120,80`.
+///
+/// Names and prefixes are case-insensitive. Values outside what lights accept are rejected with
+/// [`YeeError::InvalidValue`] naming the part that is wrong.
+pub fn parse_color(s: &str) -> Result<Color, YeeError> {
+    let s = s.trim().to_ascii_lowercase();
+    let invalid = |field_name: &'static str, value: &str| {
+        YeeError::InvalidValue { field_name, value: value.to_string() }
+    };
+    let number = |field_name: &'static str, value: &str| value.trim().parse::<u16>()
+        .map_err(|e| YeeError::ParseFieldFailed { field_name, source: Some(e) });
+    if let Some((_, color)) = NAMED_COLORS.iter().find(|(name, _)| *name == s) {
+        return Ok(*color);
+    }
+    if let Some(ct) = s.strip_prefix("ct:") {
+        let ct = number("ct", ct)?;
+        return if MOONLIGHT_CT_RANGE.contains(&ct) { Ok(Color::Ct(ct)) } else { Err(invalid("ct", &ct.to_string())) };
+    }
+    if let Some(hsv) = s.strip_prefix("hsv:") {
+        let (hue, sat) = hsv.split_once(',').ok_or_else(|| invalid("hsv", hsv))?;
+        let (hue, sat) = (number("hue", hue)?, number("sat", sat)?);
+        if hue > 359 {
+            return Err(invalid("hue", &hue.to_string()));
+        } else if sat > 100 {
+            return Err(invalid("sat", &sat.to_string()));
+        }
+        return Ok(Color::Hsv { hue, sat: sat as u8 });
+    }
+    let hex = s.strip_prefix('#').unwrap_or(&s);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid("color", &s));
+    }
+    // six hex digits always fit
+    Ok(Color::Rgb(Rgb::from(u32::from_str_radix(hex, 16).unwrap())))
+}
 
 #[cfg(test)]
 mod tests {
@@ -155,6 +432,37 @@ mod tests {
         assert!(incorrect_parsed.is_err());
     }
 
+    #[test]
+    fn parse_server_info() -> anyhow::Result<()> {
+        // given
+        let server = "POSIX UPnP/1.0 YGLC/1";
+        let odd = "POSIX UPnP/1.0";
+
+        // when
+        let parsed = server.parse::<ServerInfo>()?;
+        let odd_parsed = odd.parse::<ServerInfo>();
+
+        // then
+        assert_eq!(parsed.vendor, "POSIX");
+        assert_eq!(parsed.upnp_version, "1.0");
+        assert_eq!(parsed.yglc_revision, 1);
+        assert!(odd_parsed.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn capabilities_from_methods() {
+        // given
+        let methods = ["get_prop", "set_rgb", "set_ct_abx", "set_music", "bg_set_rgb", "bg_toggle"];
+
+        // when
+        let caps = Capabilities::from_methods(methods.iter().copied());
+
+        // then
+        assert_eq!(caps, Capabilities::SET_RGB | Capabilities::SET_CT | Capabilities::MUSIC | Capabilities::BACKGROUND);
+        assert!(Capabilities::from_methods(std::iter::empty()).is_empty());
+    }
+
     #[test]
     fn correct_parse_color_mode() -> anyhow::Result<()> {
         // given
@@ -187,6 +495,34 @@ mod tests {
         assert!(incorrect_parsed.is_err());
     }
 
+    #[test]
+    fn correct_parse_active_mode() -> anyhow::Result<()> {
+        // given
+        let correct_1 = "0";
+        let correct_2 = "1";
+
+        // when
+        let parsed_1 = correct_1.parse::<ActiveMode>()?;
+        let parsed_2 = correct_2.parse::<ActiveMode>()?;
+
+        // then
+        assert_eq!(parsed_1, ActiveMode::Daylight);
+        assert_eq!(parsed_2, ActiveMode::Moonlight);
+        Ok(())
+    }
+
+    #[test]
+    fn incorrect_parse_active_mode() {
+        // given
+        let incorrect = "2";
+
+        // when
+        let incorrect_parsed = incorrect.parse::<ActiveMode>();
+
+        // then
+        assert!(incorrect_parsed.is_err());
+    }
+
     #[test]
     fn correct_parse_rgb() -> anyhow::Result<()> {
         // given
@@ -232,4 +568,172 @@ mod tests {
         assert!(parsed_2.is_err());
         assert!(parsed_3.is_err());
     }
+
+    #[test]
+    fn rgb_from_hex_literal() {
+        // given
+        let orange = 0xFF8800;
+        let too_big = 0x1FF8800;
+
+        // when
+        let parsed = Rgb::from(orange);
+        let masked = Rgb::from(too_big);
+
+        // then
+        assert_eq!(parsed, Rgb::new(255, 136, 0));
+        assert_eq!(masked, parsed);
+    }
+
+    #[test]
+    fn correct_rgb_from_linear() {
+        // given
+        let black = (0.0, 0.0, 0.0);
+        let mixed = (0.5, 1.0, 0.0);
+        let out_of_range = (-1.0, 2.0, 0.5);
+
+        // when
+        let black = Rgb::from_linear(black.0, black.1, black.2);
+        let mixed = Rgb::from_linear(mixed.0, mixed.1, mixed.2);
+        let out_of_range = Rgb::from_linear(out_of_range.0, out_of_range.1, out_of_range.2);
+
+        // then
+        assert_eq!(black, Rgb::new(0, 0, 0));
+        assert_eq!(mixed, Rgb::new(188, 255, 0));
+        assert_eq!(out_of_range, Rgb::new(0, 255, 188));
+    }
+
+    #[test]
+    fn correct_rgb_from_xy() {
+        // given
+        let white = (0.3127, 0.3290);
+        let red = (0.64, 0.33);
+        let green = (0.30, 0.60);
+        let blue = (0.15, 0.06);
+
+        // when
+        let white = Rgb::from_xy(white.0, white.1, 255);
+        let dim_white = Rgb::from_xy(0.3127, 0.3290, 55);
+        let red = Rgb::from_xy(red.0, red.1, 255);
+        let green = Rgb::from_xy(green.0, green.1, 255);
+        let blue = Rgb::from_xy(blue.0, blue.1, 255);
+        let black = Rgb::from_xy(0.3, 0.0, 255);
+
+        // then
+        assert_eq!(white, Rgb::new(255, 255, 255));
+        assert_eq!(dim_white, Rgb::new(128, 128, 128));
+        assert_eq!(red, Rgb::new(255, 0, 0));
+        assert_eq!(green, Rgb::new(0, 255, 0));
+        assert_eq!(blue, Rgb::new(0, 0, 255));
+        assert_eq!(black, Rgb::empty());
+    }
+
+    #[test]
+    fn correct_rgb_from_hsv() {
+        // given
+        let red = (0, 100);
+        let cyan = (180, 100);
+        let pale_blue = (240, 50);
+        let white = (123, 0);
+
+        // when
+        let red = Rgb::from_hsv(red.0, red.1);
+        let cyan = Rgb::from_hsv(cyan.0, cyan.1);
+        let pale_blue = Rgb::from_hsv(pale_blue.0, pale_blue.1);
+        let white = Rgb::from_hsv(white.0, white.1);
+
+        // then
+        assert_eq!(red, Rgb::new(255, 0, 0));
+        assert_eq!(cyan, Rgb::new(0, 255, 255));
+        assert_eq!(pale_blue, Rgb::new(128, 128, 255));
+        assert_eq!(white, Rgb::new(255, 255, 255));
+    }
+
+    #[test]
+    fn correct_rgb_to_hsv() {
+        // given
+        let colors = [(0, 100), (180, 100), (240, 50), (300, 25), (0, 0)];
+
+        // when
+        let round_trip: Vec<(u16, u8)> = colors.iter().map(|(hue, sat)| Rgb::from_hsv(*hue, *sat).to_hsv()).collect();
+
+        // then
+        assert_eq!(round_trip, colors);
+        assert_eq!(Rgb::new(100, 50, 50).to_hsv(), (0, 50));
+    }
+
+    #[test]
+    fn parse_user_colors() {
+        // given
+        let inputs = ["#ff8800", "FF8800", "WarmWhite", " ct:3500 ", "hsv:120, 80"];
+        let invalid = ["#ff880", "chartreuse", "ct:9000", "ct:warm", "hsv:400,50", "hsv:120,101", "hsv:120"];
+
+        // when
+        let parsed: Vec<Color> = inputs.iter().map(|s| parse_color(s).unwrap()).collect();
+        let errors: Vec<YeeError> = invalid.iter().map(|s| parse_color(s).unwrap_err()).collect();
+
+        // then
+        assert_eq!(parsed, vec![
+            Color::Rgb(Rgb::new(255, 136, 0)),
+            Color::Rgb(Rgb::new(255, 136, 0)),
+            Color::Ct(2700),
+            Color::Ct(3500),
+            Color::Hsv { hue: 120, sat: 80 },
+        ]);
+        let fields: Vec<&str> = errors.iter().map(|e| match e {
+            YeeError::InvalidValue { field_name, .. } | YeeError::ParseFieldFailed { field_name, .. } => *field_name,
+            _ => "",
+        }).collect();
+        assert_eq!(fields, vec!["color", "color", "ct", "ct", "hue", "sat", "hsv"]);
+    }
+
+    #[test]
+    fn correct_rgb_complement() {
+        // given
+        let red = Rgb::new(255, 0, 0);
+        let orange = Rgb::new(200, 100, 50);
+        let gray = Rgb::new(80, 80, 80);
+
+        // when
+        let red = red.complement();
+        let orange = orange.complement();
+        let gray = gray.complement();
+
+        // then
+        assert_eq!(red, Rgb::new(0, 255, 255));
+        assert_eq!(orange, Rgb::new(50, 150, 200));
+        assert_eq!(gray, Rgb::new(80, 80, 80));
+    }
+
+    #[test]
+    fn correct_kelvin_to_rgb() {
+        // given
+        let warm = 2700;
+        let daylight = 6500;
+        let cool = 10000;
+
+        // when
+        let warm_rgb = kelvin_to_rgb(warm);
+        let daylight_rgb = kelvin_to_rgb(daylight);
+        let cool_rgb = kelvin_to_rgb(cool);
+
+        // then
+        assert_eq!(warm_rgb, Rgb::new(255, 166, 87));
+        assert_eq!(daylight_rgb, Rgb::new(255, 254, 250));
+        assert_eq!(cool_rgb, Rgb::new(201, 218, 255));
+    }
+
+    #[test]
+    fn kelvin_to_rgb_clamps_range() {
+        // given
+        let too_low = 500;
+        let too_high = u16::MAX;
+
+        // when
+        let low_rgb = kelvin_to_rgb(too_low);
+        let high_rgb = kelvin_to_rgb(too_high);
+
+        // then
+        assert_eq!(low_rgb, kelvin_to_rgb(1000));
+        assert_eq!(high_rgb, kelvin_to_rgb(40000));
+    }
 }
@@ -0,0 +1,244 @@
+use std::iter::FromIterator;
+
+use crate::err::YeeError;
+use crate::fields::{Color, PowerStatus};
+use crate::light::Light;
+use crate::req::{Req, Transition};
+
+/// A collection of [`Light`]s that are controlled together, e.g. all the lights in a room.
+#[derive(Debug)]
+pub struct LightGroup {
+    lights: Vec<Light>,
+}
+
+/// The outcome of a group operation for each [`Light`] in a [`LightGroup`], in the order of the group.
+#[derive(Debug)]
+pub struct GroupResult {
+    results: Vec<(String, Result<(), YeeError>)>,
+}
+
+impl LightGroup {
+    pub fn new(lights: Vec<Light>) -> LightGroup {
+        LightGroup { lights }
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    pub fn lights_mut(&mut self) -> &mut [Light] {
+        &mut self.lights
+    }
+
+    pub fn into_lights(self) -> Vec<Light> {
+        self.lights
+    }
+
+    /// Set the power of every light, continuing past lights that fail.
+    pub fn set_power_all(&mut self, power: PowerStatus, transition: Transition) -> GroupResult {
+        self.lights.iter_mut()
+            .map(|light| (light.id().to_string(), light.set_power(power, transition)))
+            .collect()
+    }
+
+    /// Set every light to `color` at as close to the same time as possible.
+    ///
+    /// All the requests are validated and sent before any response is read,
+    /// instead of waiting for each light in turn.
+    pub fn set_color_all(&mut self, color: Color, transition: Transition) -> GroupResult {
+        self.send_all(|light| light.color_req(color, transition), |light| {
+            light.cache_color(color);
+            light.track_transition(transition);
+        })
+    }
+
+    /// Turn every light off at as close to the same time as possible, like [`set_color_all`](Self::set_color_all),
+    /// e.g. to turn off a whole home quickly.
+    pub fn all_off(&mut self, transition: Transition) -> GroupResult {
+        self.set_power_at_once(PowerStatus::Off, transition)
+    }
+
+    /// Turn every light on at as close to the same time as possible, like [`set_color_all`](Self::set_color_all).
+    pub fn all_on(&mut self, transition: Transition) -> GroupResult {
+        self.set_power_at_once(PowerStatus::On, transition)
+    }
+
+    fn set_power_at_once(&mut self, power: PowerStatus, transition: Transition) -> GroupResult {
+        self.send_all(|light| light.power_req(power, transition), |light| {
+            light.cache_power(power);
+            light.track_transition(transition);
+        })
+    }
+
+    /// Send the request made by `req` to every light, then read every response,
+    /// calling `accepted` on the lights that succeeded.
+    fn send_all<R, A>(&mut self, req: R, accepted: A) -> GroupResult
+        where R: Fn(&Light) -> Result<Req, YeeError>, A: Fn(&mut Light) {
+        let sent: Vec<Result<u16, YeeError>> = self.lights.iter_mut()
+            .map(|light| {
+                let req = req(light)?;
+                light.write_req(&req)?;
+                Ok(req.id)
+            })
+            .collect();
+        self.lights.iter_mut()
+            .zip(sent)
+            .map(|(light, sent)| {
+                let result = sent
+                    .and_then(|id| light.read_result(id))
+                    .map(|_| accepted(light));
+                (light.id().to_string(), result)
+            })
+            .collect()
+    }
+}
+
+impl GroupResult {
+    /// Whether the operation succeeded on every light.
+    pub fn all_ok(&self) -> bool {
+        self.results.iter().all(|(_, r)| r.is_ok())
+    }
+
+    /// The ids and errors of the lights the operation failed on.
+    pub fn errors(&self) -> Vec<(&str, &YeeError)> {
+        self.results.iter()
+            .filter_map(|(id, r)| r.as_ref().err().map(|e| (id.as_str(), e)))
+            .collect()
+    }
+
+    /// The id and outcome for every light.
+    pub fn results(&self) -> &[(String, Result<(), YeeError>)] {
+        &self.results
+    }
+
+    pub fn into_results(self) -> Vec<(String, Result<(), YeeError>)> {
+        self.results
+    }
+}
+
+impl FromIterator<(String, Result<(), YeeError>)> for GroupResult {
+    fn from_iter<T: IntoIterator<Item=(String, Result<(), YeeError>)>>(iter: T) -> Self {
+        GroupResult { results: iter.into_iter().collect() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use serde_json::json;
+
+    use crate::fields::{ColorMode, Rgb};
+    use crate::light::tests::{fake_bulb, get_map};
+
+    use super::*;
+
+    #[test]
+    fn collect_group_errors() {
+        // given
+        let results = vec![
+            ("0x1".to_string(), Ok(())),
+            ("0x2".to_string(), Err(YeeError::MethodNotSupported { method_name: "set_power" })),
+        ];
+
+        // when
+        let result: GroupResult = results.into_iter().collect();
+
+        // then
+        assert!(!result.all_ok());
+        let errors = result.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "0x2");
+        assert_eq!(result.results().len(), 2);
+    }
+
+    #[test]
+    fn continue_past_failing_lights() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("support", "");
+        let first = Light::from_fields(&map)?;
+        map.insert("id", "0x5678");
+        let second = Light::from_fields(&map)?;
+        let mut group = LightGroup::new(vec![first, second]);
+
+        // when
+        let result = group.set_power_all(PowerStatus::Off, Transition::sudden());
+
+        // then
+        assert_eq!(result.errors().len(), 2);
+        assert_eq!(result.results()[0].0, "0x1234");
+        assert_eq!(result.results()[1].0, "0x5678");
+        Ok(())
+    }
+
+    #[test]
+    fn set_color_on_every_light() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("support", "set_rgb");
+        map.insert("Location", "yeelight://127.0.0.1:13480");
+        let bulb_1 = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13480), |_| json!(["ok"]))?;
+        let first = Light::from_fields(&map)?;
+        map.insert("id", "0x5678");
+        map.insert("Location", "yeelight://127.0.0.1:13481");
+        let bulb_2 = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13481), |_| json!(["ok"]))?;
+        let second = Light::from_fields(&map)?;
+        map.insert("id", "0x9abc");
+        map.insert("support", "");
+        let unsupported = Light::from_fields(&map)?;
+        let mut group = LightGroup::new(vec![first, second, unsupported]);
+        let color = Rgb::new(255, 136, 0);
+
+        // when
+        let result = group.set_color_all(Color::Rgb(color), Transition::sudden());
+
+        // then
+        assert_eq!(result.errors().len(), 1);
+        assert_eq!(result.errors()[0].0, "0x9abc");
+        for light in &group.lights()[..2] {
+            assert_eq!(light.rgb(), &color);
+            assert_eq!(light.color_mode(), &ColorMode::Color);
+        }
+        drop(group);
+        for bulb in [bulb_1, bulb_2] {
+            let reqs = bulb.join().unwrap();
+            assert_eq!(reqs.len(), 1);
+            assert_eq!(reqs[0].params[0], json!(color.get_num()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn turn_every_light_off() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("support", "set_power");
+        map.insert("Location", "yeelight://127.0.0.1:13489");
+        let bulb_1 = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13489), |_| json!(["ok"]))?;
+        let first = Light::from_fields(&map)?;
+        map.insert("id", "0x5678");
+        map.insert("Location", "yeelight://127.0.0.1:13490");
+        let bulb_2 = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13490),
+                               |_| json!({"error": {"code": -1, "message": "client quota exceeded"}}))?;
+        let second = Light::from_fields(&map)?;
+        let mut group = LightGroup::new(vec![first, second]);
+
+        // when
+        let result = group.all_off(Transition::sudden());
+
+        // then
+        assert_eq!(result.errors().len(), 1);
+        assert_eq!(result.errors()[0].0, "0x5678");
+        assert_eq!(group.lights()[0].power(), &PowerStatus::Off);
+        assert_eq!(group.lights()[1].power(), &PowerStatus::On);
+        drop(group);
+        for bulb in [bulb_1, bulb_2] {
+            let reqs = bulb.join().unwrap();
+            assert_eq!(reqs.len(), 1);
+            assert_eq!(reqs[0].method, "set_power");
+            assert_eq!(reqs[0].params[0], json!("off"));
+        }
+        Ok(())
+    }
+}
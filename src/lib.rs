@@ -17,7 +17,6 @@
 //! use std::time::Duration;
 //! use yeelib_rs::req::Transition;
 //! use yeelib_rs::fields::PowerStatus;
-//! use std::thread::sleep;
 //! use std::error::Error;
 //!
 //! fn main() -> Result<(), Box<dyn Error>> {
@@ -27,19 +26,19 @@
 //!
 //!     // turn the lights on  with smooth 500ms transition
 //!     for light in lights.iter_mut() {
-//!         light.set_power(PowerStatus::On, Transition::smooth(Duration::from_millis(500)).unwrap())?;
+//!         light.set_power(PowerStatus::On, Transition::try_smooth_ms(500)?)?;
 //!     }
 //!
 //!     // let the lights finish changing
-//!     sleep(Duration::from_secs(1));
+//!     for light in lights.iter() {
+//!         light.wait_for_last_transition();
+//!     }
 //!
 //!     // set the color-temperature to 3500 with instant transition
 //!     for light in lights.iter_mut() {
 //!         light.set_ct_abx(3500, Transition::sudden())?;
 //!     }
 //!
-//!     sleep(Duration::from_secs(1));
-//!
 //!     // set the brightness to full with instant transition
 //!     for light in lights.iter_mut() {
 //!         light.set_bright(100, Transition::sudden())?;
@@ -54,17 +53,37 @@
 //! ```
 //!
 use std::collections::{HashMap, HashSet};
-use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
-use std::time::{Duration, Instant};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(any(test, feature = "serde"))]
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
 
 
 pub mod light;
 pub mod fields;
 pub mod err;
 pub mod req;
+pub mod group;
+pub mod pool;
+pub mod diagnose;
+pub mod listener;
+pub mod scoped;
 
-pub use crate::err::YeeError;
-pub use crate::light::Light;
+pub use crate::err::{BulbErrorCode, YeeError};
+pub use crate::light::{
+    BackgroundLight, HistoryEntry, Light, LightMetrics, LightState, RuntimeStats, StateChange, supported_capabilities,
+    supported_methods,
+};
+pub use crate::group::{GroupResult, LightGroup};
+pub use crate::pool::LightPool;
+pub use crate::diagnose::DiagnosticReport;
+pub use crate::listener::{DiscoveryEvent, DiscoveryListener};
+pub use crate::scoped::ScopedLightState;
 
 /// Multicast IPv4 address that Yeelight products listen on for discovery.
 pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
@@ -74,6 +93,14 @@ pub const MULTICAST_PORT: u16 = 1982;
 pub const ALL_LOCAL: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 /// Default port for [`YeeClient`].
 pub const DEFAULT_LOCAL_PORT: u16 = 7821;
+/// Size of the buffer discovery responses are first received into, enough for any light seen so far.
+const DATAGRAM_BUF_LEN: usize = 1024;
+/// Largest possible UDP datagram, the most the discovery buffer grows to.
+const MAX_DATAGRAM_LEN: usize = 65536;
+/// How long [`YeeClient::diagnose`] listens for lights.
+const DIAGNOSE_WINDOW: Duration = Duration::from_secs(2);
+/// First and longest time between searches of `wait_for_light`, which doubles in between.
+const WAIT_SEARCH_INTERVALS: (Duration, Duration) = (Duration::from_millis(500), Duration::from_secs(8));
 
 /// Message that is broadcasted to [`MULTICAST_ADDR`].
 pub const SEARCH_MSG: &str = "\
@@ -87,64 +114,704 @@ pub const SEARCH_MSG: &str = "\
 pub struct YeeClient {
     seeker: UdpSocket,
     multicast_addr: SocketAddrV4,
+    eager_connect: bool,
+    sync_on_connect: bool,
+    handshake_timeout: Option<Duration>,
+    history_len: usize,
+}
+
+/// Builder for a [`YeeClient`] with non-default options.
+#[derive(Debug, Clone)]
+pub struct YeeClientBuilder {
+    multicast_addr: SocketAddrV4,
+    bind_addr: Ipv4Addr,
+    local_port: u16,
+    eager_connect: bool,
+    sync_on_connect: bool,
+    recv_buffer_size: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    history_len: usize,
+}
+
+impl YeeClientBuilder {
+    pub fn new() -> YeeClientBuilder {
+        YeeClientBuilder {
+            multicast_addr: SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT),
+            bind_addr: Ipv4Addr::UNSPECIFIED,
+            local_port: DEFAULT_LOCAL_PORT,
+            eager_connect: true,
+            sync_on_connect: false,
+            recv_buffer_size: None,
+            handshake_timeout: None,
+            history_len: 0,
+        }
+    }
+
+    pub fn multicast_addr(mut self, multicast_addr: SocketAddrV4) -> YeeClientBuilder {
+        self.multicast_addr = multicast_addr;
+        self
+    }
+
+    /// Local address to bind to and join the multicast group on, [`Ipv4Addr::UNSPECIFIED`] by default.
+    ///
+    /// Set this to the address of the interface the lights are on if the default picks the wrong one,
+    /// e.g. a bridge interface inside a container.
+    pub fn bind_addr(mut self, bind_addr: Ipv4Addr) -> YeeClientBuilder {
+        self.bind_addr = bind_addr;
+        self
+    }
+
+    pub fn local_port(mut self, local_port: u16) -> YeeClientBuilder {
+        self.local_port = local_port;
+        self
+    }
+
+    /// Whether discovered lights are connected to during discovery, `true` by default.
+    ///
+    /// If `false`, lights are returned unconnected and connect on their first command instead,
+    /// which is much cheaper on networks with many lights when only a few will be used.
+    pub fn eager_connect(mut self, eager_connect: bool) -> YeeClientBuilder {
+        self.eager_connect = eager_connect;
+        self
+    }
+
+    /// Whether lights connected to during discovery also read their state right away, `false` by default.
+    ///
+    /// Only has an effect with [`eager_connect`](Self::eager_connect), see [`Light::init_and_sync`].
+    /// Lights whose state can't be read are still found, with the state they advertised.
+    pub fn sync_on_connect(mut self, sync_on_connect: bool) -> YeeClientBuilder {
+        self.sync_on_connect = sync_on_connect;
+        self
+    }
+
+    /// Size in bytes of the receive buffer (`SO_RCVBUF`) of the discovery socket, the OS default by default.
+    ///
+    /// A larger buffer reduces missed lights on networks with many lights, where their responses arrive
+    /// all at once and can overflow the default one. The OS may round or limit the size.
+    pub fn recv_buffer_size(mut self, recv_buffer_size: usize) -> YeeClientBuilder {
+        self.recv_buffer_size = Some(recv_buffer_size);
+        self
+    }
+
+    /// Check that discovered lights answer a `get_prop` within `timeout` when they connect, off by default.
+    ///
+    /// Lights in a bad state may accept the connection but never answer, which would otherwise hang the
+    /// first command. Connecting to them fails with [`YeeError::Unresponsive`] instead.
+    /// A zero `timeout` is rejected by [`build`](Self::build).
+    pub fn handshake_timeout(mut self, timeout: Duration) -> YeeClientBuilder {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// How many of the most recent commands discovered lights keep, to debug automations, zero (off) by default.
+    ///
+    /// See [`Light::set_history_len`] and [`Light::history`].
+    pub fn history_len(mut self, history_len: usize) -> YeeClientBuilder {
+        self.history_len = history_len;
+        self
+    }
+
+    pub fn build(self) -> Result<YeeClient, YeeError> {
+        if self.handshake_timeout == Some(Duration::from_secs(0)) {
+            return Err(YeeError::InvalidValue { field_name: "handshake_timeout", value: "0ms".to_string() });
+        }
+        // we don't know the IPs of the lights, so listen to all traffic by default
+        let socket = UdpSocket::bind(SocketAddrV4::new(self.bind_addr, self.local_port))?;
+        socket.join_multicast_v4(self.multicast_addr.ip(), &self.bind_addr)?;
+        socket.set_nonblocking(true)?;
+        if let Some(size) = self.recv_buffer_size {
+            SockRef::from(&socket).set_recv_buffer_size(size)?;
+        }
+
+        Ok(YeeClient {
+            seeker: socket,
+            multicast_addr: self.multicast_addr,
+            eager_connect: self.eager_connect,
+            sync_on_connect: self.sync_on_connect,
+            handshake_timeout: self.handshake_timeout,
+            history_len: self.history_len,
+        })
+    }
+}
+
+impl Default for YeeClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl YeeClient {
     pub fn new() -> Result<YeeClient, YeeError> {
-        let addr = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT);
-        Self::with_addr(addr, DEFAULT_LOCAL_PORT)
+        Self::builder().build()
     }
 
     pub fn with_addr(multicast_addr: SocketAddrV4, local_port: u16) -> Result<YeeClient, YeeError> {
-        // we don't know the IPs of the lights, so listen to all traffic
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port))?;
-        socket.join_multicast_v4(multicast_addr.ip(), &Ipv4Addr::UNSPECIFIED)?;
-        socket.set_nonblocking(true)?;
+        Self::builder()
+            .multicast_addr(multicast_addr)
+            .local_port(local_port)
+            .build()
+    }
+
+    pub fn builder() -> YeeClientBuilder {
+        YeeClientBuilder::new()
+    }
+
+    /// Search for lights from every local IPv4 interface separately and report which ones lights answered on,
+    /// to find out why [`find_lights`](Self::find_lights) finds nothing, e.g. because the default interface
+    /// is a VPN.
+    ///
+    /// Takes about two seconds. If the interfaces can't be listed, the report has none.
+    pub fn diagnose() -> DiagnosticReport {
+        let interfaces = if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|interface| match interface.addr {
+                if_addrs::IfAddr::V4(v4) => Some((interface.name, v4.ip)),
+                _ => None
+            })
+            .collect();
+        diagnose::diagnose(interfaces, SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT), DIAGNOSE_WINDOW)
+    }
+
+    /// Keep discovering lights in a background thread until the returned listener is dropped,
+    /// to follow lights joining the network or changing state.
+    ///
+    /// Searches periodically and also catches the advertisements lights send on their own,
+    /// if the multicast port isn't taken by another program.
+    pub fn listen(&self) -> Result<DiscoveryListener, YeeError> {
+        DiscoveryListener::start(self.seeker.try_clone()?, self.multicast_addr)
+    }
+
+    /// The socket used for discovery, e.g. to read its options.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.seeker
+    }
 
-        Ok(YeeClient { seeker: socket, multicast_addr })
+    /// Apply any option to the socket used for discovery, e.g. the multicast TTL for lights behind a router,
+    /// instead of a setting for each on [`YeeClientBuilder`].
+    ///
+    /// The socket must stay non-blocking and in the multicast group, or discovery breaks.
+    pub fn configure_socket<F>(&self, configure: F) -> Result<(), YeeError>
+        where F: FnOnce(&UdpSocket) -> std::io::Result<()> {
+        configure(&self.seeker)?;
+        Ok(())
     }
 
     pub fn find_lights(&self, timeout: Duration) -> Vec<Light> {
         // TODO: handle send multicast fail
-        self.seeker.send_to(SEARCH_MSG.as_bytes(), &self.multicast_addr).unwrap();
+        self.find_lights_until(Instant::now() + timeout).unwrap()
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), but search until `deadline` instead of for a
+    /// duration, e.g. to share a deadline with other operations.
+    pub fn find_lights_until(&self, deadline: Instant) -> Result<Vec<Light>, YeeError> {
+        self.discover(self.search(deadline))
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), but send the search to `broadcast_addr`, the
+    /// broadcast address of the subnet the lights are on, e.g. `192.168.1.255`, for networks that block
+    /// multicast.
+    ///
+    /// Only reaches lights on that subnet, and only the ones that answer searches sent to them directly,
+    /// which not every firmware does. Routers never forward broadcasts, and some access points drop them too.
+    pub fn find_lights_broadcast(&self, broadcast_addr: Ipv4Addr, timeout: Duration) -> Result<Vec<Light>, YeeError> {
+        self.seeker.set_broadcast(true)?;
+        let target = SocketAddrV4::new(broadcast_addr, self.multicast_addr.port());
+        let lights = self.discover(Search { target, ..self.search(Instant::now() + timeout) });
+        self.seeker.set_broadcast(false)?;
+        lights
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), but only keep the ones whose location is in the
+    /// subnet given by `subnet` and `mask`, e.g. `192.168.1.0` and `255.255.255.0`, to ignore lights on other
+    /// VLANs that may not be reachable.
+    ///
+    /// Lights outside the subnet are never connected to.
+    pub fn find_lights_in_subnet(&self, timeout: Duration, subnet: Ipv4Addr, mask: Ipv4Addr)
+                                 -> Result<Vec<Light>, YeeError> {
+        self.discover(Search { subnet: Some((subnet, mask)), ..self.search(Instant::now() + timeout) })
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), with the fastest to respond first.
+    ///
+    /// See [`Light::discovery_latency`].
+    pub fn find_lights_sorted_by_latency(&self, timeout: Duration) -> Result<Vec<Light>, YeeError> {
+        let mut lights = self.find_lights_until(Instant::now() + timeout)?;
+        lights.sort_by_key(|light| light.discovery_latency());
+        Ok(lights)
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), but also return every response received,
+    /// including the ones that couldn't be turned into a [`Light`], to debug discovery.
+    pub fn try_find_lights(&self, timeout: Duration) -> Result<Discovery, YeeError> {
+        let mut responses = Vec::new();
+        let search = Search { responses: Some(&mut responses), ..self.search(Instant::now() + timeout) };
+        let lights = self.discover(search)?;
+        Ok(Discovery { lights, responses })
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), also returning the ids that responded from more than one
+    /// location, e.g. a cloned light, of which only the first response is kept as a [`Light`].
+    pub fn find_lights_with_conflicts(&self, timeout: Duration) -> Result<(Vec<Light>, Vec<IdConflict>), YeeError> {
+        let mut conflicts = Vec::new();
+        let search = Search { conflicts: Some(&mut conflicts), ..self.search(Instant::now() + timeout) };
+        let lights = self.discover(search)?;
+        Ok((lights, conflicts))
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), but stop early once `cancel` is set,
+    /// returning the lights found so far.
+    pub fn find_lights_cancellable(&self, timeout: Duration, cancel: Arc<AtomicBool>) -> Result<Vec<Light>, YeeError> {
+        self.discover(Search { cancel: Some(&cancel), ..self.search(Instant::now() + timeout) })
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), but only check whether each one accepts a connection
+    /// with [`Light::probe`] instead of connecting, see [`Light::reachable`].
+    ///
+    /// Lighter than keeping a connection open to each light, e.g. to list lights and whether they can be reached.
+    pub fn find_lights_probed(&self, timeout: Duration) -> Result<Vec<Light>, YeeError> {
+        self.discover(Search { probe: true, ..self.search(Instant::now() + timeout) })
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), keeping them in `pool`, where lights found in
+    /// an earlier round keep their connection instead of connecting again.
+    ///
+    /// Pooled connections the light has closed since are dropped first. Lights not found this round stay in
+    /// the pool.
+    pub fn find_lights_pooled(&self, timeout: Duration, pool: &mut LightPool) -> Result<(), YeeError> {
+        pool.check_connections();
+        let lights = self.discover(Search { pool: Some(pool), ..self.search(Instant::now() + timeout) })?;
+        for light in lights {
+            pool.insert(light);
+        }
+        Ok(())
+    }
+
+    /// Find the first light to respond and connect to it, for scripts that only deal with one light,
+    /// or `None` if no light responds within `timeout`.
+    ///
+    /// Stops searching as soon as a light is found, so the light is the one closest to this machine
+    /// as far as can be told by how quickly it responded, not necessarily the physically nearest.
+    pub fn find_one(&self, timeout: Duration) -> Result<Option<Light>, YeeError> {
+        let lights = self.discover(Search { limit: Some(1), ..self.search(Instant::now() + timeout) })?;
+        Ok(lights.into_iter().next())
+    }
+
+    /// Find lights like [`find_lights`](Self::find_lights), but stop once no new light was found for
+    /// `quiet_period`, or after `max_total`, to be quick on small networks and patient on large ones.
+    ///
+    /// Lights answering again don't count as new.
+    pub fn find_until_quiet(&self, quiet_period: Duration, max_total: Duration) -> Result<Vec<Light>, YeeError> {
+        self.discover(Search { quiet: Some(quiet_period), ..self.search(Instant::now() + max_total) })
+    }
+
+    /// Find lights like [`try_find_lights`](Self::try_find_lights) and record everything found, e.g. to keep
+    /// a lighting setup under version control and diff it over time.
+    ///
+    /// Lights are sorted by id so snapshots of the same network diff cleanly.
+    pub fn snapshot_network(&self, timeout: Duration) -> Result<NetworkSnapshot, YeeError> {
+        let Discovery { lights, responses } = self.try_find_lights(timeout)?;
+        let mut lights: Vec<LightSnapshot> = lights.iter().map(Light::snapshot).collect();
+        lights.sort_by(|a, b| a.id.cmp(&b.id));
+        let taken_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        Ok(NetworkSnapshot { taken_at, responses: responses.len(), lights })
+    }
+
+    /// Search for the light with `id` until it answers or `timeout` elapses, and connect to it, e.g. to wait
+    /// for a light to boot and join the network after its power was switched back on.
+    ///
+    /// Searches again after 0.5s, then waits twice as long each time up to 8s, to limit multicast traffic.
+    /// A search that can't be sent, e.g. while the network is still unreachable, counts as the light not
+    /// answering yet. Returns `None` if the light didn't answer, or couldn't be connected to, in time.
+    pub fn wait_for_light(&self, id: &str, timeout: Duration) -> Option<Light> {
+        let deadline = Instant::now() + timeout;
+        let (mut interval, max_interval) = WAIT_SEARCH_INTERVALS;
+        while Instant::now() < deadline {
+            let round_end = (Instant::now() + interval).min(deadline);
+            let search = Search { id: Some(id), limit: Some(1), ..self.search(round_end) };
+            if let Some(mut light) = self.discover(search).ok().and_then(|mut lights| lights.pop()) {
+                if light.init().is_ok() {
+                    return Some(light);
+                }
+            }
+            sleep(round_end.saturating_duration_since(Instant::now()));
+            interval = (interval * 2).min(max_interval);
+        }
+        None
+    }
+
+    /// A search for every light answering on the multicast group until `deadline`.
+    fn search<'a>(&self, deadline: Instant) -> Search<'a> {
+        Search {
+            target: self.multicast_addr,
+            deadline,
+            cancel: None,
+            responses: None,
+            pool: None,
+            subnet: None,
+            limit: None,
+            quiet: None,
+            id: None,
+            probe: false,
+            conflicts: None,
+        }
+    }
+
+    fn discover(&self, search: Search) -> Result<Vec<Light>, YeeError> {
+        let Search { target, deadline, cancel, mut responses, pool, subnet, limit, quiet, id, probe, mut conflicts } =
+            search;
+        self.seeker.send_to(SEARCH_MSG.as_bytes(), target)?;
 
         let mut lights: HashSet<Light> = HashSet::new();
         let now = Instant::now();
-        while now.elapsed() < timeout {
-            // all lifetimes depend on this buf
-            let mut buf = [0u8; 1024];
-            let mut headers = [httparse::EMPTY_HEADER; 17];
-            let mut res = httparse::Response::new(&mut headers);
-
-            if let Ok((size, _)) = self.seeker.recv_from(&mut buf) {
-                // TODO: handle if failed to parse response
-                res.parse(&buf[..size]).unwrap();
-                let headers: HashMap<&str, _> = res.headers.iter()
-                    .map(|h| {
-                        let name = h.name;
-                        let value = String::from_utf8_lossy(h.value);
-                        (name, value)
-                    }).collect();
-                if let Ok(mut new_light) = Light::from_fields(&headers) {
-                    if !lights.contains(&new_light) {
-                        if let Ok(()) = new_light.init() {
-                            lights.insert(new_light);
+        let mut last_new = now;
+        let mut buf = vec![0u8; DATAGRAM_BUF_LEN];
+        while Instant::now() < deadline {
+            if matches!(cancel, Some(c) if c.load(Ordering::Relaxed)) {
+                break;
+            }
+            if matches!(limit, Some(limit) if lights.len() >= limit) {
+                break;
+            }
+            if matches!(quiet, Some(quiet) if last_new.elapsed() >= quiet) {
+                break;
+            }
+            if let Ok((size, from)) = self.seeker.recv_from(&mut buf) {
+                let latency = now.elapsed();
+                // a datagram filling the buffer may have been cut off, and can't be read again,
+                // so search again with room for it
+                let truncated = size == buf.len();
+                if truncated && buf.len() < MAX_DATAGRAM_LEN {
+                    buf.resize(buf.len() * 2, 0);
+                    // keep the lights found so far, the truncated light may still answer the first search again
+                    if let Err(e) = self.seeker.send_to(SEARCH_MSG.as_bytes(), target) {
+                        log::warn!("failed to search again for a truncated response: {}", e);
+                    }
+                }
+                let parsed = if truncated {
+                    Err(YeeError::InvalidResponse { message: format!("datagram of {} bytes may be truncated", size) })
+                } else {
+                    parse_response(&buf[..size])
+                };
+                let result = parsed
+                    .and_then(|mut light| {
+                        if let Some((subnet, mask)) = subnet {
+                            if !in_subnet(*light.location().ip(), subnet, mask) {
+                                return Err(YeeError::InvalidResponse {
+                                    message: format!("{} is outside {}/{}", light.location(), subnet, mask)
+                                });
+                            }
+                        }
+                        if matches!(id, Some(id) if id != light.id()) {
+                            let message = format!("not looking for {}", light.id());
+                            return Err(YeeError::InvalidResponse { message });
+                        }
+                        light.discovery_latency = Some(latency);
+                        light.handshake_timeout = self.handshake_timeout;
+                        light.history_len = self.history_len;
+                        let pooled = matches!(pool, Some(pool) if pool.can_reuse(&light));
+                        if self.eager_connect && !probe && !pooled && !lights.contains(&light) {
+                            light.init()?;
+                            // the light answered, so keep it with its advertised state if it can't be read
+                            if self.sync_on_connect {
+                                if let Err(e) = light.refresh() {
+                                    log::warn!("failed to read the state of light {}: {}", light.id(), e);
+                                }
+                            }
+                        }
+                        Ok(light)
+                    });
+                let error = match result {
+                    Ok(light) => {
+                        if let (Some(conflicts), Some(seen)) = (conflicts.as_mut(), lights.get(&light)) {
+                            if seen.location() != light.location() {
+                                match conflicts.iter_mut().find(|conflict| conflict.id == light.id()) {
+                                    Some(conflict) if !conflict.locations.contains(light.location()) =>
+                                        conflict.locations.push(*light.location()),
+                                    Some(_) => {}
+                                    None => conflicts.push(IdConflict {
+                                        id: light.id().to_string(),
+                                        locations: vec![*seen.location(), *light.location()],
+                                    }),
+                                }
+                            }
                         }
+                        if lights.insert(light) {
+                            last_new = Instant::now();
+                        }
+                        None
                     }
+                    Err(e) => Some(e)
+                };
+                if let Some(responses) = responses.as_mut() {
+                    let text = String::from_utf8_lossy(&buf[..size]).into_owned();
+                    responses.push(RawResponse { from, text, error });
                 }
             }
         }
-        let lights: Vec<Light> = lights.into_iter().collect();
-        lights
+        let mut lights: Vec<Light> = lights.into_iter().collect();
+        if probe {
+            for light in lights.iter_mut() {
+                light.reachable = Some(light.probe());
+            }
+        }
+        Ok(lights)
     }
 }
 
+/// Options of a single discovery by `YeeClient::discover`.
+struct Search<'a> {
+    /// Where the search is sent.
+    target: SocketAddrV4,
+    deadline: Instant,
+    /// Stops the search early once set.
+    cancel: Option<&'a AtomicBool>,
+    /// Collects every datagram received.
+    responses: Option<&'a mut Vec<RawResponse>>,
+    /// Lights that don't need to connect again.
+    pool: Option<&'a LightPool>,
+    /// Only keeps lights in this subnet and netmask.
+    subnet: Option<(Ipv4Addr, Ipv4Addr)>,
+    /// Stops the search once this many lights are found.
+    limit: Option<usize>,
+    /// Stops the search once no new light was found for this long.
+    quiet: Option<Duration>,
+    /// Only keeps the light with this id.
+    id: Option<&'a str>,
+    /// Probes the lights found instead of connecting.
+    probe: bool,
+    /// Collects the ids found at more than one location.
+    conflicts: Option<&'a mut Vec<IdConflict>>,
+}
+
+/// Lights found by [`YeeClient::try_find_lights`], with every response received while searching.
+#[derive(Debug)]
+pub struct Discovery {
+    pub lights: Vec<Light>,
+    pub responses: Vec<RawResponse>,
+}
+
+/// An id that responded from more than one location during [`YeeClient::find_lights_with_conflicts`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdConflict {
+    pub id: String,
+    /// Every location the id responded from, in order, starting with the one of the [`Light`] kept.
+    pub locations: Vec<SocketAddrV4>,
+}
+
+/// A datagram received during discovery.
+#[derive(Debug)]
+pub struct RawResponse {
+    pub from: SocketAddr,
+    /// The datagram, with invalid UTF-8 replaced.
+    pub text: String,
+    /// Why the datagram wasn't turned into a [`Light`], or `None` if it was (or was a duplicate).
+    pub error: Option<YeeError>,
+}
+
+/// Everything found by [`YeeClient::snapshot_network`], serializable to save it with the `serde` feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+pub struct NetworkSnapshot {
+    /// When the snapshot was taken, in seconds since the Unix epoch.
+    pub taken_at: u64,
+    /// Number of datagrams received, including duplicates and ones that couldn't be parsed.
+    pub responses: usize,
+    pub lights: Vec<LightSnapshot>,
+}
+
+/// A light in a [`NetworkSnapshot`], or saved with [`Light::snapshot`] to restore it with [`Light::from_state`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+pub struct LightSnapshot {
+    pub id: String,
+    pub model: String,
+    pub name: String,
+    pub location: SocketAddrV4,
+    /// See [`Light::discovery_latency`].
+    pub latency_ms: Option<u64>,
+    /// See [`Light::support`], sorted.
+    pub support: Vec<String>,
+    pub state: LightState,
+}
+
+/// Whether `ip` is in the subnet `subnet` with netmask `mask`.
+fn in_subnet(ip: Ipv4Addr, subnet: Ipv4Addr, mask: Ipv4Addr) -> bool {
+    let mask = u32::from(mask);
+    u32::from(ip) & mask == u32::from(subnet) & mask
+}
+
+/// Parse a discovery response into an unconnected [`Light`].
+fn parse_response(buf: &[u8]) -> Result<Light, YeeError> {
+    // all lifetimes depend on buf
+    let mut headers = [httparse::EMPTY_HEADER; 17];
+    let mut res = httparse::Response::new(&mut headers);
+    // reject anything that isn't a successful response, e.g. other SSDP traffic or stray packets
+    match res.parse(buf) {
+        Ok(_) if res.code != Some(200) =>
+            return Err(YeeError::InvalidResponse { message: format!("unexpected status code: {:?}", res.code) }),
+        Ok(httparse::Status::Complete(_)) => {}
+        // lights don't end responses with a blank line, so they parse as partial,
+        // but a datagram cut off mid-line is incomplete
+        Ok(httparse::Status::Partial) if buf.ends_with(b"\r\n") => {}
+        Ok(httparse::Status::Partial) => return Err(YeeError::InvalidResponse { message: "incomplete response".to_string() }),
+        Err(e) => return Err(YeeError::InvalidResponse { message: e.to_string() })
+    }
+    light_from_headers(res.headers)
+}
+
+/// Parse a discovery response, or a `NOTIFY` advertisement lights send on their own, into an unconnected [`Light`].
+fn parse_advertisement(buf: &[u8]) -> Result<Light, YeeError> {
+    if !buf.starts_with(b"NOTIFY") {
+        return parse_response(buf);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 17];
+    let mut req = httparse::Request::new(&mut headers);
+    req.parse(buf).map_err(|e| YeeError::InvalidResponse { message: e.to_string() })?;
+    light_from_headers(req.headers)
+}
+
+fn light_from_headers(headers: &[httparse::Header]) -> Result<Light, YeeError> {
+    let headers: HashMap<&str, _> = headers.iter()
+        .map(|h| {
+            let name = h.name;
+            let value = String::from_utf8_lossy(h.value);
+            (name, value)
+        }).collect();
+    Light::from_fields(&headers)
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::net::{IpAddr, TcpListener};
+    use std::thread;
+
+    use serde_json::json;
+
+    use crate::fields::PowerStatus;
+    use crate::light::tests::fake_bulb;
 
     use super::*;
 
+    fn fake_client(seeker: UdpSocket, multicast_addr: SocketAddrV4) -> YeeClient {
+        YeeClient {
+            seeker,
+            multicast_addr,
+            eager_connect: true,
+            sync_on_connect: false,
+            handshake_timeout: None,
+            history_len: 0,
+        }
+    }
+
+    /// A discovery response of a `color` light with `id` at `location`.
+    pub(crate) fn fake_advertisement(location: SocketAddrV4, id: &str) -> String {
+        // there are already newlines in the string, so need to add \n
+        format!("HTTP/1.1 200 OK\r
+Location: yeelight://{}\r
+id: {}\r
+model: color\r
+fw_ver: 20\r
+support: get_prop set_power\r
+power: on\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: \r\n", location, id)
+    }
+
+    #[test]
+    fn discover_with_probe() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41259;
+        let multicast_port = 41260;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = fake_client(fake_sender, fake_multicast_addr);
+
+        // only the first light accepts connections
+        let _fake_bulb = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13495))?;
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41261))?;
+        for (id, port) in [("0xup", 13495), ("0xdown", 13496)] {
+            let fake_msg = fake_advertisement(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port), id);
+            fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        }
+        drop(fake_light);
+
+        // WHEN
+        let lights = client.find_lights_probed(Duration::from_millis(500))?;
+
+        // THEN
+        let mut reachable: Vec<(&str, Option<bool>)> = lights.iter().map(|l| (l.id(), l.reachable())).collect();
+        reachable.sort();
+        assert_eq!(reachable, vec![("0xdown", Some(false)), ("0xup", Some(true))]);
+        assert!(lights.iter().all(|light| !light.is_controllable()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn discover_id_conflicts() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41262;
+        let multicast_port = 41263;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41264))?;
+        for (id, port) in [("0xclone", 55443), ("0xclone", 55444), ("0xclone", 55443), ("0xother", 55445)] {
+            let fake_msg = fake_advertisement(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port), id);
+            fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        }
+        drop(fake_light);
+
+        // WHEN
+        let (lights, conflicts) = client.find_lights_with_conflicts(Duration::from_millis(500))?;
+
+        // THEN
+        assert_eq!(lights.len(), 2);
+        assert_eq!(conflicts, vec![IdConflict {
+            id: "0xclone".to_string(),
+            locations: vec![
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 55443),
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 55444),
+            ],
+        }]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn configure_discovery_socket() -> anyhow::Result<()> {
+        // GIVEN
+        let seeker = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41257))?;
+        let client = fake_client(seeker, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41258));
+
+        // WHEN
+        client.configure_socket(|socket| socket.set_multicast_ttl_v4(4))?;
+
+        // THEN
+        assert_eq!(client.socket().multicast_ttl_v4()?, 4);
+
+        Ok(())
+    }
+
     #[test]
     fn is_multicast() {
         assert!(MULTICAST_ADDR.is_multicast());
@@ -192,6 +859,55 @@ mod tests {
         assert_eq!(local_addr.port(), DEFAULT_LOCAL_PORT);
     }
 
+    #[test]
+    fn create_client_on_bind_addr() {
+        // given
+        let multicast_addr = SocketAddrV4::new(MULTICAST_ADDR, MULTICAST_PORT);
+        let local_port = 5436;
+
+        // when
+        let client = YeeClient::builder()
+            .multicast_addr(multicast_addr)
+            .bind_addr(Ipv4Addr::LOCALHOST)
+            .local_port(local_port)
+            .build();
+
+        // then
+        assert!(client.is_ok());
+        let local_addr = client.unwrap().seeker.local_addr().unwrap();
+        assert_eq!(local_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(local_addr.port(), local_port);
+    }
+
+    #[test]
+    fn create_client_with_recv_buffer_size() -> anyhow::Result<()> {
+        // given
+        let size = 1 << 18;
+
+        // when
+        let client = YeeClient::builder()
+            .bind_addr(Ipv4Addr::LOCALHOST)
+            .local_port(5437)
+            .recv_buffer_size(size)
+            .build()?;
+
+        // then
+        // Linux reports double the size, to leave room for bookkeeping
+        assert!(SockRef::from(client.socket()).recv_buffer_size()? >= size);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_zero_handshake_timeout() {
+        // when
+        let client = YeeClient::builder()
+            .handshake_timeout(Duration::from_secs(0))
+            .build();
+
+        // then
+        assert!(matches!(client, Err(YeeError::InvalidValue { field_name: "handshake_timeout", .. })));
+    }
+
     #[test]
     fn create_with_invalid_multicast() {
         // given
@@ -215,7 +931,7 @@ mod tests {
         let multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
         let fake_sender = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port))?;
         fake_sender.set_nonblocking(true)?;
-        let client = YeeClient { seeker: fake_sender, multicast_addr: fake_multicast_addr };
+        let client = fake_client(fake_sender, fake_multicast_addr);
 
         // when
         client.find_lights(Duration::from_millis(500));
@@ -243,7 +959,7 @@ mod tests {
         let fake_sender = UdpSocket::bind(client_addr)?;
 
         fake_sender.set_nonblocking(true)?;
-        let client = YeeClient { seeker: fake_sender, multicast_addr: fake_multicast_addr };
+        let client = fake_client(fake_sender, fake_multicast_addr);
 
         // send mock messages
         let fake_addr_1 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9889);
@@ -345,7 +1061,7 @@ name: light_one\r\n";
         let fake_sender = UdpSocket::bind(client_addr)?;
 
         fake_sender.set_nonblocking(true)?;
-        let client = YeeClient { seeker: fake_sender, multicast_addr: fake_multicast_addr };
+        let client = fake_client(fake_sender, fake_multicast_addr);
 
         // send mock messages
         let fake_addr_1 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 56356);
@@ -395,7 +1111,7 @@ name: light_one\r\n";
         let fake_sender = UdpSocket::bind(client_addr)?;
 
         fake_sender.set_nonblocking(true)?;
-        let client = YeeClient { seeker: fake_sender, multicast_addr: fake_multicast_addr };
+        let client = fake_client(fake_sender, fake_multicast_addr);
 
         // send mock messages
         let fake_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 23395);
@@ -440,6 +1156,718 @@ name: light_one\r\n";
 
         Ok(())
     }
-}
 
+    #[test]
+    fn skip_unparseable_datagrams() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41213;
+        let multicast_port = 41214;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = fake_client(fake_sender, fake_multicast_addr);
+
+        // send garbage, other SSDP traffic, and an error response before a valid advertisement
+        let fake_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41215);
+        let fake_light = UdpSocket::bind(fake_addr)?;
+        fake_light.send_to(&[0xff, 0x00, 0x13, 0x37, 0xde, 0xad], client_addr)?;
+        fake_light.send_to(SEARCH_MSG.as_bytes(), client_addr)?;
+        fake_light.send_to(b"HTTP/1.1 404 Not Found\r\nLocation: yeelight://127.0.0.1:41215\r\n\r\n", client_addr)?;
+        // there are already newlines in the string, so need to add \n
+        let fake_msg = "HTTP/1.1 200 OK\r
+Cache-Control: max-age=3600\r
+Date: \r
+Ext: \r
+Location: yeelight://127.0.0.1:41215\r
+Server: POSIX UPnP/1.0 YGLC/1\r
+id: 0x12345abcde\r
+model: ceiling3\r
+fw_ver: 20\r
+support: get_prop set_default set_power toggle set_bright\r
+power: on\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: light_one\r\n";
+        fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        drop(fake_light);
+
+        let _fake_listener = TcpListener::bind(fake_addr)?;
+
+        // WHEN
+        let result = client.find_lights(Duration::from_millis(500));
+
+        // THEN
+        assert_eq!(result.len(), 1);
+
+        Ok(())
+    }
 
+    #[test]
+    fn lazy_client_does_not_connect() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41216;
+        let multicast_port = 41217;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        // no TCP listener for this light, so connecting would fail
+        let fake_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41218);
+        let fake_light = UdpSocket::bind(fake_addr)?;
+        // there are already newlines in the string, so need to add \n
+        let fake_msg = "HTTP/1.1 200 OK\r
+Cache-Control: max-age=3600\r
+Date: \r
+Ext: \r
+Location: yeelight://127.0.0.1:41218\r
+Server: POSIX UPnP/1.0 YGLC/1\r
+id: 0x12345abcde\r
+model: ceiling3\r
+fw_ver: 20\r
+support: get_prop set_default set_power toggle set_bright\r
+power: on\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: light_one\r\n";
+        fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        drop(fake_light);
+
+        // WHEN
+        let result = client.find_lights(Duration::from_millis(500));
+
+        // THEN
+        assert_eq!(result.len(), 1);
+        assert!(result[0].read.is_none());
+        assert!(result[0].write.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_every_response() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41219;
+        let multicast_port = 41220;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = fake_client(fake_sender, fake_multicast_addr);
+
+        let fake_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41221);
+        let fake_light = UdpSocket::bind(fake_addr)?;
+        fake_light.send_to(b"garbage", client_addr)?;
+        // there are already newlines in the string, so need to add \n
+        // missing color_mode
+        let fake_msg = "HTTP/1.1 200 OK\r
+Cache-Control: max-age=3600\r
+Date: \r
+Ext: \r
+Location: yeelight://127.0.0.1:41221\r
+Server: POSIX UPnP/1.0 YGLC/1\r
+id: 0x12345abcde\r
+model: ceiling3\r
+fw_ver: 20\r
+support: get_prop set_default set_power toggle set_bright\r
+power: on\r
+bright: 40\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: light_one\r\n";
+        fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        drop(fake_light);
+
+        // WHEN
+        let result = client.try_find_lights(Duration::from_millis(500))?;
+
+        // THEN
+        assert!(result.lights.is_empty());
+        assert_eq!(result.responses.len(), 2);
+        assert_eq!(result.responses[0].text, "garbage");
+        assert!(matches!(result.responses[0].error, Some(YeeError::InvalidResponse { .. })));
+        assert_eq!(result.responses[1].from, SocketAddr::V4(fake_addr));
+        assert!(matches!(result.responses[1].error, Some(YeeError::FieldNotFound { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sort_lights_by_latency() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41222;
+        let multicast_port = 41223;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        // there are already newlines in the string, so need to add \n
+        let fake_msg = |port: u16, id: &str| format!("HTTP/1.1 200 OK\r
+Cache-Control: max-age=3600\r
+Date: \r
+Ext: \r
+Location: yeelight://127.0.0.1:{}\r
+Server: POSIX UPnP/1.0 YGLC/1\r
+id: {}\r
+model: ceiling3\r
+fw_ver: 20\r
+support: get_prop set_default set_power toggle set_bright\r
+power: on\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: light_one\r\n", port, id);
+        let slow_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41224))?;
+        let slow_msg = fake_msg(41224, "0xslow");
+        let slow = thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            slow_light.send_to(slow_msg.as_bytes(), client_addr).unwrap();
+        });
+        let fast_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41225))?;
+        fast_light.send_to(fake_msg(41225, "0xfast").as_bytes(), client_addr)?;
+
+        // WHEN
+        let result = client.find_lights_sorted_by_latency(Duration::from_millis(500))?;
+        slow.join().unwrap();
+
+        // THEN
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].id(), "0xfast");
+        assert_eq!(result[1].id(), "0xslow");
+        assert!(result[1].discovery_latency().unwrap() >= Duration::from_millis(200));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_discovery() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41226;
+        let multicast_port = 41227;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = fake_client(fake_sender, fake_multicast_addr);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = Arc::clone(&cancel);
+            thread::spawn(move || {
+                sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::Relaxed);
+            })
+        };
+
+        // WHEN
+        let start = Instant::now();
+        let result = client.find_lights_cancellable(Duration::from_secs(10), cancel)?;
+        canceller.join().unwrap();
+
+        // THEN
+        assert!(result.is_empty());
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn discover_until_deadline() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41228;
+        let multicast_port = 41229;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        let multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = fake_client(fake_sender, fake_multicast_addr);
+
+        // WHEN
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(200);
+        let result = client.find_lights_until(deadline)?;
+
+        // THEN
+        let mut buf = [0u8; 1024];
+        let (size, _) = multicast_listener.recv_from(&mut buf)?;
+        assert_eq!(&buf[..size], SEARCH_MSG.as_bytes());
+        assert!(result.is_empty());
+        assert!(Instant::now() >= deadline);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_state_on_connect() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41230;
+        let multicast_port = 41231;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { sync_on_connect: true, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        let fake_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41232);
+        let bulb = fake_bulb(fake_addr, |_| {
+            json!(["off", "75", "1", "4000", "65280", "120", "50", "light_one", "0", ""])
+        })?;
+        let fake_light = UdpSocket::bind(fake_addr)?;
+        // there are already newlines in the string, so need to add \n
+        let fake_msg = "HTTP/1.1 200 OK\r
+Cache-Control: max-age=3600\r
+Location: yeelight://127.0.0.1:41232\r
+id: 0x12345abcde\r
+model: color\r
+fw_ver: 20\r
+support: get_prop set_power\r
+power: on\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: light_one\r\n";
+        fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        drop(fake_light);
+
+        // WHEN
+        let result = client.find_lights(Duration::from_millis(500));
+
+        // THEN
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].power(), &PowerStatus::Off);
+        assert_eq!(result[0].bright(), 75);
+        assert_eq!(result[0].ct(), 4000);
+        drop(result);
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].method, "get_prop");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_advertised_state_when_sync_fails() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41271;
+        let multicast_port = 41272;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { sync_on_connect: true, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        let fake_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41273);
+        let bulb = fake_bulb(fake_addr, |_| json!({ "error": { "code": -5000, "message": "general error" } }))?;
+        let fake_light = UdpSocket::bind(fake_addr)?;
+        fake_light.send_to(fake_advertisement(fake_addr, "0x12345abcde").as_bytes(), client_addr)?;
+        drop(fake_light);
+
+        // WHEN
+        let result = client.find_lights(Duration::from_millis(500));
+
+        // THEN
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].power(), &PowerStatus::On);
+        assert_eq!(result[0].bright(), 40);
+        drop(result);
+        assert_eq!(bulb.join().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn retry_truncated_response() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41237;
+        let multicast_port = 41238;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        let multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        multicast_listener.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        // the longest name lights accept, and padding to make the response longer than the buffer
+        let name = "n".repeat(64);
+        let fake_msg = format!("HTTP/1.1 200 OK\r
+Cache-Control: max-age=3600\r
+Date: \r
+Ext: \r
+Location: yeelight://127.0.0.1:41239\r
+Server: POSIX UPnP/1.0 YGLC/1\r
+id: 0x12345abcde\r
+model: color\r
+fw_ver: 20\r
+support: {}\r
+power: on\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: {}\r\n", "get_prop set_power ".repeat(60), name);
+        assert!(fake_msg.len() > DATAGRAM_BUF_LEN);
+        let fake_light = thread::spawn(move || {
+            // answer every search, like a light would
+            let mut buf = [0u8; 1024];
+            let mut searches = 0;
+            while multicast_listener.recv_from(&mut buf).is_ok() {
+                multicast_listener.send_to(fake_msg.as_bytes(), client_addr).unwrap();
+                searches += 1;
+            }
+            searches
+        });
+
+        // WHEN
+        let result = client.try_find_lights(Duration::from_millis(500))?;
+
+        // THEN
+        assert_eq!(fake_light.join().unwrap(), 2);
+        assert_eq!(result.lights.len(), 1);
+        assert_eq!(result.lights[0].name(), name);
+        assert_eq!(result.responses.len(), 2);
+        assert!(matches!(result.responses[0].error, Some(YeeError::InvalidResponse { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn match_subnet() {
+        let mask = Ipv4Addr::new(255, 255, 255, 0);
+        assert!(in_subnet(Ipv4Addr::new(192, 168, 1, 42), Ipv4Addr::new(192, 168, 1, 0), mask));
+        assert!(in_subnet(Ipv4Addr::new(192, 168, 1, 42), Ipv4Addr::new(192, 168, 1, 7), mask));
+        assert!(!in_subnet(Ipv4Addr::new(192, 168, 2, 42), Ipv4Addr::new(192, 168, 1, 0), mask));
+        assert!(in_subnet(Ipv4Addr::new(10, 20, 30, 40), Ipv4Addr::new(10, 0, 0, 0), Ipv4Addr::new(255, 0, 0, 0)));
+        assert!(!in_subnet(Ipv4Addr::new(11, 20, 30, 40), Ipv4Addr::new(10, 0, 0, 0), Ipv4Addr::new(255, 0, 0, 0)));
+        assert!(in_subnet(Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED));
+    }
+
+    #[test]
+    fn discover_in_subnet() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41240;
+        let multicast_port = 41241;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41242))?;
+        let inside = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 55443);
+        let outside = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 5), 55443);
+        for (location, id) in [(inside, "0xinside"), (outside, "0xoutside")] {
+            let fake_msg = fake_advertisement(location, id);
+            fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        }
+        drop(fake_light);
+
+        // WHEN
+        let result = client.find_lights_in_subnet(Duration::from_millis(300),
+                                                  Ipv4Addr::new(127, 0, 0, 0), Ipv4Addr::new(255, 0, 0, 0))?;
+
+        // THEN
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].id(), "0xinside");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_response_with_any_header_case() -> anyhow::Result<()> {
+        // GIVEN
+        let response = "HTTP/1.1 200 OK\r
+cache-control: max-age=3600\r
+location: yeelight://192.168.1.239:55443\r
+ID: 0x000000000015243f\r
+Model: color\r
+fw_ver: 18\r
+Support: get_prop set_power\r
+POWER: on\r
+bright: 100\r
+color_mode: 2\r
+ct: 4000\r
+rgb: 16711680\r
+hue: 100\r
+sat: 35\r
+Name: my_bulb\r\n";
+
+        // WHEN
+        let light = parse_response(response.as_bytes())?;
+
+        // THEN
+        assert_eq!(light.location(), &SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 239), 55443));
+        assert_eq!(light.id(), "0x000000000015243f");
+        assert_eq!(light.power(), &PowerStatus::On);
+        assert_eq!(light.name(), "my_bulb");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_date_header() -> anyhow::Result<()> {
+        // GIVEN
+        let response = fake_advertisement(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 239), 55443), "0x15243f");
+        let dated = response.replacen("\r\n", "\r\nDate: Mon, 12 Jul 2021 18:04:11 GMT\r\n", 1);
+        let undated = response.replacen("\r\n", "\r\nDate: \r\n", 1);
+
+        // WHEN
+        let dated = parse_response(dated.as_bytes())?;
+        let undated = parse_response(undated.as_bytes())?;
+
+        // THEN
+        assert_eq!(dated.device_time(), Some("Mon, 12 Jul 2021 18:04:11 GMT"));
+        assert_eq!(undated.device_time(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn discover_by_broadcast() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41243;
+        let multicast_port = 41244;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), multicast_port);
+
+        // stands in for the broadcast address, which lights listen on with the multicast port
+        let broadcast_listener = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port))?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = fake_client(fake_sender, fake_multicast_addr);
+
+        // WHEN
+        let result = client.find_lights_broadcast(Ipv4Addr::LOCALHOST, Duration::from_millis(200))?;
+
+        // THEN
+        let mut buf = [0u8; 1024];
+        let (size, _) = broadcast_listener.recv_from(&mut buf)?;
+        assert_eq!(&buf[..size], SEARCH_MSG.as_bytes());
+        assert!(result.is_empty());
+        assert!(!client.seeker.broadcast()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_first_light() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41245;
+        let multicast_port = 41246;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41247))?;
+        for id in ["0xfirst", "0xsecond"] {
+            let fake_msg = fake_advertisement(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 55443), id);
+            fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        }
+        drop(fake_light);
+
+        // WHEN
+        let start = Instant::now();
+        let result = client.find_one(Duration::from_secs(10))?;
+
+        // THEN
+        assert_eq!(result.map(|light| light.id().to_string()).as_deref(), Some("0xfirst"));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn discover_until_quiet() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41248;
+        let multicast_port = 41249;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41250))?;
+        let fake_msg = fake_advertisement(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 55443), "0x12345abcde");
+        let responder = thread::spawn(move || {
+            // the same light answering twice, then nothing
+            for _ in 0..2 {
+                fake_light.send_to(fake_msg.as_bytes(), client_addr).unwrap();
+                sleep(Duration::from_millis(150));
+            }
+        });
+
+        // WHEN
+        let start = Instant::now();
+        let result = client.find_until_quiet(Duration::from_millis(300), Duration::from_secs(10))?;
+        let elapsed = start.elapsed();
+        responder.join().unwrap();
+
+        // THEN
+        assert_eq!(result.len(), 1);
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_secs(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_discovered_network() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41251;
+        let multicast_port = 41252;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41253))?;
+        for id in ["0xb", "0xa", "0xa"] {
+            let fake_msg = fake_advertisement(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 55443), id)
+                .replace("name: \r", "name: desk\r");
+            fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        }
+        drop(fake_light);
+
+        // WHEN
+        let snapshot = client.snapshot_network(Duration::from_millis(300))?;
+        let saved = serde_json::to_string(&snapshot)?;
+
+        // THEN
+        assert_eq!(snapshot.responses, 3);
+        let ids: Vec<&str> = snapshot.lights.iter().map(|light| light.id.as_str()).collect();
+        assert_eq!(ids, ["0xa", "0xb"]);
+        assert_eq!(snapshot.lights[0].name, "desk");
+        assert_eq!(snapshot.lights[0].state.bright, 40);
+        assert!(snapshot.lights[0].latency_ms.is_some());
+        assert!(snapshot.taken_at > 0);
+        assert_eq!(serde_json::from_str::<NetworkSnapshot>(&saved)?, snapshot);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_light_to_appear() -> anyhow::Result<()> {
+        // GIVEN
+        let client_port = 41254;
+        let multicast_port = 41255;
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, multicast_port);
+
+        // count the searches
+        let multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        multicast_listener.set_nonblocking(true)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, client_port);
+        let fake_sender = UdpSocket::bind(client_addr)?;
+
+        fake_sender.set_nonblocking(true)?;
+        let client = YeeClient { eager_connect: false, ..fake_client(fake_sender, fake_multicast_addr) };
+
+        let fake_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41256);
+        let bulb = fake_bulb(fake_addr, |_| json!(["ok"]))?;
+        let fake_light = UdpSocket::bind(fake_addr)?;
+        let responder = thread::spawn(move || {
+            // another light right away, the one waited for only after booting
+            for (id, delay) in [("0xother", 0), ("0xplug", 700)] {
+                sleep(Duration::from_millis(delay));
+                let fake_msg = fake_advertisement(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41256), id);
+                fake_light.send_to(fake_msg.as_bytes(), client_addr).unwrap();
+            }
+        });
+
+        // WHEN
+        let found = client.wait_for_light("0xplug", Duration::from_secs(5));
+        responder.join().unwrap();
+        let missing = client.wait_for_light("0xmissing", Duration::from_millis(200));
+
+        // THEN
+        let found = found.expect("light should be found");
+        assert_eq!(found.id(), "0xplug");
+        assert!(found.is_controllable());
+        assert!(missing.is_none());
+        let mut buf = [0u8; 1024];
+        let mut searches = 0;
+        while multicast_listener.recv_from(&mut buf).is_ok() {
+            searches += 1;
+        }
+        assert_eq!(searches, 3);
+        drop(found);
+        bulb.join().unwrap();
+
+        Ok(())
+    }
+}
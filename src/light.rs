@@ -1,17 +1,35 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::hash::{Hash, Hasher};
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::net::{SocketAddr, SocketAddrV4, TcpStream};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::net::{SocketAddr, SocketAddrV4, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
 
 use lazy_static::*;
 use regex::Regex;
-use serde_json::json;
+#[cfg(any(test, feature = "serde"))]
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::err::YeeError;
-use crate::fields::{ColorMode, PowerStatus, Rgb};
-use crate::req::{Req, Transition};
+use crate::LightSnapshot;
+use crate::err::{BulbErrorCode, YeeError};
+use crate::fields::{
+    ActiveMode, Capabilities, Color, ColorMode, CT_RANGE, MOONLIGHT_CT_RANGE, PowerStatus, Rgb, ServerInfo,
+};
+use crate::req::{
+    ColorFlow, ColorPath, CronType, FlowEnd, FlowTuple, MIN_SMOOTH_DURATION, Preset, Req, Scene, SceneFile, Transition,
+};
 
 /// A struct representing an active light on the local network, with interface methods and fields.
+///
+/// A light goes through three phases:
+/// 1. discovery, where [`YeeClient`](crate::YeeClient) finds it and parses its state from its advertisement,
+/// 2. connection, where a TCP connection to it is opened, by discovery itself unless `eager_connect` is off,
+///    explicitly with [`try_connect`](Self::try_connect), or implicitly by the first command,
+/// 3. commands, which change the light and keep the cached fields in sync.
 #[derive(Debug)]
 pub struct Light {
     location: SocketAddrV4,
@@ -36,15 +54,214 @@ pub struct Light {
 
     name: String,
 
+    // only reported by moonlight-capable lamps
+    active_mode: Option<ActiveMode>,
+
+    // from the `Server` header, if the light sent a well-formed one
+    server_info: Option<ServerInfo>,
+
+    // whether a color flow is running, only known after `refresh`
+    flowing: Option<bool>,
+
+    // only known for dual-light lamps that advertise it
+    background: Option<BackgroundLight>,
+
+    // from the `Cache-Control` header, how long the advertisement is valid
+    max_age: Option<Duration>,
+
+    // from the `Date` header, only populated by some firmwares
+    device_time: Option<String>,
+
+    // whether the light restores its state after losing power, only reported by some firmwares
+    save_state: Option<bool>,
+
+    // time between sending the search and receiving this light's response, if found by discovery
+    pub(crate) discovery_latency: Option<Duration>,
+
+    // how long the light has to answer when connecting, see `YeeClientBuilder::handshake_timeout`
+    pub(crate) handshake_timeout: Option<Duration>,
+
+    // whether `probe` could connect, if probed during discovery
+    pub(crate) reachable: Option<bool>,
+
+    metrics: LightMetrics,
+
+    // when the most recent smooth transition is done
+    last_transition_end: Option<Instant>,
+
+    // used by `set_power_default`
+    power_on_transition: Transition,
+    power_off_transition: Transition,
+
+    // see `set_retry_policy`
+    retries: u32,
+    retry_backoff: Duration,
+
+    // see `set_white_calibration`
+    ct_offset: i16,
+    bright_offset: i8,
+
+    // the most recent commands, oldest first, see `set_history_len`
+    history: Vec<HistoryEntry>,
+    pub(crate) history_len: usize,
+
+    // responses that arrived while waiting for another one, oldest first
+    pending_responses: VecDeque<(u16, Value)>,
+
+    // replaces the connection for lights made by `mock`
+    #[cfg(any(test, feature = "test-utils"))]
+    mock: Option<MockConnection>,
+
+    // the connection the light opened in music mode, see `start_music`
+    music: Option<BufWriter<TcpStream>>,
+    // see `prefer_music_channel`
+    prefer_music: bool,
+
     // wrapped in option for late init
     // if successfully made a Light, can always assume it is valid
     pub(crate) read: Option<BufReader<TcpStream>>,
     pub(crate) write: Option<BufWriter<TcpStream>>,
 }
 
+/// A snapshot of the adjustable state of a [`Light`].
+///
+/// Saved along with the identity of a light in a [`LightSnapshot`], from which it can be restored with
+/// [`Light::from_state`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+pub struct LightState {
+    pub power: PowerStatus,
+    pub bright: u8,
+    pub color_mode: ColorMode,
+    pub ct: u16,
+    pub rgb: Rgb,
+    pub hue: u16,
+    pub sat: u8,
+}
+
+/// Longest delay accepted by `set_delay_off`.
+pub const MAX_DELAY_OFF: Duration = Duration::from_secs(24 * 60 * 60);
+/// How far the brightness read back by `set_bright_verified` may be from the requested one.
+const BRIGHT_TOLERANCE: u8 = 1;
+
+/// Power of models at full brightness, in watts, see `estimated_power_watts` for how rough these are.
+const MAX_WATTS: [(&str, f32); 8] = [
+    ("mono", 9.0),
+    ("color", 10.0),
+    ("ct_bulb", 8.0),
+    ("stripe", 7.5),
+    ("bslamp", 10.0),
+    ("lamp", 5.0),
+    ("ceiling", 28.0),
+    ("ceiling3", 32.0),
+];
+
+/// Rated power assumed for models not in `MAX_WATTS`, that of a typical bulb.
+const DEFAULT_MAX_WATTS: f32 = 9.0;
+
+/// Length of each half of a blink by `identify`.
+const IDENTIFY_BLINK_MS: u32 = 250;
+
+/// How long to wait for the newline after a complete response, see `read_response`.
+const NEWLINE_WINDOW: Duration = Duration::from_millis(200);
+
+/// Most responses to other requests kept while waiting for a response, see `read_result`.
+const MAX_PENDING_RESPONSES: usize = 16;
+
+/// Shortest step of `set_rgb_path` through hsv.
+const HSV_PATH_STEP: Duration = Duration::from_millis(250);
+
+/// Most steps of `set_rgb_path` through hsv, however long the transition.
+const MAX_HSV_PATH_STEPS: u128 = 24;
+
+/// How long `start_music` waits for the light to connect back.
+const MUSIC_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Methods always sent over the control connection, since their results are needed and
+/// the music connection gets no responses.
+const CONTROL_ONLY_METHODS: [&str; 3] = ["get_prop", "cron_get", "set_music"];
+
+/// How long `probe` waits for the light to accept the connection.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long [`Light::connect_host`] waits for the light to answer its search.
+pub const HOST_SEARCH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Counters of the traffic between a [`Light`] and this library, see [`Light::metrics`].
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct LightMetrics {
+    /// Commands written to the light.
+    pub commands_sent: u64,
+    /// Commands that failed while being sent or were answered with an error.
+    pub errors: u64,
+    /// TCP connections opened to the light, more than one means it had to reconnect.
+    pub connections: u64,
+    /// Bytes written to the light.
+    pub bytes_written: u64,
+}
+
+/// A command sent to a [`Light`], see [`Light::history`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HistoryEntry {
+    pub method: String,
+    /// The params as JSON, cut off after 80 characters.
+    pub params: String,
+    /// Why the command failed, or `None` if it succeeded.
+    pub error: Option<String>,
+    pub sent_at: SystemTime,
+}
+
+/// Most characters of the params kept in a [`HistoryEntry`].
+const HISTORY_PARAMS_LEN: usize = 80;
+
+/// Usage counters some firmwares report, see [`Light::runtime_stats`].
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RuntimeStats {
+    /// How many times the light was turned on, if reported.
+    pub power_on_count: Option<u32>,
+    /// How long the light has been on in total, in whole minutes, if reported.
+    pub runtime: Option<Duration>,
+}
+
+/// Properties queried by `runtime_stats`, not in the spec and only reported by some firmwares.
+const RUNTIME_PROPS: [&str; 2] = ["power_on_count", "runtime"];
+
+/// Stands in for the connection of a [`Light`] made by [`Light::mock`].
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Debug, Default)]
+struct MockConnection {
+    sent: Vec<u8>,
+    pending: HashMap<u16, Req>,
+}
+
+/// Methods this library can send.
+const KNOWN_METHODS: [&str; 22] = [
+    "get_prop", "set_ct_abx", "set_rgb", "set_hsv", "set_bright", "set_power", "toggle", "adjust_bright",
+    "adjust_ct", "start_cf", "stop_cf", "set_scene", "cron_add", "cron_get", "cron_del", "set_ps", "set_name",
+    "set_default", "set_music", "bg_start_cf", "bg_stop_cf", "bg_set_scene",
+];
+
+/// Every method this library implements, to compare with [`Light::support`] and find what can be
+/// done with a light through this library.
+pub fn supported_methods() -> &'static [&'static str] {
+    &KNOWN_METHODS
+}
+
+/// The [`Capabilities`] covered by [`supported_methods`], to intersect with [`Light::capability_flags`].
+pub fn supported_capabilities() -> Capabilities {
+    Capabilities::from_methods(KNOWN_METHODS.iter().copied())
+}
+
+/// Properties queried by `get_all_props`, the same ones as in [`LightState`].
+pub const STATE_PROPS: [&str; 7] = ["power", "bright", "color_mode", "ct", "rgb", "hue", "sat"];
+
+/// Properties queried by `refresh` in one `get_prop` call: [`STATE_PROPS`], then `name`, `flowing`
+/// and `active_mode`.
+pub const REFRESH_PROPS: [&str; 10] =
+    ["power", "bright", "color_mode", "ct", "rgb", "hue", "sat", "name", "flowing", "active_mode"];
+
 lazy_static! {
     static ref MATCH_IP: Regex = Regex::new(r#"yeelight://(.*)"#).unwrap();
-    static ref MATCH_ERR_MSG: Regex = Regex::new(r#""message":"(.*)""#).unwrap();
 }
 
 macro_rules! get_field {
@@ -87,25 +304,126 @@ macro_rules! check_support {
     };
 }
 
+impl LightState {
+    /// Parse the state from discovery headers or `get_prop` results.
+    pub(crate) fn from_fields<S: AsRef<str>>(fields: &HashMap<&str, S>) -> Result<LightState, YeeError> {
+        let power = get_field!(fields, "power", PowerStatus, true)?;
+        let bright = get_field!(fields, "bright", u8)?;
+        let color_mode = get_field!(fields, "color_mode", ColorMode, true)?;
+        let ct = get_field!(fields, "ct", u16)?;
+        let rgb = get_field!(fields, "rgb", Rgb, true)?;
+        let hue: u16 = get_field!(fields, "hue", u16)?;
+        let sat = get_field!(fields, "sat", u8)?;
+        Ok(LightState { power, bright, color_mode, ct, rgb, hue, sat })
+    }
+}
+
+/// How a `props` notification changed the cached state of a [`Light`], see [`Light::apply_notification`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StateChange {
+    pub before: LightState,
+    pub after: LightState,
+    /// Whether a color flow is now running, if the notification changed it.
+    pub flowing: Option<bool>,
+}
+
+impl StateChange {
+    /// The new color mode, if it changed, e.g. because an app started a color flow.
+    pub fn mode_change(&self) -> Option<ColorMode> {
+        Some(self.after.color_mode).filter(|mode| *mode != self.before.color_mode)
+    }
+
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.before == self.after && self.flowing.is_none()
+    }
+}
+
+/// The state of the background light of a dual-light lamp, e.g. a ceiling light with an ambient ring,
+/// see [`Light::background`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+pub struct BackgroundLight {
+    pub power: PowerStatus,
+    pub bright: u8,
+    pub color_mode: ColorMode,
+    pub ct: u16,
+    pub rgb: Rgb,
+    pub hue: u16,
+    pub sat: u8,
+}
+
+impl BackgroundLight {
+    /// Parse the `bg_` fields, or `None` if any is missing, as for lamps without a background light.
+    fn from_fields<S: AsRef<str>>(fields: &HashMap<&str, S>) -> Option<BackgroundLight> {
+        // the background color mode is `bg_lmode`, the rest match the main light's fields
+        let fields: HashMap<&str, &str> = fields.iter()
+            .filter_map(|(name, value)| name.strip_prefix("bg_").map(|name| (name, value.as_ref())))
+            .map(|(name, value)| (if name == "lmode" { "color_mode" } else { name }, value))
+            .collect();
+        let LightState { power, bright, color_mode, ct, rgb, hue, sat } = LightState::from_fields(&fields).ok()?;
+        Some(BackgroundLight { power, bright, color_mode, ct, rgb, hue, sat })
+    }
+
+    fn cache_scene(&mut self, scene: &Scene) {
+        self.power = PowerStatus::On;
+        match *scene {
+            Scene::Color { color, bright } => {
+                self.bright = bright;
+                match color {
+                    Color::Rgb(rgb) => {
+                        self.rgb = rgb;
+                        self.color_mode = ColorMode::Color;
+                    }
+                    Color::Ct(temperature) => {
+                        self.ct = temperature;
+                        self.color_mode = ColorMode::ColorTemperature;
+                    }
+                    Color::Hsv { hue, sat } => {
+                        self.hue = hue;
+                        self.sat = sat;
+                        self.color_mode = ColorMode::Hsv;
+                    }
+                }
+            }
+            Scene::AutoDelayOff { bright, .. } => self.bright = bright,
+            Scene::Flow(_) => {}
+        }
+    }
+}
+
 impl Light {
     pub(crate) fn from_fields<S: AsRef<str>>(fields: &HashMap<&str, S>) -> Result<Light, YeeError> {
+        // lights send `Location` capitalized and everything else lowercase, but some firmwares and proxies
+        // change the case of headers
+        let lowercase: Vec<(String, &str)> = fields.iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.as_ref()))
+            .collect();
+        let fields: HashMap<&str, &str> = lowercase.iter().map(|(name, value)| (name.as_str(), *value)).collect();
+        Light::from_lowercase_fields(&fields)
+    }
+
+    fn from_lowercase_fields<S: AsRef<str>>(fields: &HashMap<&str, S>) -> Result<Light, YeeError> {
         let id = get_field!(fields, "id")?.to_string();
         let model = get_field!(fields, "model")?.to_string();
         let fw_ver = get_field!(fields, "fw_ver", u8)?;
-        let power = get_field!(fields, "power", PowerStatus, true)?;
-        let support: HashSet<String> = get_field!(fields, "support")?.trim()
+        let support: HashSet<String> = get_field!(fields, "support")?
             .split_whitespace()
             .map(|s| s.to_string())
             .collect();
-        let bright = get_field!(fields, "bright", u8)?;
-        let color_mode = get_field!(fields, "color_mode", ColorMode, true)?;
-        let ct = get_field!(fields, "ct", u16)?;
-        let rgb = get_field!(fields, "rgb", Rgb, true)?;
-        let hue: u16 = get_field!(fields, "hue", u16)?;
-        let sat = get_field!(fields, "sat", u8)?;
+        let LightState { power, bright, color_mode, ct, rgb, hue, sat } = LightState::from_fields(fields)?;
         let name = get_field!(fields, "name")?.to_string();
+        let active_mode = fields.get("active_mode").and_then(|s| s.as_ref().parse::<ActiveMode>().ok());
+        let save_state = fields.get("save_state").and_then(|s| parse_flag(s.as_ref()));
+        let server_info = fields.get("server").and_then(|s| s.as_ref().parse::<ServerInfo>().ok());
+        let background = BackgroundLight::from_fields(fields);
+        let max_age = fields.get("cache-control").and_then(|s| parse_max_age(s.as_ref()));
+        let device_time = fields.get("date")
+            .map(|s| s.as_ref().trim())
+            .filter(|s| !s.is_empty())
+            .map(String::from);
 
-        let location = get_field!(fields,"Location")?;
+        let location = get_field!(fields, "location")?;
         let captures = MATCH_IP
             .captures(location)
             .and_then(|c| c.get(1))
@@ -121,7 +439,146 @@ impl Light {
             _ => panic!("Light should not have an IPv6 address")
         };
 
-        Ok(Light { location, id, model, fw_ver, power, support, bright, color_mode, ct, rgb, hue, sat, name, read: None, write: None })
+        Ok(Light {
+            location, id, model, fw_ver, power, support, bright, color_mode, ct, rgb, hue, sat, name, active_mode,
+            save_state,
+            server_info,
+            flowing: None,
+            background,
+            max_age,
+            device_time,
+            discovery_latency: None,
+            handshake_timeout: None,
+            reachable: None,
+            metrics: LightMetrics::default(),
+            last_transition_end: None,
+            power_on_transition: Transition::Sudden,
+            power_off_transition: Transition::Sudden,
+            retries: 0,
+            retry_backoff: Duration::from_secs(0),
+            ct_offset: 0,
+            bright_offset: 0,
+            history: Vec::new(),
+            history_len: 0,
+            pending_responses: VecDeque::new(),
+            #[cfg(any(test, feature = "test-utils"))]
+            mock: None,
+            music: None,
+            prefer_music: false,
+            read: None,
+            write: None,
+        })
+    }
+
+    /// A [`LightSnapshot`] of this light, e.g. to save it before a restart and restore it with
+    /// [`from_state`](Self::from_state). The supported methods are sorted so saved snapshots diff cleanly.
+    pub fn snapshot(&self) -> LightSnapshot {
+        let mut support: Vec<String> = self.support.iter().cloned().collect();
+        support.sort();
+        LightSnapshot {
+            id: self.id.clone(),
+            model: self.model.clone(),
+            name: self.name.clone(),
+            location: self.location,
+            latency_ms: self.discovery_latency.map(|latency| latency.as_millis() as u64),
+            support,
+            state: self.state(),
+        }
+    }
+
+    /// Make an unconnected light from `saved`, e.g. a snapshot saved before a restart, without discovering it
+    /// again. It connects on its first command.
+    ///
+    /// Locations that can't be connected to, on an unspecified address or port 0, are rejected.
+    pub fn from_state(saved: LightSnapshot) -> Result<Light, YeeError> {
+        let LightSnapshot { id, model, name, location, latency_ms, support, state } = saved;
+        if location.ip().is_unspecified() || location.port() == 0 {
+            return Err(YeeError::InvalidValue { field_name: "location", value: location.to_string() });
+        }
+        let mut light = Light::unconnected(location, state);
+        light.id = id;
+        light.model = model;
+        light.name = name;
+        light.discovery_latency = latency_ms.map(Duration::from_millis);
+        light.support = support.into_iter().collect();
+        Ok(light)
+    }
+
+    /// An unconnected light at `location` with the cached fields of `state`, and no id, model or support.
+    fn unconnected(location: SocketAddrV4, state: LightState) -> Light {
+        let LightState { power, bright, color_mode, ct, rgb, hue, sat } = state;
+        Light {
+            location,
+            id: String::new(),
+            model: String::new(),
+            fw_ver: 0,
+            support: HashSet::new(),
+            power,
+            bright,
+            color_mode,
+            ct,
+            rgb,
+            hue,
+            sat,
+            name: String::new(),
+            active_mode: None,
+            save_state: None,
+            server_info: None,
+            flowing: None,
+            background: None,
+            max_age: None,
+            device_time: None,
+            discovery_latency: None,
+            handshake_timeout: None,
+            reachable: None,
+            metrics: LightMetrics::default(),
+            last_transition_end: None,
+            power_on_transition: Transition::Sudden,
+            power_off_transition: Transition::Sudden,
+            retries: 0,
+            retry_backoff: Duration::from_secs(0),
+            ct_offset: 0,
+            bright_offset: 0,
+            history: Vec::new(),
+            history_len: 0,
+            pending_responses: VecDeque::new(),
+            #[cfg(any(test, feature = "test-utils"))]
+            mock: None,
+            music: None,
+            prefer_music: false,
+            read: None,
+            write: None,
+        }
+    }
+
+    /// Connect to the light at `host`, e.g. a stable DNS name instead of an address assigned by DHCP,
+    /// and read its state with `get_prop`, without multicast discovery.
+    ///
+    /// The id, model and support are taken from the light's answer to a search sent to `host` alone, which
+    /// must come within [`HOST_SEARCH_TIMEOUT`]. Returns [`YeeError::InvalidValue`] if `host` resolves to no
+    /// IPv4 address.
+    pub fn connect_host(host: &str, port: u16) -> Result<Light, YeeError> {
+        Light::connect_host_searching(host, port, crate::MULTICAST_PORT)
+    }
+
+    fn connect_host_searching(host: &str, port: u16, search_port: u16) -> Result<Light, YeeError> {
+        let location = (host, port).to_socket_addrs()?
+            .find_map(|addr| match addr {
+                SocketAddr::V4(v4) => Some(v4),
+                _ => None
+            })
+            .ok_or_else(|| YeeError::InvalidValue { field_name: "host", value: host.to_string() })?;
+        let socket = UdpSocket::bind(SocketAddrV4::new(crate::ALL_LOCAL, 0))?;
+        socket.set_read_timeout(Some(HOST_SEARCH_TIMEOUT))?;
+        socket.send_to(crate::SEARCH_MSG.as_bytes(), SocketAddrV4::new(*location.ip(), search_port))?;
+        let mut buf = [0u8; 1024];
+        let (len, _) = socket.recv_from(&mut buf)?;
+        let mut light = crate::parse_response(&buf[..len])?;
+        // the advertised location may not be the port asked for, e.g. behind port forwarding
+        light.location = location;
+        light.init()?;
+        light.refresh()?;
+        Ok(light)
     }
 
     pub(crate) fn init(&mut self) -> Result<(), YeeError> {
@@ -129,69 +586,367 @@ impl Light {
             return Ok(());
         }
         let connection = TcpStream::connect(self.location)?;
+        // responses to requests sent over the old connection will never be waited for
+        self.pending_responses.clear();
         self.write = Some(BufWriter::new(connection.try_clone()?));
         self.read = Some(BufReader::new(connection));
+        self.metrics.connections += 1;
+        if let Some(timeout) = self.handshake_timeout {
+            self.handshake(timeout)?;
+        }
         Ok(())
     }
 
-    pub fn set_ct_abx(&mut self, temperature: u16, transition: Transition) -> Result<(), YeeError> {
-        check_support!(self, "set_ct_abx")?;
-        // SPEC IS WRONG: temperature bounds should be 2700-6500
-        if !(2700..=6500).contains(&temperature) {
-            return Err(YeeError::InvalidValue { field_name: "ct", value: temperature.to_string() });
+    /// Check the light answers a cheap `get_prop` within `timeout`, dropping the connection if not,
+    /// so the next `init` tries again.
+    fn handshake(&mut self, timeout: Duration) -> Result<(), YeeError> {
+        let result = self.try_handshake(timeout);
+        if result.is_err() {
+            self.read = None;
+            self.write = None;
         }
-        let req = Req::new("set_ct_abx".to_string(),
-                           vec![json!(temperature), json!(transition.text()), json!(transition.value())]);
-        self.send_req(&req)?;
+        result
+    }
+
+    fn try_handshake(&mut self, timeout: Duration) -> Result<(), YeeError> {
+        self.read.as_ref().unwrap().get_ref().set_read_timeout(Some(timeout))?;
+        let req = Req::new("get_prop".to_string(), vec![json!("power")]);
+        match self.write_req(&req).and_then(|_| self.read_result(req.id)) {
+            // an error sent by the light is still an answer
+            Ok(_) | Err(YeeError::ChangeFailed { .. }) => {
+                self.read.as_ref().unwrap().get_ref().set_read_timeout(None)?;
+                Ok(())
+            }
+            Err(_) => Err(YeeError::Unresponsive { timeout })
+        }
+    }
+
+    /// Connect to the light if not already connected, then read its state with `get_prop`,
+    /// since the state advertised during discovery may be out of date.
+    pub fn init_and_sync(&mut self) -> Result<(), YeeError> {
+        self.init()?;
+        self.refresh()
+    }
+
+    /// Connect to the light if not already connected, e.g. to check it is reachable before using it.
+    pub fn try_connect(&mut self) -> Result<(), YeeError> {
+        self.init()
+    }
+
+    /// Drop the connection if the light closed it, and return whether the light is still connected.
+    pub(crate) fn check_connection(&mut self) -> bool {
+        let alive = match self.read.as_ref().map(BufReader::get_ref) {
+            Some(stream) => {
+                let mut byte = [0u8];
+                // a closed connection reads as EOF right away, a live one has nothing to read
+                let alive = stream.set_nonblocking(true).is_ok() && match stream.peek(&mut byte) {
+                    Ok(0) => false,
+                    Ok(_) => true,
+                    Err(e) => e.kind() == ErrorKind::WouldBlock
+                };
+                alive && stream.set_nonblocking(false).is_ok()
+            }
+            None => false
+        };
+        if !alive {
+            self.read = None;
+            self.write = None;
+        }
+        alive
+    }
+
+    /// Take over the connection and metrics of `old`, an earlier instance of the same light.
+    pub(crate) fn adopt_connection(&mut self, old: Light) {
+        self.read = old.read;
+        self.write = old.write;
+        self.metrics = old.metrics;
+        self.last_transition_end = old.last_transition_end;
+    }
+
+    /// Whether the light accepts a connection within a second, without keeping it open or changing
+    /// this light, unlike [`try_connect`](Self::try_connect).
+    pub fn probe(&self) -> bool {
+        TcpStream::connect_timeout(&SocketAddr::V4(self.location), PROBE_TIMEOUT).is_ok()
+    }
+
+    /// Whether the light is connected and ready to take commands without connecting first.
+    pub fn is_controllable(&self) -> bool {
+        self.read.is_some() && self.write.is_some()
+    }
+
+    /// Set the color temperature, corrected by [`set_white_calibration`](Self::set_white_calibration).
+    pub fn set_ct_abx(&mut self, temperature: u16, transition: Transition) -> Result<(), YeeError> {
+        let range = self.ct_range();
+        let corrected = if self.ct_offset != 0 && range.contains(&temperature) {
+            (temperature as i32 + self.ct_offset as i32).clamp(*range.start() as i32, *range.end() as i32) as u16
+        } else {
+            temperature
+        };
+        self.set_color(Color::Ct(corrected), transition)?;
         self.ct = temperature;
         Ok(())
     }
 
     pub fn set_rgb(&mut self, rgb: Rgb, transition: Transition) -> Result<(), YeeError> {
-        check_support!(self, "set_rgb")?;
-        let req = Req::new("set_rgb".to_string(),
-                           vec![json!(rgb.get_num()), json!(transition.text()), json!(transition.value())]);
-        self.send_req(&req)?;
-        self.rgb = rgb;
-        Ok(())
+        self.set_color(Color::Rgb(rgb), transition)
+    }
+
+    /// Set the color from a `0xRRGGBB` literal, e.g. `light.set_rgb_hex(0xFF8800, Transition::sudden())`.
+    pub fn set_rgb_hex(&mut self, hex: u32, transition: Transition) -> Result<(), YeeError> {
+        if hex > 0xFFFFFF {
+            return Err(YeeError::InvalidValue { field_name: "rgb", value: format!("{:#x}", hex) });
+        }
+        self.set_rgb(Rgb::from(hex), transition)
+    }
+
+    /// Set the color from linear channels in 0.0-1.0, see [`Rgb::from_linear`].
+    pub fn set_rgb_linear(&mut self, red: f32, green: f32, blue: f32, transition: Transition) -> Result<(), YeeError> {
+        self.set_rgb(Rgb::from_linear(red, green, blue), transition)
+    }
+
+    /// Set the color to the CIE 1931 xy chromaticity `x`, `y`, both in 0.0-1.0, see [`Rgb::from_xy`].
+    ///
+    /// Only the color changes, the brightness is left as is.
+    pub fn set_xy(&mut self, x: f32, y: f32, transition: Transition) -> Result<(), YeeError> {
+        if !(0.0..=1.0).contains(&x) {
+            return Err(YeeError::InvalidValue { field_name: "x", value: x.to_string() });
+        } else if !(0.0..=1.0).contains(&y) || y == 0.0 || x + y > 1.0 {
+            return Err(YeeError::InvalidValue { field_name: "y", value: y.to_string() });
+        }
+        self.set_rgb(Rgb::from_xy(x, y, u8::MAX), transition)
+    }
+
+    /// Set the red channel of the cached color, keeping green and blue, e.g. for a slider per channel.
+    ///
+    /// Switches the light to rgb mode, starting from the last rgb color if it was in another mode.
+    pub fn set_red(&mut self, red: u8, transition: Transition) -> Result<(), YeeError> {
+        self.set_rgb(Rgb { red, ..self.rgb }, transition)
     }
 
+    /// Set the green channel of the cached color, keeping red and blue, like [`set_red`](Self::set_red).
+    pub fn set_green(&mut self, green: u8, transition: Transition) -> Result<(), YeeError> {
+        self.set_rgb(Rgb { green, ..self.rgb }, transition)
+    }
+
+    /// Set the blue channel of the cached color, keeping red and green, like [`set_red`](Self::set_red).
+    pub fn set_blue(&mut self, blue: u8, transition: Transition) -> Result<(), YeeError> {
+        self.set_rgb(Rgb { blue, ..self.rgb }, transition)
+    }
+
+    /// Set the brightness, corrected by [`set_white_calibration`](Self::set_white_calibration).
     pub fn set_bright(&mut self, brightness: u8, transition: Transition) -> Result<(), YeeError> {
         check_support!(self, "set_bright")?;
         if !(1..=100).contains(&brightness) {
             return Err(YeeError::InvalidValue { field_name: "bright", value: brightness.to_string() });
         }
+        transition.validate()?;
+        let corrected = self.calibrated_bright(brightness);
         let req = Req::new("set_bright".to_string(),
-                           vec![json!(brightness), json!(transition.text()), json!(transition.value())]);
+                           vec![json!(corrected), json!(transition.text()), json!(transition.value())]);
         self.send_req(&req)?;
         self.bright = brightness;
+        self.track_transition(transition);
         Ok(())
     }
 
+    /// Like [`set_bright`](Self::set_bright), but `perceived` is how bright the light should look, from 1 to 100,
+    /// so evenly spaced values look evenly spaced, e.g. for a dimmer slider.
+    ///
+    /// The light's brightness is linear, so `perceived` is taken as CIE 1976 lightness (L*) and sent as the
+    /// matching relative luminance: `((L* + 16) / 116)³`, or `L* / 903.3` below 8, rounded and at least 1.
+    /// Half the perceived brightness is about 18% on the light. The cached brightness is the one sent.
+    pub fn set_bright_perceptual(&mut self, perceived: u8, transition: Transition) -> Result<(), YeeError> {
+        if !(1..=100).contains(&perceived) {
+            return Err(YeeError::InvalidValue { field_name: "bright", value: perceived.to_string() });
+        }
+        self.set_bright(perceived_to_linear(perceived), transition)
+    }
+
+    /// Offset every later [`set_ct_abx`](Self::set_ct_abx) by `ct_offset` kelvin and [`set_bright`](Self::set_bright)
+    /// by `bright_offset` percent, clamped to the valid ranges, e.g. to match a light from another batch
+    /// to its neighbors. Both are zero by default.
+    ///
+    /// The cached color temperature and brightness stay the ones asked for, before the offsets.
+    pub fn set_white_calibration(&mut self, ct_offset: i16, bright_offset: i8) {
+        self.ct_offset = ct_offset;
+        self.bright_offset = bright_offset;
+    }
+
+    /// The offsets set by [`set_white_calibration`](Self::set_white_calibration).
+    pub fn white_calibration(&self) -> (i16, i8) {
+        (self.ct_offset, self.bright_offset)
+    }
+
+    fn calibrated_bright(&self, brightness: u8) -> u8 {
+        (brightness as i16 + self.bright_offset as i16).clamp(1, 100) as u8
+    }
+
+    fn ct_range(&self) -> RangeInclusive<u16> {
+        match self.active_mode {
+            Some(ActiveMode::Moonlight) => MOONLIGHT_CT_RANGE,
+            _ => CT_RANGE
+        }
+    }
+
     pub fn set_hsv(&mut self, hue: u16, sat: u8, transition: Transition) -> Result<(), YeeError> {
-        check_support!(self, "set_hsv")?;
-        if !(0..=359).contains(&hue) {
-            return Err(YeeError::InvalidValue { field_name: "hue", value: hue.to_string() });
-        } else if !(0..=100).contains(&sat) {
-            return Err(YeeError::InvalidValue { field_name: "sat", value: sat.to_string() });
-        }
-        let req = Req::new("set_hsv".to_string(),
-                           vec![json!(hue), json!(sat), json!(transition.text()), json!(transition.value())]);
+        self.set_color(Color::Hsv { hue, sat }, transition)
+    }
+
+    /// Set the hue, saturation and brightness together, to avoid a visible step between the color
+    /// and the brightness changing.
+    ///
+    /// Sudden changes are made in one command with `set_scene` if supported, turning the light on if it was off.
+    /// Smooth ones are made with a one-step `start_cf` to the rgb equivalent of the color, see [`Rgb::from_hsv`],
+    /// so the light is left in rgb mode, after turning it on if it was off. Otherwise, the light is turned on
+    /// if needed and the color and brightness are set in turn with `transition`, so the change isn't atomic.
+    pub fn set_hsv_bright(&mut self, hue: u16, sat: u8, bright: u8, transition: Transition) -> Result<(), YeeError> {
+        let scene = Scene::Color { color: Color::Hsv { hue, sat }, bright };
+        scene.validate()?;
+        if transition == Transition::Sudden && self.support.contains("set_scene") {
+            return self.set_scene(&scene);
+        }
+        if self.power != PowerStatus::On {
+            self.set_power(PowerStatus::On, transition)?;
+        }
+        match transition {
+            Transition::Smooth { duration } if self.support.contains("start_cf") => {
+                let rgb = Rgb::from_hsv(hue, sat);
+                let step = FlowTuple::rgb(duration.as_millis() as u32, rgb, bright as i8);
+                self.start_cf(&ColorFlow::new(1, FlowEnd::Stay, vec![step]))?;
+                self.hue = hue;
+                self.sat = sat;
+                self.cache_color(Color::Rgb(rgb));
+                self.bright = bright;
+                Ok(())
+            }
+            _ => {
+                self.set_hsv(hue, sat, transition)?;
+                self.set_bright(bright, transition)
+            }
+        }
+    }
+
+    /// Set the color to the complement of the cached color, see [`Rgb::complement`].
+    ///
+    /// Lights in hsv mode get the opposite hue instead. Color temperature has no complement,
+    /// so lights in that mode return [`YeeError::InvalidValue`].
+    pub fn set_complement(&mut self, transition: Transition) -> Result<(), YeeError> {
+        match self.color_mode {
+            ColorMode::Color => self.set_rgb(self.rgb.complement(), transition),
+            ColorMode::Hsv => self.set_hsv((self.hue + 180) % 360, self.sat, transition),
+            ColorMode::ColorTemperature =>
+                Err(YeeError::InvalidValue { field_name: "color_mode", value: self.color_mode.to_string() })
+        }
+    }
+
+    /// Set the color with `set_rgb`, `set_ct_abx` or `set_hsv`, depending on the mode of `color`.
+    pub fn set_color(&mut self, color: Color, transition: Transition) -> Result<(), YeeError> {
+        let req = self.color_req(color, transition)?;
         self.send_req(&req)?;
-        self.hue = hue;
-        self.sat = sat;
+        self.cache_color(color);
+        self.track_transition(transition);
         Ok(())
     }
 
-    pub fn set_power(&mut self, power: PowerStatus, transition: Transition) -> Result<(), YeeError> {
+    /// Validate `color` and build the request that sets it, without sending it.
+    pub(crate) fn color_req(&self, color: Color, transition: Transition) -> Result<Req, YeeError> {
+        transition.validate()?;
+        match color {
+            Color::Rgb(rgb) => {
+                check_support!(self, "set_rgb")?;
+                Ok(Req::new("set_rgb".to_string(),
+                            vec![json!(rgb.get_num()), json!(transition.text()), json!(transition.value())]))
+            }
+            Color::Ct(temperature) => {
+                check_support!(self, "set_ct_abx")?;
+                if !self.ct_range().contains(&temperature) {
+                    return Err(YeeError::InvalidValue { field_name: "ct", value: temperature.to_string() });
+                }
+                Ok(Req::new("set_ct_abx".to_string(),
+                            vec![json!(temperature), json!(transition.text()), json!(transition.value())]))
+            }
+            Color::Hsv { hue, sat } => {
+                check_support!(self, "set_hsv")?;
+                if !(0..=359).contains(&hue) {
+                    return Err(YeeError::InvalidValue { field_name: "hue", value: hue.to_string() });
+                } else if !(0..=100).contains(&sat) {
+                    return Err(YeeError::InvalidValue { field_name: "sat", value: sat.to_string() });
+                }
+                Ok(Req::new("set_hsv".to_string(),
+                            vec![json!(hue), json!(sat), json!(transition.text()), json!(transition.value())]))
+            }
+        }
+    }
+
+    /// Update the cached fields after `color` was successfully set.
+    pub(crate) fn cache_color(&mut self, color: Color) {
+        match color {
+            Color::Rgb(rgb) => {
+                self.rgb = rgb;
+                self.color_mode = ColorMode::Color;
+            }
+            Color::Ct(temperature) => {
+                self.ct = temperature;
+                self.color_mode = ColorMode::ColorTemperature;
+            }
+            Color::Hsv { hue, sat } => {
+                self.hue = hue;
+                self.sat = sat;
+                self.color_mode = ColorMode::Hsv;
+            }
+        }
+    }
+
+    /// The request to set the power, also sent by [`LightGroup`](crate::LightGroup).
+    pub(crate) fn power_req(&self, power: PowerStatus, transition: Transition) -> Result<Req, YeeError> {
         check_support!(self, "set_power")?;
-        let req = Req::new("set_power".to_string(),
-                           vec![json!(power.to_string()), json!(transition.text()), json!(transition.value())]);
-        self.send_req(&req)?;
+        transition.validate()?;
+        Ok(Req::new("set_power".to_string(),
+                    vec![json!(power.to_string()), json!(transition.text()), json!(transition.value())]))
+    }
+
+    /// Update the cached power after the light accepted a `power_req`.
+    pub(crate) fn cache_power(&mut self, power: PowerStatus) {
         self.power = power;
+    }
+
+    pub fn set_power(&mut self, power: PowerStatus, transition: Transition) -> Result<(), YeeError> {
+        let req = self.power_req(power, transition)?;
+        self.send_req(&req)?;
+        self.cache_power(power);
+        self.track_transition(transition);
         Ok(())
     }
 
+    /// Set the transitions used by [`set_power_default`](Self::set_power_default), both sudden by default,
+    /// e.g. to turn on quickly but fade out slowly.
+    pub fn set_power_transitions(&mut self, on: Transition, off: Transition) {
+        self.power_on_transition = on;
+        self.power_off_transition = off;
+    }
+
+    /// Set the power with the transition for `power` set by [`set_power_transitions`](Self::set_power_transitions).
+    pub fn set_power_default(&mut self, power: PowerStatus) -> Result<(), YeeError> {
+        let transition = match power {
+            PowerStatus::On => self.power_on_transition,
+            PowerStatus::Off => self.power_off_transition
+        };
+        self.set_power(power, transition)
+    }
+
+    /// Set the power to `desired` only if the cached power differs, to avoid a redundant command,
+    /// returning whether one was sent.
+    ///
+    /// Unlike [`toggle`](Self::toggle), this relies on the cached state,
+    /// so [`refresh`](Self::refresh) first if the light may have been changed elsewhere.
+    pub fn ensure_power(&mut self, desired: PowerStatus, transition: Transition) -> Result<bool, YeeError> {
+        if self.power == desired {
+            return Ok(false);
+        }
+        self.set_power(desired, transition)?;
+        Ok(true)
+    }
+
     pub fn toggle(&mut self) -> Result<(), YeeError> {
         check_support!(self, "toggle")?;
         let req = Req::new("toggle".to_string(), vec![]);
@@ -201,308 +956,3308 @@ impl Light {
     }
 
     pub fn adjust_bright(&mut self, bright_percentage: i8, transition: Transition) -> Result<(), YeeError> {
-        if !(-100 as i8..=100).contains(&bright_percentage) {
+        if !(-100_i8..=100).contains(&bright_percentage) {
             return Err(YeeError::InvalidValue { field_name: "bright", value: bright_percentage.to_string() });
         }
         check_support!(self, "adjust_bright")?;
+        transition.validate()?;
         let req = Req::new("adjust_bright".to_string(), vec![json!(bright_percentage), json!(transition.value())]);
         self.send_req(&req)?;
         if bright_percentage > 0 {
             self.bright += self.bright * bright_percentage as u8 / 100;
         } else {
-            self.bright -= self.bright * bright_percentage.abs() as u8 / 100;
+            self.bright -= self.bright * bright_percentage.unsigned_abs() / 100;
         }
+        self.track_transition(transition);
         Ok(())
     }
 
     pub fn adjust_ct(&mut self, ct_percentage: i8, transition: Transition) -> Result<(), YeeError> {
         check_support!(self, "adjust_ct")?;
-        if !(-100 as i8..=100).contains(&ct_percentage) {
+        if !(-100_i8..=100).contains(&ct_percentage) {
             return Err(YeeError::InvalidValue { field_name: "ct", value: ct_percentage.to_string() });
         }
         check_support!(self, "adjust_ct")?;
+        transition.validate()?;
         let req = Req::new("adjust_ct".to_string(), vec![json!(ct_percentage), json!(transition.value())]);
         self.send_req(&req)?;
         if ct_percentage > 0 {
             self.ct += self.ct * ct_percentage as u16 / 100;
         } else {
-            self.ct -= self.ct * ct_percentage.abs() as u16
+            self.ct -= self.ct * ct_percentage.unsigned_abs() as u16
         }
+        self.track_transition(transition);
         Ok(())
     }
 
-    pub(crate) fn send_req(&mut self, req: &Req) -> Result<(), YeeError> {
-        let rand_val = req.id.to_string();
-        let mut json = serde_json::to_string(req).unwrap();
-        let reader = self.read.as_mut().unwrap();
-        let writer = self.write.as_mut().unwrap();
-        json.push_str("\r\n");
-        writer.write_all(json.as_bytes())?;
-        writer.flush()?;
+    /// Set the name of the light, which it keeps across restarts and reports during discovery.
+    ///
+    /// Some lights silently truncate names or drop characters, use [`set_name_verified`](Self::set_name_verified)
+    /// to catch that.
+    pub fn set_name(&mut self, name: &str) -> Result<(), YeeError> {
+        check_support!(self, "set_name")?;
+        self.send_req(&Req::new("set_name".to_string(), vec![json!(name)]))?;
+        self.name = name.to_string();
+        Ok(())
+    }
 
-        let mut buf = String::new();
-        let rand_val = rand_val.to_string();
-        while !buf.contains(rand_val.as_str()) {
-            reader.read_line(&mut buf)?;
-        }
-        if buf.contains("error") {
-            let s =
-                MATCH_ERR_MSG.captures(&buf)
-                    .and_then(|c| c.get(0))
-                    .map(|s| s.as_str().to_string())
-                    .unwrap_or_else(String::new);
-            Err(YeeError::ChangeFailed { message: s })
-        } else {
-            Ok(())
+    /// Set the name, then read it back from the light.
+    ///
+    /// Returns [`YeeError::NotApplied`] if the light stored a different name, which is then cached instead.
+    pub fn set_name_verified(&mut self, name: &str) -> Result<(), YeeError> {
+        self.set_name(name)?;
+        let actual = self.get_prop(&["name"])?
+            .remove("name")
+            .ok_or(YeeError::FieldNotFound { field_name: "name" })?;
+        if actual != name {
+            self.name = actual.clone();
+            return Err(YeeError::NotApplied {
+                field_name: "name",
+                expected: format!("{:?}", name),
+                actual: format!("{:?}", actual),
+            });
         }
+        Ok(())
     }
 
-    pub fn location(&self) -> &SocketAddrV4 {
-        &self.location
+    /// Set the brightness, then read it back from the light once the transition is done.
+    ///
+    /// Returns the brightness the light actually ended up at,
+    /// or [`YeeError::NotApplied`] if it differs from `brightness` by more than 1.
+    pub fn set_bright_verified(&mut self, brightness: u8, transition: Transition) -> Result<u8, YeeError> {
+        self.set_bright(brightness, transition)?;
+        self.wait_for_last_transition();
+        let actual = self.get_prop(&["bright"])?
+            .get("bright")
+            .ok_or(YeeError::FieldNotFound { field_name: "bright" })?
+            .parse::<u8>()
+            .map_err(|e| YeeError::ParseFieldFailed { field_name: "bright", source: Some(e) })?;
+        self.bright = actual;
+        let expected = self.calibrated_bright(brightness);
+        if (actual as i16 - expected as i16).unsigned_abs() > BRIGHT_TOLERANCE as u16 {
+            return Err(YeeError::NotApplied {
+                field_name: "bright",
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+        Ok(actual)
     }
 
-    pub fn id(&self) -> &str {
-        &self.id
+    /// Query the current values of `props` from the light.
+    ///
+    /// Properties the light doesn't support are returned as empty strings.
+    /// Querying `save_state` also updates [`saves_state`](Self::saves_state).
+    ///
+    /// During a smooth transition the light reports the values it is passing through, not the target,
+    /// see [`sample_during_transition`](Self::sample_during_transition).
+    pub fn get_prop(&mut self, props: &[&str]) -> Result<HashMap<String, String>, YeeError> {
+        check_support!(self, "get_prop")?;
+        let req = Req::new("get_prop".to_string(), props.iter().map(|p| json!(p)).collect());
+        let result = self.send_req(&req)?;
+        let values = result.as_array()
+            .filter(|values| values.len() == props.len())
+            .ok_or(YeeError::ParseFieldFailed { field_name: "result", source: None })?;
+        let props: HashMap<String, String> = props.iter()
+            .zip(values)
+            .map(|(prop, value)| {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string()
+                };
+                (prop.to_string(), value)
+            })
+            .collect();
+        if let Some(save_state) = props.get("save_state") {
+            self.save_state = parse_flag(save_state);
+        }
+        Ok(props)
     }
 
-    pub fn model(&self) -> &str {
-        &self.model
+    /// Set whether the light restores its state after losing power, e.g. when switched by a smart plug,
+    /// instead of turning on with its defaults.
+    pub fn set_save_state_on_change(&mut self, enabled: bool) -> Result<(), YeeError> {
+        check_support!(self, "set_ps")?;
+        let value = if enabled { "1" } else { "0" };
+        self.send_req(&Req::new("set_ps".to_string(), vec![json!("cfg_save_state"), json!(value)]))?;
+        self.save_state = Some(enabled);
+        Ok(())
     }
 
-    pub fn fw_ver(&self) -> u8 {
-        self.fw_ver
+    /// Save the current state as the one the light turns on with after losing power.
+    pub fn set_default(&mut self) -> Result<(), YeeError> {
+        check_support!(self, "set_default")?;
+        self.send_req(&Req::new("set_default".to_string(), vec![]))?;
+        Ok(())
     }
 
-    pub fn support(&self) -> &HashSet<String> {
-        &self.support
+    /// Turn the light on at color temperature `ct` and brightness `bright`, then save that as its default
+    /// with [`set_default`](Self::set_default), e.g. to bring every light back to the same warm white.
+    ///
+    /// Both values are checked before anything is sent, then the first failing command is returned.
+    pub fn normalize(&mut self, ct: u16, bright: u8) -> Result<(), YeeError> {
+        if !CT_RANGE.contains(&ct) {
+            return Err(YeeError::InvalidValue { field_name: "ct", value: ct.to_string() });
+        }
+        if !(1..=100).contains(&bright) {
+            return Err(YeeError::InvalidValue { field_name: "bright", value: bright.to_string() });
+        }
+        self.ensure_power(PowerStatus::On, Transition::sudden())?;
+        self.set_ct_abx(ct, Transition::sudden())?;
+        self.set_bright(bright, Transition::sudden())?;
+        self.set_default()
     }
 
-    pub fn power(&self) -> &PowerStatus {
-        &self.power
+    /// Read the brightness `count` times, `interval` apart, e.g. to follow a smooth transition
+    /// on a slider in a UI.
+    ///
+    /// The cached brightness is left alone, since the samples may be intermediate.
+    pub fn sample_during_transition(&mut self, interval: Duration, count: usize) -> Result<Vec<u8>, YeeError> {
+        let mut samples = Vec::with_capacity(count);
+        for i in 0..count {
+            if i > 0 {
+                sleep(interval);
+            }
+            let bright = self.get_prop(&["bright"])?
+                .remove("bright")
+                .ok_or(YeeError::FieldNotFound { field_name: "bright" })?
+                .parse::<u8>()
+                .map_err(|e| YeeError::ParseFieldFailed { field_name: "bright", source: Some(e) })?;
+            samples.push(bright);
+        }
+        Ok(samples)
     }
 
-    pub fn bright(&self) -> u8 {
-        self.bright
+    /// Query the usage counters of the light, leaving out the ones it doesn't report.
+    pub fn runtime_stats(&mut self) -> Result<RuntimeStats, YeeError> {
+        let props = self.get_prop(&RUNTIME_PROPS)?;
+        // unsupported properties are empty, treat anything unparseable the same
+        let number = |prop: &str| props.get(prop).and_then(|value| value.parse::<u32>().ok());
+        Ok(RuntimeStats {
+            power_on_count: number("power_on_count"),
+            runtime: number("runtime").map(|minutes| Duration::from_secs(minutes as u64 * 60)),
+        })
     }
 
-    pub fn color_mode(&self) -> &ColorMode {
-        &self.color_mode
+    /// Run `flow` on the light.
+    pub fn start_cf(&mut self, flow: &ColorFlow) -> Result<(), YeeError> {
+        check_support!(self, "start_cf")?;
+        flow.validate()?;
+        self.send_req(&Req::new("start_cf".to_string(), flow.params()))?;
+        Ok(())
     }
 
-    pub fn ct(&self) -> u16 {
-        self.ct
+    /// Run `flow` starting from `baseline`, so a flow ending in [`FlowEnd::Recover`] returns to `baseline`
+    /// instead of the state before this call.
+    ///
+    /// `baseline` is applied suddenly with [`apply_state_diff`](Self::apply_state_diff) before the flow starts,
+    /// since the light recovers to the state it was in when the flow started. Flows can't run while the light
+    /// is off, so for an off `baseline` the light is turned on for the flow and a recovering flow turns it off
+    /// at the end instead. Other end actions are unaffected, apart from starting from `baseline`.
+    pub fn start_cf_with_baseline(&mut self, flow: &ColorFlow, baseline: LightState) -> Result<(), YeeError> {
+        check_support!(self, "start_cf")?;
+        flow.validate()?;
+        let ends_off = baseline.power == PowerStatus::Off && flow.end == FlowEnd::Recover;
+        self.apply_state_diff(&LightState { power: PowerStatus::On, ..baseline }, Transition::sudden())?;
+        if ends_off {
+            self.start_cf(&ColorFlow { end: FlowEnd::TurnOff, ..flow.clone() })
+        } else {
+            self.start_cf(flow)
+        }
     }
 
-    pub fn rgb(&self) -> &Rgb {
-        &self.rgb
-    }
+    /// Stop a running color flow.
+    pub fn stop_cf(&mut self) -> Result<(), YeeError> {
+        check_support!(self, "stop_cf")?;
+        let req = Req::new("stop_cf".to_string(), vec![]);
+        self.send_req(&req)?;
+        Ok(())
+    }
+
+    /// Check `scene`, with color temperatures in the range this light accepts in its active mode, see
+    /// [`set_ct_abx`](Self::set_ct_abx).
+    fn validate_scene(&self, scene: &Scene) -> Result<(), YeeError> {
+        scene.validate()?;
+        match *scene {
+            Scene::Color { color: Color::Ct(ct), .. } if !self.ct_range().contains(&ct) =>
+                Err(YeeError::InvalidValue { field_name: "ct", value: ct.to_string() }),
+            _ => Ok(())
+        }
+    }
+
+    /// Set the light to `scene`, turning it on if it was off.
+    pub fn set_scene(&mut self, scene: &Scene) -> Result<(), YeeError> {
+        check_support!(self, "set_scene")?;
+        self.validate_scene(scene)?;
+        self.send_req(&Req::new("set_scene".to_string(), scene.params()))?;
+        self.power = PowerStatus::On;
+        match *scene {
+            Scene::Color { color, bright } => {
+                self.bright = bright;
+                self.cache_color(color);
+            }
+            Scene::AutoDelayOff { bright, .. } => self.bright = bright,
+            Scene::Flow(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Set the light to `preset`, turning it on if it was off.
+    ///
+    /// Sudden presets are set in one command with `set_scene` if supported,
+    /// otherwise the power, color and brightness are set in turn with `transition`.
+    pub fn apply_preset(&mut self, preset: Preset, transition: Transition) -> Result<(), YeeError> {
+        if transition == Transition::Sudden && self.support.contains("set_scene") {
+            return self.set_scene(&preset.scene());
+        }
+        if self.power != PowerStatus::On {
+            self.set_power(PowerStatus::On, transition)?;
+        }
+        self.set_color(preset.color(), transition)?;
+        self.set_bright(preset.bright(), transition)
+    }
+
+    /// Run through the steps of `scene` in order, waiting for each transition and delay before the next step.
+    ///
+    /// The whole file is validated before anything is sent. Blocks until the last step is done.
+    pub fn play_scene_file(&mut self, scene: &SceneFile) -> Result<(), YeeError> {
+        scene.validate()?;
+        for step in scene.steps.iter() {
+            if let Some(color) = step.color {
+                self.set_color(color, step.transition)?;
+            }
+            if let Some(bright) = step.bright {
+                self.set_bright(bright, step.transition)?;
+            }
+            self.wait_for_last_transition();
+            sleep(Duration::from_millis(step.delay_ms));
+        }
+        Ok(())
+    }
+
+    /// Run `flow` on the background light of lamps that have one, see [`start_cf`](Self::start_cf).
+    pub fn bg_start_cf(&mut self, flow: &ColorFlow) -> Result<(), YeeError> {
+        check_support!(self, "bg_start_cf")?;
+        flow.validate()?;
+        self.send_req(&Req::new("bg_start_cf".to_string(), flow.params()))?;
+        Ok(())
+    }
+
+    /// Stop a color flow running on the background light.
+    pub fn bg_stop_cf(&mut self) -> Result<(), YeeError> {
+        check_support!(self, "bg_stop_cf")?;
+        let req = Req::new("bg_stop_cf".to_string(), vec![]);
+        self.send_req(&req)?;
+        Ok(())
+    }
+
+    /// Set the background light to `scene`, see [`set_scene`](Self::set_scene).
+    ///
+    /// Updates [`background`](Self::background) if its state is known.
+    pub fn bg_set_scene(&mut self, scene: &Scene) -> Result<(), YeeError> {
+        check_support!(self, "bg_set_scene")?;
+        self.validate_scene(scene)?;
+        self.send_req(&Req::new("bg_set_scene".to_string(), scene.params()))?;
+        if let Some(background) = self.background.as_mut() {
+            background.cache_scene(scene);
+        }
+        Ok(())
+    }
+
+    /// Briefly blink the light so it can be told apart from others, then return it to its prior state.
+    ///
+    /// Blinks with a color flow if supported, otherwise toggles the power off and on again.
+    pub fn identify(&mut self) -> Result<(), YeeError> {
+        if self.support.contains("start_cf") {
+            let ct = if CT_RANGE.contains(&self.ct) { self.ct } else { 4000 };
+            let blink = vec![FlowTuple::ct(IDENTIFY_BLINK_MS, ct, 100), FlowTuple::ct(IDENTIFY_BLINK_MS, ct, 1)];
+            self.start_cf(&ColorFlow::new(4, FlowEnd::Recover, blink))
+        } else {
+            self.toggle()?;
+            sleep(Duration::from_millis(2 * IDENTIFY_BLINK_MS as u64));
+            self.toggle()
+        }
+    }
+
+    /// Fade from the cached color to `to` over `duration` through `steps` linearly interpolated colors.
+    ///
+    /// Unlike the native transition of `set_rgb`, which may take a shortcut through other hues,
+    /// this goes straight through RGB. Runs as a color flow if supported, otherwise steps with `set_rgb`
+    /// and blocks until done. Each step must be at least 50ms long.
+    ///
+    /// Like [`ramp_bright`](Self::ramp_bright), `cancel` is checked before each step, leaving the light at the last
+    /// color sent, and returns whether the fade ran to the end. A color flow runs on the light once started,
+    /// so it can only be cancelled before then.
+    pub fn crossfade_rgb(&mut self, to: Rgb, duration: Duration, steps: u32, cancel: Option<&AtomicBool>)
+                         -> Result<bool, YeeError> {
+        if steps == 0 {
+            return Err(YeeError::InvalidValue { field_name: "steps", value: steps.to_string() });
+        }
+        let step = duration / steps;
+        if step < Duration::from_millis(50) {
+            return Err(YeeError::InvalidValue { field_name: "duration", value: format!("{:?}", duration) });
+        }
+        let from = self.rgb;
+        self.fade_through((1..=steps).map(|i| lerp_rgb(from, to, i, steps)).collect(), step, cancel)
+    }
+
+    /// Set the color with `transition`, going the way through color space given by `path`.
+    ///
+    /// [`ColorPath::ViaHsv`] sweeps the hue from the cached color like [`crossfade_rgb`](Self::crossfade_rgb),
+    /// in steps of at least 250ms, so shorter and sudden transitions are sent as they are with `set_rgb`.
+    pub fn set_rgb_path(&mut self, to: Rgb, transition: Transition, path: ColorPath) -> Result<(), YeeError> {
+        transition.validate()?;
+        let duration = match (path, transition) {
+            (ColorPath::ViaHsv, Transition::Smooth { duration }) if duration >= HSV_PATH_STEP * 2 => duration,
+            _ => return self.set_rgb(to, transition)
+        };
+        let steps = (duration.as_millis() / HSV_PATH_STEP.as_millis()).min(MAX_HSV_PATH_STEPS) as u32;
+        let from = self.rgb;
+        self.fade_through((1..=steps).map(|i| hsv_path(from, to, i, steps)).collect(), duration / steps, None)?;
+        Ok(())
+    }
+
+    /// Go through `colors`, one every `step` of at least 50ms, as a color flow if supported,
+    /// otherwise with `set_rgb`, blocking until done or `cancel` is set. Returns whether it ran to the end.
+    fn fade_through(&mut self, colors: Vec<Rgb>, step: Duration, cancel: Option<&AtomicBool>)
+                    -> Result<bool, YeeError> {
+        let to = match colors.last() {
+            Some(to) => *to,
+            None => return Ok(true)
+        };
+        if is_cancelled(cancel) {
+            return Ok(false);
+        }
+        if self.support.contains("start_cf") {
+            let tuples: Vec<FlowTuple> = colors.into_iter().map(|c| FlowTuple::rgb(step.as_millis() as u32, c, -1))
+                .collect();
+            self.start_cf(&ColorFlow::new(tuples.len() as u32, FlowEnd::Stay, tuples))?;
+            self.rgb = to;
+            self.color_mode = ColorMode::Color;
+        } else {
+            // callers check the step against at least MIN_SMOOTH_DURATION, so always a valid smooth transition
+            let transition = Transition::smooth(step).unwrap();
+            for (i, color) in colors.into_iter().enumerate() {
+                if i > 0 && is_cancelled(cancel) {
+                    return Ok(false);
+                }
+                self.set_rgb(color, transition)?;
+                sleep(step);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Ramp the brightness from `from` to `to` over `over`, one smooth step every `step_interval`,
+    /// e.g. for a wake-up light taking far longer than a native transition can.
+    ///
+    /// If `ct` is given, the color temperature is ramped along from the first to the second value,
+    /// which takes a second command per step. The light only accepts so many commands per minute on a connection
+    /// (see music mode in the spec), so a short `step_interval` over a long ramp may fail with
+    /// [`BulbErrorCode::QuotaExceeded`], more so with `ct`.
+    ///
+    /// The light should already be on. Blocks until done, checking `cancel` before each step,
+    /// and returns whether the ramp ran to the end. When cancelled, the light stays at the last brightness sent.
+    pub fn ramp_bright(&mut self, from: u8, to: u8, over: Duration, step_interval: Duration,
+                       ct: Option<(u16, u16)>, cancel: Option<&AtomicBool>) -> Result<bool, YeeError> {
+        for bright in [from, to] {
+            if !(1..=100).contains(&bright) {
+                return Err(YeeError::InvalidValue { field_name: "bright", value: bright.to_string() });
+            }
+        }
+        if let Some((warm, cool)) = ct {
+            for temperature in [warm, cool] {
+                if !CT_RANGE.contains(&temperature) {
+                    return Err(YeeError::InvalidValue { field_name: "ct", value: temperature.to_string() });
+                }
+            }
+        }
+        if step_interval < MIN_SMOOTH_DURATION || over < step_interval {
+            return Err(YeeError::InvalidValue { field_name: "step_interval", value: format!("{:?}", step_interval) });
+        }
+        let steps = over.as_millis() / step_interval.as_millis();
+        let lerp = |a: i64, b: i64, i: u128| a + (b - a) * i as i64 / steps as i64;
+        // checked against MIN_SMOOTH_DURATION above, so always a valid smooth transition
+        let transition = Transition::smooth(step_interval).unwrap();
+        for i in 0..=steps {
+            if is_cancelled(cancel) {
+                return Ok(false);
+            }
+            let step_transition = if i == 0 { Transition::sudden() } else { transition };
+            if let Some((warm, cool)) = ct {
+                self.set_ct_abx(lerp(warm as i64, cool as i64, i) as u16, step_transition)?;
+            }
+            self.set_bright(lerp(from as i64, to as i64, i) as u8, step_transition)?;
+            if i < steps {
+                sleep(step_interval);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Query the full [`LightState`] from the light in one `get_prop` call, see [`STATE_PROPS`].
+    pub fn get_all_props(&mut self) -> Result<LightState, YeeError> {
+        let props = self.get_prop(&STATE_PROPS)?;
+        let props: HashMap<&str, &String> = props.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        LightState::from_fields(&props)
+    }
+
+    /// Update the cached state with the light's current state, e.g. after it was changed by another client.
+    ///
+    /// Like [`get_prop`](Self::get_prop), this caches intermediate values if a smooth transition is running,
+    /// call [`wait_for_last_transition`](Self::wait_for_last_transition) first to read where it ends.
+    ///
+    /// Also updates the name, [`flowing`](Self::flowing) and [`active_mode`](Self::active_mode),
+    /// all in one `get_prop` call, see [`REFRESH_PROPS`].
+    pub fn refresh(&mut self) -> Result<(), YeeError> {
+        let props = self.get_prop(&REFRESH_PROPS)?;
+        let props: HashMap<&str, &String> = props.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        let state = LightState::from_fields(&props)?;
+        self.set_state(state);
+        // all three are empty if the light doesn't report them, keep the name from discovery then
+        if let Some(name) = props.get("name").filter(|name| !name.is_empty()) {
+            self.name = name.to_string();
+        }
+        self.flowing = props.get("flowing").and_then(|flowing| parse_flag(flowing));
+        self.active_mode = props.get("active_mode").and_then(|mode| mode.parse::<ActiveMode>().ok());
+        Ok(())
+    }
+
+    fn set_state(&mut self, state: LightState) {
+        let LightState { power, bright, color_mode, ct, rgb, hue, sat } = state;
+        self.power = power;
+        self.bright = bright;
+        self.color_mode = color_mode;
+        self.ct = ct;
+        self.rgb = rgb;
+        self.hue = hue;
+        self.sat = sat;
+    }
+
+    /// Start a timer of `cron_type` that fires after `minutes`.
+    pub fn cron_add(&mut self, cron_type: CronType, minutes: u32) -> Result<(), YeeError> {
+        check_support!(self, "cron_add")?;
+        let req = Req::new("cron_add".to_string(), vec![json!(cron_type.value()), json!(minutes)]);
+        self.send_req(&req)?;
+        Ok(())
+    }
+
+    /// Get the minutes left on the timer of `cron_type`, if one is running.
+    pub fn cron_get(&mut self, cron_type: CronType) -> Result<Option<u32>, YeeError> {
+        check_support!(self, "cron_get")?;
+        let req = Req::new("cron_get".to_string(), vec![json!(cron_type.value())]);
+        let result = self.send_req(&req)?;
+        let job = match result.as_array().and_then(|jobs| jobs.first()) {
+            Some(job) => job,
+            None => return Ok(None)
+        };
+        job.get("delay")
+            .and_then(Value::as_u64)
+            .map(|delay| Some(delay as u32))
+            .ok_or(YeeError::ParseFieldFailed { field_name: "delay", source: None })
+    }
+
+    /// Stop the timer of `cron_type`.
+    pub fn cron_del(&mut self, cron_type: CronType) -> Result<(), YeeError> {
+        check_support!(self, "cron_del")?;
+        let req = Req::new("cron_del".to_string(), vec![json!(cron_type.value())]);
+        self.send_req(&req)?;
+        Ok(())
+    }
+
+    /// Turn the light off after `after`, rounded up to whole minutes, or cancel the timer if it is zero.
+    ///
+    /// Delays longer than [`MAX_DELAY_OFF`] are rejected.
+    pub fn set_delay_off(&mut self, after: Duration) -> Result<(), YeeError> {
+        if after == Duration::from_secs(0) {
+            return self.cron_del(CronType::PowerOff);
+        }
+        if after > MAX_DELAY_OFF {
+            return Err(YeeError::InvalidValue { field_name: "delay", value: format!("{:?}", after) });
+        }
+        let whole_minutes = after.as_secs() / 60;
+        let minutes = if after > Duration::from_secs(whole_minutes * 60) { whole_minutes + 1 } else { whole_minutes };
+        self.cron_add(CronType::PowerOff, minutes as u32)
+    }
+
+    /// Get the time left until the light turns off, in whole minutes, if a timer is running.
+    pub fn get_delay_off(&mut self) -> Result<Option<Duration>, YeeError> {
+        let minutes = self.cron_get(CronType::PowerOff)?;
+        Ok(minutes.map(|m| Duration::from_secs(m as u64 * 60)))
+    }
+
+    /// Apply `target`, only sending commands for the fields that differ from the cached state.
+    ///
+    /// The comparison is made against the cached fields, so it is only as accurate as the cache: changes made
+    /// by other clients (e.g. the official app) are not seen unless [`refresh`](Self::refresh) is called first.
+    /// If `target` is off, the light is only turned off, since the other fields can't be changed while off.
+    pub fn apply_state_diff(&mut self, target: &LightState, transition: Transition) -> Result<(), YeeError> {
+        if target.power == PowerStatus::Off {
+            if self.power != PowerStatus::Off {
+                self.set_power(PowerStatus::Off, transition)?;
+            }
+            return Ok(());
+        }
+        if self.power != PowerStatus::On {
+            self.set_power(PowerStatus::On, transition)?;
+        }
+        if self.bright != target.bright {
+            self.set_bright(target.bright, transition)?;
+        }
+        let same_mode = self.color_mode == target.color_mode;
+        match target.color_mode {
+            ColorMode::Color if !same_mode || self.rgb != target.rgb => self.set_rgb(target.rgb, transition),
+            ColorMode::ColorTemperature if !same_mode || self.ct != target.ct => self.set_ct_abx(target.ct, transition),
+            ColorMode::Hsv if !same_mode || self.hue != target.hue || self.sat != target.sat =>
+                self.set_hsv(target.hue, target.sat, transition),
+            _ => Ok(())
+        }
+    }
+
+    /// Give this light the state of `other` and `other` the state of this light, e.g. to rebalance two lamps.
+    ///
+    /// Both states are read from the lights before either is changed. A light that can't show the other's color
+    /// mode, e.g. a white-only bulb given an rgb color, only takes the power and brightness.
+    ///
+    /// If `other` can't be changed, this light is set back to its own state before the error is returned.
+    pub fn swap_state_with(&mut self, other: &mut Light, transition: Transition) -> Result<(), YeeError> {
+        self.refresh()?;
+        other.refresh()?;
+        let original = self.state();
+        let for_self = self.supported_state(other.state());
+        let for_other = other.supported_state(original.clone());
+        self.apply_state_diff(&for_self, transition)?;
+        let result = other.apply_state_diff(&for_other, transition);
+        if result.is_err() {
+            if let Err(e) = self.apply_state_diff(&original, transition) {
+                log::warn!("failed to restore the state of light {}: {}", self.id(), e);
+            }
+        }
+        result
+    }
+
+    /// `target`, keeping the cached values for the fields this light can't set.
+    fn supported_state(&self, target: LightState) -> LightState {
+        let current = self.state();
+        let color_method = match target.color_mode {
+            ColorMode::Color => "set_rgb",
+            ColorMode::ColorTemperature => "set_ct_abx",
+            ColorMode::Hsv => "set_hsv"
+        };
+        let target = if self.support.contains(color_method) {
+            target
+        } else {
+            LightState { power: target.power, bright: target.bright, ..current.clone() }
+        };
+        if self.support.contains("set_bright") {
+            target
+        } else {
+            LightState { bright: current.bright, ..target }
+        }
+    }
+
+    /// Caveats about how the light will treat `method` with `params`, given the cached state, e.g. for tooltips.
+    ///
+    /// Only looks at what this library knows about the light's quirks, nothing is sent to the light.
+    pub fn explain_command(&self, method: &str, params: &[Value]) -> Option<String> {
+        let number = |i: usize| params.get(i).and_then(Value::as_i64);
+        let mut caveats = Vec::new();
+        if self.power == PowerStatus::Off && !["set_power", "toggle", "get_prop", "cron_add", "cron_get", "cron_del"]
+            .contains(&method) {
+            caveats.push(format!("{} is rejected while the light is off", method));
+        }
+        match (method, number(0)) {
+            ("set_bright", Some(bright)) if bright < 1 => caveats.push("brightness below 1 will turn the bulb off".to_string()),
+            ("set_bright", Some(bright)) if bright > 100 => caveats.push("brightness above 100 is rejected".to_string()),
+            ("set_rgb", Some(0)) => caveats.push("rgb 0 is rejected, use set_power to turn the light off".to_string()),
+            ("set_ct_abx", Some(ct)) => {
+                let range = match self.active_mode {
+                    Some(ActiveMode::Moonlight) => MOONLIGHT_CT_RANGE,
+                    _ => CT_RANGE
+                };
+                if !(*range.start() as i64..=*range.end() as i64).contains(&ct) {
+                    caveats.push(format!("color temperature outside {}-{}K is rejected", range.start(), range.end()));
+                }
+            }
+            _ => {}
+        }
+        match (method, &self.color_mode) {
+            ("set_rgb", ColorMode::ColorTemperature) | ("set_hsv", ColorMode::ColorTemperature) =>
+                caveats.push(format!("{} switches the light out of color temperature mode", method)),
+            ("set_ct_abx", ColorMode::Color) | ("set_ct_abx", ColorMode::Hsv) =>
+                caveats.push("set_ct_abx switches the light out of color mode".to_string()),
+            _ => {}
+        }
+        // the transition is always the last two params of the setters
+        if let [.., Value::String(effect), Value::Number(duration)] = params {
+            let too_short = matches!(duration.as_u64(), Some(d) if d < MIN_SMOOTH_DURATION.as_millis() as u64);
+            if effect == "smooth" && too_short {
+                caveats.push("smooth transitions shorter than 30ms are rejected".to_string());
+            }
+        }
+        if caveats.is_empty() {
+            None
+        } else {
+            Some(caveats.join("; "))
+        }
+    }
+
+    /// Send a request written as JSON, e.g. replayed from a capture, and return its result.
+    ///
+    /// `json` must be a single request object with `id`, `method` and `params`, or [`YeeError::JsonError`]
+    /// is returned without sending anything. The support list isn't checked and the cached state isn't updated.
+    pub fn send_raw_json(&mut self, json: &str) -> Result<Value, YeeError> {
+        let req: Req = serde_json::from_str(json).map_err(|e| YeeError::JsonError { source: e })?;
+        self.send_req(&req)
+    }
+
+    /// Block until the most recent smooth transition sent to this light is done, e.g. before chaining commands.
+    ///
+    /// Returns right away if there is none, or it is already done.
+    pub fn wait_for_last_transition(&self) {
+        if let Some(end) = self.last_transition_end {
+            let now = Instant::now();
+            if end > now {
+                sleep(end - now);
+            }
+        }
+    }
+
+    /// Remember when `transition` is done, after a command with it was successfully sent.
+    pub(crate) fn track_transition(&mut self, transition: Transition) {
+        if let Transition::Smooth { duration } = transition {
+            self.last_transition_end = Some(Instant::now() + duration);
+        }
+    }
+
+    /// Turn on music mode, where the light connects back to this machine and takes commands over that
+    /// connection without its rate limit, e.g. to sync to music. Does nothing if already on.
+    ///
+    /// Listens on the local address of the connection to the light, which is connected first if needed.
+    /// Commands only go over the music connection with [`prefer_music_channel`](Self::prefer_music_channel).
+    pub fn start_music(&mut self) -> Result<(), YeeError> {
+        check_support!(self, "set_music")?;
+        if self.music.is_some() {
+            return Ok(());
+        }
+        self.init()?;
+        let local_ip = match self.read.as_ref().unwrap().get_ref().local_addr()? {
+            SocketAddr::V4(local) => *local.ip(),
+            SocketAddr::V6(_) => panic!("Light should not have an IPv6 address")
+        };
+        let listener = TcpListener::bind(SocketAddrV4::new(local_ip, 0))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        self.send_req(&Req::new("set_music".to_string(), vec![json!(1), json!(local_ip.to_string()), json!(port)]))?;
+        let deadline = Instant::now() + MUSIC_CONNECT_TIMEOUT;
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock && Instant::now() < deadline =>
+                    sleep(Duration::from_millis(50)),
+                Err(e) if e.kind() == ErrorKind::WouldBlock =>
+                    return Err(io::Error::new(ErrorKind::TimedOut, "light didn't connect for music mode").into()),
+                Err(e) => return Err(e.into())
+            }
+        };
+        stream.set_nonblocking(false)?;
+        self.music = Some(BufWriter::new(stream));
+        Ok(())
+    }
+
+    /// Turn off music mode, closing the music connection.
+    pub fn stop_music(&mut self) -> Result<(), YeeError> {
+        check_support!(self, "set_music")?;
+        self.music = None;
+        self.send_req(&Req::new("set_music".to_string(), vec![json!(0)]))?;
+        Ok(())
+    }
+
+    /// Whether music mode is on, see [`start_music`](Self::start_music).
+    pub fn is_music_on(&self) -> bool {
+        self.music.is_some()
+    }
+
+    /// Whether later commands go over the music connection while music mode is on, `false` by default.
+    ///
+    /// The light doesn't answer commands sent over the music connection, so they always succeed once sent,
+    /// even if the light rejects them, and return nothing. Queries like `get_prop` always go over the control
+    /// connection, since they need the answer.
+    pub fn prefer_music_channel(&mut self, prefer: bool) {
+        self.prefer_music = prefer;
+    }
+
+    /// Retry commands that fail with a transient error (see [`YeeError::is_transient`]) up to `retries` times,
+    /// waiting `backoff` before the first retry and twice as long before each one after. No retries by default.
+    ///
+    /// A command that was sent but got no response may already have been applied, so it's only resent if running
+    /// it twice is harmless, like `set_*` and `get_prop`, and not e.g. `toggle` or `adjust_bright`. Commands the light
+    /// rejected, e.g. for exceeding the quota, are always retried.
+    ///
+    /// Commands sent to a [`LightGroup`](crate::LightGroup) at once aren't retried.
+    pub fn set_retry_policy(&mut self, retries: u32, backoff: Duration) {
+        self.retries = retries;
+        self.retry_backoff = backoff;
+    }
+
+    /// Keep the last `len` commands sent to the light, to see what an automation actually did.
+    /// Off (zero) by default, see [`YeeClientBuilder::history_len`](crate::YeeClientBuilder::history_len).
+    ///
+    /// Commands sent to a [`LightGroup`](crate::LightGroup) at once, or without waiting for the response,
+    /// aren't kept.
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history_len = len;
+        let excess = self.history.len().saturating_sub(len);
+        self.history.drain(..excess);
+    }
+
+    /// The commands kept by [`set_history_len`](Self::set_history_len), oldest first.
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history
+    }
+
+    pub(crate) fn send_req(&mut self, req: &Req) -> Result<Value, YeeError> {
+        let result = self.send_req_with_retries(req);
+        if self.history_len > 0 {
+            if self.history.len() == self.history_len {
+                self.history.remove(0);
+            }
+            let params = serde_json::to_string(&req.params).unwrap();
+            self.history.push(HistoryEntry {
+                method: req.method.clone(),
+                params: params.chars().take(HISTORY_PARAMS_LEN).collect(),
+                error: result.as_ref().err().map(YeeError::to_string),
+                sent_at: SystemTime::now(),
+            });
+        }
+        result
+    }
+
+    fn send_req_with_retries(&mut self, req: &Req) -> Result<Value, YeeError> {
+        if self.prefer_music && self.music.is_some() && !CONTROL_ONLY_METHODS.contains(&req.method.as_str()) {
+            self.write_music_req(req)?;
+            return Ok(Value::Null);
+        }
+        let mut backoff = self.retry_backoff;
+        for _ in 0..self.retries {
+            let (result, sent) = match self.write_req(req) {
+                Ok(()) => (self.read_result(req.id), true),
+                Err(e) => (Err(e), false)
+            };
+            match result {
+                Err(e) if e.is_transient() && (!sent || can_resend(&req.method, &e)) => {
+                    sleep(backoff);
+                    backoff = backoff.checked_mul(2).unwrap_or(backoff);
+                }
+                result => return result
+            }
+        }
+        self.write_req(req)?;
+        self.read_result(req.id)
+    }
+
+    /// Send `req` without waiting for the response, e.g. for effects in a tight loop where a round trip
+    /// per command is too slow.
+    ///
+    /// Only errors sending the request are returned: the light's response, including any error it reports,
+    /// is put aside by the next command or [`drain_notifications`](Self::drain_notifications) with the last
+    /// few others that weren't waited for. The cached state isn't updated either.
+    pub fn send_req_no_wait(&mut self, req: &Req) -> Result<(), YeeError> {
+        self.write_req(req)
+    }
+
+    /// Read the messages the light already sent without waiting for more, returning the params of the
+    /// `props` notifications it sends when its state changes. Responses, e.g. to
+    /// [`send_req_no_wait`](Self::send_req_no_wait), are kept for when they're waited for, like the out of order
+    /// responses seen while waiting for another one.
+    ///
+    /// The cached state is updated from the notifications, see [`apply_notification`](Self::apply_notification).
+    pub fn drain_notifications(&mut self) -> Result<Vec<Value>, YeeError> {
+        #[cfg(any(test, feature = "test-utils"))]
+        if let Some(mock) = self.mock.as_mut() {
+            mock.pending.clear();
+            return Ok(Vec::new());
+        }
+        let mut notifications = Vec::new();
+        let mut responses = Vec::new();
+        let reader = match self.read.as_mut() {
+            Some(reader) => reader,
+            None => return Ok(notifications)
+        };
+        loop {
+            if reader.buffer().is_empty() {
+                reader.get_ref().set_nonblocking(true)?;
+                let filled = reader.fill_buf().map(|b| b.len());
+                reader.get_ref().set_nonblocking(false)?;
+                match filled {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => return self.track_error(Err(e.into())),
+                    Ok(0) => {
+                        let closed = io::Error::new(ErrorKind::UnexpectedEof, "connection closed by light");
+                        return self.track_error(Err(closed.into()));
+                    }
+                    Ok(_) => {}
+                }
+            }
+            let message = match read_response(reader) {
+                Ok(message) => message,
+                Err(e) => return self.track_error(Err(e.into()))
+            };
+            if let Ok(mut message) = serde_json::from_str::<Value>(&message) {
+                match message.get("id").and_then(Value::as_u64).and_then(|id| u16::try_from(id).ok()) {
+                    Some(id) => responses.push((id, message)),
+                    None if message.get("method").and_then(Value::as_str) == Some("props") =>
+                        notifications.push(message["params"].take()),
+                    None => {}
+                }
+            }
+        }
+        for (id, response) in responses {
+            self.keep_pending_response(id, response);
+        }
+        for props in notifications.iter() {
+            self.apply_notification(props);
+        }
+        Ok(notifications)
+    }
+
+    /// Update the cached state from the params of a `props` notification, e.g. `{"color_mode": 2, "flowing": 1}`,
+    /// ignoring properties that aren't cached or can't be parsed.
+    pub fn apply_notification(&mut self, props: &Value) -> StateChange {
+        let before = self.state();
+        let flowing_before = self.flowing;
+        for (prop, value) in props.as_object().into_iter().flatten() {
+            // firmwares send values as strings or numbers
+            let value = value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+            match prop.as_str() {
+                "power" => if let Ok(power) = value.parse() { self.power = power },
+                "bright" => if let Ok(bright) = value.parse() { self.bright = bright },
+                "color_mode" => if let Ok(color_mode) = value.parse() { self.color_mode = color_mode },
+                "ct" => if let Ok(ct) = value.parse() { self.ct = ct },
+                "rgb" => if let Ok(rgb) = value.parse() { self.rgb = rgb },
+                "hue" => if let Ok(hue) = value.parse() { self.hue = hue },
+                "sat" => if let Ok(sat) = value.parse() { self.sat = sat },
+                "flowing" => if let Some(flowing) = parse_flag(&value) { self.flowing = Some(flowing) },
+                _ => {}
+            }
+        }
+        StateChange {
+            before,
+            after: self.state(),
+            flowing: self.flowing.filter(|_| self.flowing != flowing_before),
+        }
+    }
+
+    /// Send `req` without waiting for the response, see `read_result`.
+    pub(crate) fn write_req(&mut self, req: &Req) -> Result<(), YeeError> {
+        let result = self.try_write_req(req);
+        self.track_error(result)
+    }
+
+    /// Wait for the response to the request with `id` and return its result.
+    pub(crate) fn read_result(&mut self, id: u16) -> Result<Value, YeeError> {
+        let result = self.try_read_result(id);
+        self.track_error(result)
+    }
+
+    /// Count errors, and drop broken connections so the next command reconnects.
+    fn track_error<T>(&mut self, result: Result<T, YeeError>) -> Result<T, YeeError> {
+        if let Err(e) = &result {
+            self.metrics.errors += 1;
+            if let YeeError::IoError { .. } = e {
+                self.read = None;
+                self.write = None;
+            }
+        }
+        result
+    }
+
+    /// Send `req` over the music connection, which is closed if that fails.
+    fn write_music_req(&mut self, req: &Req) -> Result<(), YeeError> {
+        let mut json = serde_json::to_string(req).unwrap();
+        json.push_str("\r\n");
+        let writer = self.music.as_mut().unwrap();
+        if let Err(e) = writer.write_all(json.as_bytes()).and_then(|_| writer.flush()) {
+            self.music = None;
+            self.metrics.errors += 1;
+            return Err(e.into());
+        }
+        self.metrics.commands_sent += 1;
+        self.metrics.bytes_written += json.len() as u64;
+        Ok(())
+    }
+
+    fn try_write_req(&mut self, req: &Req) -> Result<(), YeeError> {
+        let mut json = serde_json::to_string(req).unwrap();
+        #[cfg(any(test, feature = "test-utils"))]
+        if let Some(mock) = self.mock.as_mut() {
+            json.push_str("\r\n");
+            mock.sent.extend_from_slice(json.as_bytes());
+            mock.pending.insert(req.id, Req::with_id(req.id, req.method.clone(), req.params.clone()));
+            self.metrics.commands_sent += 1;
+            self.metrics.bytes_written += json.len() as u64;
+            return Ok(());
+        }
+        // lights found without eager_connect connect on their first command
+        self.init()?;
+        let writer = self.write.as_mut().unwrap();
+        json.push_str("\r\n");
+        writer.write_all(json.as_bytes())?;
+        writer.flush()?;
+        self.metrics.commands_sent += 1;
+        self.metrics.bytes_written += json.len() as u64;
+        Ok(())
+    }
+
+    fn try_read_result(&mut self, id: u16) -> Result<Value, YeeError> {
+        #[cfg(any(test, feature = "test-utils"))]
+        if let Some(mock) = self.mock.as_mut() {
+            let req = mock.pending.remove(&id)
+                .ok_or_else(|| io::Error::new(ErrorKind::UnexpectedEof, "no request with this id was sent"))?;
+            return Ok(self.mock_result(&req));
+        }
+        if let Some(i) = self.pending_responses.iter().position(|(pending, _)| *pending == id) {
+            let (_, response) = self.pending_responses.remove(i).unwrap();
+            return result_of(response);
+        }
+        // apply notifications, and keep responses to other requests for when they're waited for,
+        // since they may arrive out of order
+        loop {
+            let buf = read_response(self.read.as_mut().unwrap())?;
+            if buf.is_empty() {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed by light").into());
+            }
+            let response = serde_json::from_str::<Value>(&buf)
+                .map_err(|_| YeeError::ParseFieldFailed { field_name: "result", source: None })?;
+            match response.get("id").and_then(Value::as_u64).and_then(|id| u16::try_from(id).ok()) {
+                Some(response_id) if response_id == id => return result_of(response),
+                Some(response_id) => self.keep_pending_response(response_id, response),
+                None if response.get("method").and_then(Value::as_str) == Some("props") => {
+                    self.apply_notification(&response["params"]);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Keep the response to request `id` until it is waited for, dropping the oldest one once full.
+    fn keep_pending_response(&mut self, id: u16, response: Value) {
+        if self.pending_responses.len() == MAX_PENDING_RESPONSES {
+            self.pending_responses.pop_front();
+        }
+        self.pending_responses.push_back((id, response));
+    }
+
+    pub fn location(&self) -> &SocketAddrV4 {
+        &self.location
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Number of distinct brightness levels the light can show, e.g. to quantize a slider.
+    ///
+    /// Always 100, the levels `set_bright` accepts, as no model is documented to show fewer.
+    pub fn brightness_resolution(&self) -> u8 {
+        100
+    }
+
+    /// A rough estimate of the power the light draws, in watts, from its rated power and the cached brightness,
+    /// e.g. to add up on a dashboard.
+    ///
+    /// Only an approximation: lights don't report their draw, the full-brightness power of each model is a rough
+    /// guess rather than a datasheet figure, real draw isn't linear in brightness, and unknown models are assumed
+    /// to draw as much as a typical bulb. Standby draw is ignored, so lights
+    /// that are off draw nothing.
+    pub fn estimated_power_watts(&self) -> f32 {
+        if self.power == PowerStatus::Off {
+            return 0.0;
+        }
+        let max_watts = MAX_WATTS.iter()
+            .find(|(model, _)| *model == self.model)
+            .map_or(DEFAULT_MAX_WATTS, |(_, watts)| *watts);
+        max_watts * self.bright as f32 / 100.0
+    }
+
+    pub fn fw_ver(&self) -> u8 {
+        self.fw_ver
+    }
+
+    pub fn support(&self) -> &HashSet<String> {
+        &self.support
+    }
+
+    /// The [`Capabilities`] covered by [`support`](Self::support), which keeps every method,
+    /// including ones without a flag.
+    pub fn capability_flags(&self) -> Capabilities {
+        Capabilities::from_methods(self.support.iter().map(String::as_str))
+    }
+
+    pub fn power(&self) -> &PowerStatus {
+        &self.power
+    }
+
+    pub fn bright(&self) -> u8 {
+        self.bright
+    }
+
+    pub fn color_mode(&self) -> &ColorMode {
+        &self.color_mode
+    }
+
+    pub fn ct(&self) -> u16 {
+        self.ct
+    }
+
+    pub fn rgb(&self) -> &Rgb {
+        &self.rgb
+    }
+
+    pub fn hue(&self) -> u16 {
+        self.hue
+    }
+
+    pub fn sat(&self) -> u8 {
+        self.sat
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The active light source, only reported by moonlight-capable lamps.
+    pub fn active_mode(&self) -> Option<&ActiveMode> {
+        self.active_mode.as_ref()
+    }
+
+    /// The firmware platform and protocol revisions the light advertised during discovery,
+    /// if it sent a well-formed `Server` header.
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.server_info.as_ref()
+    }
+
+    /// Whether a color flow is running, as of the last [`refresh`](Self::refresh).
+    pub fn flowing(&self) -> Option<bool> {
+        self.flowing
+    }
+
+    /// Whether the light restores its state after losing power, if reported by discovery or read with
+    /// [`get_prop`](Self::get_prop) as `save_state`. Only some firmwares report it.
+    pub fn saves_state(&self) -> Option<bool> {
+        self.save_state
+    }
+
+    /// Make a light that isn't backed by a real light, with the given state and every method supported.
+    ///
+    /// Commands succeed without touching the network and are captured in [`sent_bytes`](Self::sent_bytes),
+    /// while `get_prop` answers from the cached state.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn mock(state: LightState) -> Light {
+        let mut light = Light::unconnected(SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 55443), state);
+        light.id = "0x0000000000000000".to_string();
+        light.model = "mock".to_string();
+        light.support = KNOWN_METHODS.iter().map(|s| s.to_string()).collect();
+        light.mock = Some(MockConnection::default());
+        light
+    }
+
+    /// Everything sent to a light made by [`mock`](Self::mock), or an empty slice for real lights.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn sent_bytes(&self) -> &[u8] {
+        self.mock.as_ref().map_or(&[], |mock| &mock.sent)
+    }
+
+    /// The result a real light would answer `req` with, given the cached state.
+    #[cfg(any(test, feature = "test-utils"))]
+    fn mock_result(&self, req: &Req) -> Value {
+        match req.method.as_str() {
+            "get_prop" => req.params.iter()
+                .map(|prop| match prop.as_str().unwrap_or_default() {
+                    "power" => self.power.to_string(),
+                    "bright" => self.bright.to_string(),
+                    "color_mode" => match self.color_mode {
+                        ColorMode::Color => "1",
+                        ColorMode::ColorTemperature => "2",
+                        ColorMode::Hsv => "3"
+                    }.to_string(),
+                    "ct" => self.ct.to_string(),
+                    "rgb" => self.rgb.get_num().to_string(),
+                    "hue" => self.hue.to_string(),
+                    "sat" => self.sat.to_string(),
+                    "name" => self.name.clone(),
+                    "save_state" => match self.save_state {
+                        Some(true) => "1",
+                        Some(false) => "0",
+                        None => ""
+                    }.to_string(),
+                    _ => String::new()
+                })
+                .map(Value::String)
+                .collect(),
+            "cron_get" => json!([]),
+            _ => json!(["ok"])
+        }
+    }
+
+    /// Counters of the commands sent to this light and how they went.
+    pub fn metrics(&self) -> LightMetrics {
+        self.metrics
+    }
+
+    /// How long this light took to respond to discovery, if it was found by discovery.
+    pub fn discovery_latency(&self) -> Option<Duration> {
+        self.discovery_latency
+    }
+
+    /// Whether the light accepted a connection when discovered by
+    /// [`YeeClient::find_lights_probed`](crate::YeeClient::find_lights_probed), or `None` if it wasn't probed.
+    pub fn reachable(&self) -> Option<bool> {
+        self.reachable
+    }
+
+    /// How long the light's advertisement is valid, from its `Cache-Control` header, if it sent one.
+    ///
+    /// A light that doesn't advertise again within this time can be taken as gone.
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age
+    }
+
+    /// The light's clock when it advertised, as sent in its `Date` header, or `None` if the header was empty.
+    ///
+    /// Most firmwares leave it empty, but where it's sent, comparing it to the local time shows clock skew that
+    /// makes cron timers fire at the wrong time.
+    pub fn device_time(&self) -> Option<&str> {
+        self.device_time.as_deref()
+    }
+
+    /// The cached state of the background light, for dual-light lamps that advertise it.
+    pub fn background(&self) -> Option<&BackgroundLight> {
+        self.background.as_ref()
+    }
+
+    /// Snapshot the cached state of this light.
+    pub fn state(&self) -> LightState {
+        LightState {
+            power: self.power,
+            bright: self.bright,
+            color_mode: self.color_mode,
+            ct: self.ct,
+            rgb: self.rgb,
+            hue: self.hue,
+            sat: self.sat,
+        }
+    }
+}
+
+/// The linear brightness that looks as bright as the CIE lightness `perceived`, both from 1 to 100.
+fn perceived_to_linear(perceived: u8) -> u8 {
+    let lightness = f64::from(perceived);
+    let luminance = if lightness > 8.0 {
+        ((lightness + 16.0) / 116.0).powi(3)
+    } else {
+        lightness / 903.3
+    };
+    ((luminance * 100.0).round() as u8).clamp(1, 100)
+}
+
+/// Whether `method` can be sent again after it was sent and failed with `error`, i.e. if the light rejected it,
+/// or it may have been applied but running it twice is harmless.
+fn can_resend(method: &str, error: &YeeError) -> bool {
+    let idempotent = method.starts_with("set_") || method.starts_with("bg_set_")
+        || ["get_prop", "cron_get", "cron_del"].contains(&method);
+    idempotent || matches!(error, YeeError::ChangeFailed { .. })
+}
+
+/// Whether the cancel token of a stepped effect is set.
+fn is_cancelled(cancel: Option<&AtomicBool>) -> bool {
+    matches!(cancel, Some(c) if c.load(Ordering::Relaxed))
+}
+
+/// Parse a `0` or `1` property, or `None` for anything else, e.g. an empty value for an unsupported property.
+fn parse_flag(value: &str) -> Option<bool> {
+    match value {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None
+    }
+}
+
+/// The `max-age` directive of a `Cache-Control` header, e.g. `max-age=3600`.
+fn parse_max_age(value: &str) -> Option<Duration> {
+    value.split(',')
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// The result of a response, or the error the light responded with.
+fn result_of(mut response: Value) -> Result<Value, YeeError> {
+    if let Some(error) = response.get("error") {
+        let code = error.get("code").and_then(Value::as_i64).unwrap_or_default() as i32;
+        let message = error.get("message").and_then(Value::as_str).unwrap_or_default().to_string();
+        return Err(YeeError::ChangeFailed { code: BulbErrorCode::from_response(code, &message), message });
+    }
+    response.get_mut("result")
+        .map(Value::take)
+        .ok_or(YeeError::ParseFieldFailed { field_name: "result", source: None })
+}
+
+/// Read one response from the light, or an empty string if the connection was closed.
+///
+/// Responses are normally terminated by `\r\n`, but some firmwares leave it out, so a complete JSON object
+/// that isn't followed by anything within `NEWLINE_WINDOW` is also accepted as a response.
+fn read_response(reader: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut scanner = JsonScanner::default();
+    loop {
+        if scanner.is_complete() && reader.buffer().is_empty() {
+            let timeout = reader.get_ref().read_timeout()?;
+            reader.get_ref().set_read_timeout(Some(NEWLINE_WINDOW))?;
+            let filled = reader.fill_buf().map(|b| b.len());
+            reader.get_ref().set_read_timeout(timeout)?;
+            match filled {
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+        let byte = match reader.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None => break
+        };
+        // another response right after a complete one without a newline
+        if scanner.is_complete() && !byte.is_ascii_whitespace() {
+            break;
+        }
+        reader.consume(1);
+        line.push(byte);
+        if byte == b'\n' {
+            break;
+        }
+        scanner.feed(byte);
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Tracks whether the bytes fed so far form a complete JSON object.
+#[derive(Default)]
+struct JsonScanner {
+    started: bool,
+    depth: u32,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonScanner {
+    fn feed(&mut self, byte: u8) {
+        if self.in_string {
+            match byte {
+                _ if self.escaped => self.escaped = false,
+                b'\\' => self.escaped = true,
+                b'"' => self.in_string = false,
+                _ => {}
+            }
+            return;
+        }
+        match byte {
+            b'"' => self.in_string = true,
+            b'{' => {
+                self.started = true;
+                self.depth += 1;
+            }
+            b'}' => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.started && self.depth == 0
+    }
+}
+
+/// The color `numerator / denominator` of the way from `from` to `to`.
+fn lerp_rgb(from: Rgb, to: Rgb, numerator: u32, denominator: u32) -> Rgb {
+    let lerp = |a: u8, b: u8| {
+        (a as i64 + (b as i64 - a as i64) * numerator as i64 / denominator as i64) as u8
+    };
+    Rgb::new(lerp(from.red, to.red), lerp(from.green, to.green), lerp(from.blue, to.blue))
+}
+
+/// The color `numerator / denominator` of the way from `from` to `to`, going the short way around
+/// the color wheel and scaling the brightness linearly.
+fn hsv_path(from: Rgb, to: Rgb, numerator: u32, denominator: u32) -> Rgb {
+    if numerator == denominator {
+        return to;
+    }
+    let lerp = |a: i64, b: i64| a + (b - a) * numerator as i64 / denominator as i64;
+    let ((from_hue, from_sat), (to_hue, to_sat)) = (from.to_hsv(), to.to_hsv());
+    let turn = (to_hue as i64 - from_hue as i64 + 540) % 360 - 180;
+    let hue = (from_hue as i64 + turn * numerator as i64 / denominator as i64).rem_euclid(360) as u16;
+    let sat = lerp(from_sat as i64, to_sat as i64) as u8;
+    let value = |c: Rgb| c.red.max(c.green).max(c.blue) as i64;
+    let scale = lerp(value(from), value(to));
+    let full = Rgb::from_hsv(hue, sat);
+    let dim = |c: u8| (c as i64 * scale / 255) as u8;
+    Rgb::new(dim(full.red), dim(full.green), dim(full.blue))
+}
+
+impl Hash for Light {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write(self.id.as_bytes());
+    }
+}
+
+impl PartialEq for Light {
+    fn eq(&self, other: &Self) -> bool {
+        self.id.eq(&other.id)
+    }
+}
+
+impl Eq for Light {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use crate::tests::fake_advertisement;
+
+    macro_rules! map {
+        ($($key:expr => $value: expr), *) => {{
+            let mut map = HashMap::new();
+            $(map.insert($key,$value);)*
+            map
+        }};
+    }
+
+    /// The state of a light made by [`Light::mock`] in most tests: on, at 3000K and 20% brightness.
+    pub(crate) fn mock_state() -> LightState {
+        LightState {
+            power: PowerStatus::On,
+            bright: 20,
+            color_mode: ColorMode::ColorTemperature,
+            ct: 3000,
+            rgb: Rgb::new(0, 0, 0),
+            hue: 0,
+            sat: 0,
+        }
+    }
+
+    /// Every request sent to a light made by [`Light::mock`].
+    pub(crate) fn sent_reqs(light: &Light) -> Vec<Req> {
+        let sent = String::from_utf8(light.sent_bytes().to_vec()).expect("requests are valid utf-8");
+        sent.split_terminator("\r\n")
+            .map(|line| serde_json::from_str(line).expect("requests are valid json"))
+            .collect()
+    }
+
+    pub(crate) fn get_map() -> HashMap<&'static str, &'static str> {
+        let mut m: HashMap<&str, &str> =
+            map!(
+            "id" => "0x1234",
+            "model" => "floor",
+            "fw_ver" => "40", // can fail
+            "power" => "on", // can fail
+            "bright" => "34", // can fail
+            "color_mode" => "2", // can fail
+            "ct" => "0", // can fail
+            "rgb" => "657930", // 0A0A0A, can fail
+            "hue" => "314", // can fail
+            "sat" => "12", // can fail
+            "name" => "room_light",
+            "Location" => "yeelight://127.0.0.1:13454"
+            );
+        let support = "get_power set_power get_rgb set_rgb";
+        m.insert("support", support);
+        m
+    }
+
+    /// Accept a single connection on `addr`, answer every request with the result of `respond`,
+    /// and return every request received once the connection is closed.
+    /// If `respond` returns an object with an `error`, that error is sent instead of a result.
+    pub(crate) fn fake_bulb<F>(addr: SocketAddrV4, mut respond: F) -> anyhow::Result<JoinHandle<Vec<Req>>>
+        where F: FnMut(&Req) -> Value + Send + 'static {
+        let listener = TcpListener::bind(addr)?;
+        Ok(thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reqs = vec![];
+            for line in BufReader::new(stream).lines() {
+                let req: Req = serde_json::from_str(&line.unwrap()).unwrap();
+                let res = match respond(&req) {
+                    Value::Object(error) if error.contains_key("error") => json!({ "id": req.id, "error": error["error"] }),
+                    result => json!({ "id": req.id, "result": result })
+                };
+                write!(writer, "{}\r\n", res).unwrap();
+                reqs.push(req);
+            }
+            reqs
+        }))
+    }
+
+    #[test]
+    fn send_correct_req() -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    #[test]
+    fn get_correct_location() -> anyhow::Result<()> {
+        // given
+        let map = get_map();
+        let expected_addr = match SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), 13454) {
+            SocketAddr::V4(v4) => v4,
+            _ => unreachable!()
+        };
+
+        // when
+        let light = Light::from_fields(&map)?;
+
+        // then
+        assert_eq!(*light.location(), expected_addr);
+        Ok(())
+    }
+
+    macro_rules! generate_getter_tests {
+        () => {};
+        ($field:ident, $($tail: tt)*) => {
+            #[test]
+            fn $field() -> anyhow::Result<()> {
+
+                // given
+                let map = get_map();
+
+                // when
+                let light = Light::from_fields(&map)?;
+
+                // then
+                assert_eq!(map.get(stringify!($field)).unwrap(), &light.$field().to_string());
+                Ok(())
+            }
+            generate_getter_tests!($($tail)*);
+        };
+        ($field:ident => $expected: expr, $($tail: tt)*) => {
+            #[test]
+            fn $field() -> anyhow::Result<()> {
+
+                // given
+                let map = get_map();
+
+                // when
+                let light = Light::from_fields(&map)?;
+
+                // then
+                assert_eq!(&$expected, light.$field());
+                Ok(())
+            }
+            generate_getter_tests!($($tail)*);
+        };
+
+    }
+
+    mod test_get_parse {
+        use super::*;
+
+        generate_getter_tests!(
+            id,
+            model,
+            fw_ver,
+            power,
+            bright,
+            color_mode => ColorMode::ColorTemperature,
+            ct,
+            rgb => Rgb { red: 10, green: 10, blue: 10 },
+            hue,
+            sat,
+            name, );
+    }
+
+    macro_rules! generate_parse_fail_tests {
+        ($($field:ident), *) => {
+            $(
+                #[test]
+                fn $field() {
+
+                    // given
+                    let mut map = get_map();
+                    map.remove(stringify!($field)).unwrap();
+
+                    // when
+                    let fail = Light::from_fields(&map);
+
+                    // then
+                    assert!(fail.is_err());
+                }
+            )*
+        };
+    }
+
+    mod test_parse_fail {
+        use super::*;
+
+        generate_parse_fail_tests!(
+            id,
+            model,
+            fw_ver,
+            support,
+            power,
+            bright,
+            color_mode,
+            ct,
+            rgb,
+            hue,
+            sat,
+            name);
+    }
+
+    #[test]
+    fn get_correct_support() -> anyhow::Result<()> {
+        // given
+        let map = get_map();
+        let expected_fields: HashSet<String> = map.get("support").unwrap().split_whitespace().map(|s| s.to_string()).collect();
+
+        // when
+        let light = Light::from_fields(&map)?;
+
+        // then
+        let support = light.support();
+        assert_eq!(&expected_fields, support);
+        Ok(())
+    }
+
+    #[test]
+    fn brightness_resolution_of_any_model() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        let floor = Light::from_fields(&map)?;
+        map.insert("model", "stripe");
+
+        // when
+        let stripe = Light::from_fields(&map)?;
+
+        // then
+        assert_eq!(floor.brightness_resolution(), 100);
+        assert_eq!(stripe.brightness_resolution(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn capture_server_info() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        let without = Light::from_fields(&map)?;
+        map.insert("Server", "POSIX UPnP/1.0 YGLC/1");
+        let with = Light::from_fields(&map)?;
+        map.insert("Server", "something else");
+
+        // when
+        let odd = Light::from_fields(&map)?;
+
+        // then
+        assert_eq!(without.server_info(), None);
+        assert_eq!(with.server_info().map(|info| info.yglc_revision), Some(1));
+        assert_eq!(odd.server_info(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn estimate_power_by_model() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("bright", "50");
+        let floor = Light::from_fields(&map)?;
+        map.insert("model", "ceiling");
+        let ceiling = Light::from_fields(&map)?;
+        map.insert("power", "off");
+
+        // when
+        let off = Light::from_fields(&map)?;
+
+        // then
+        assert_eq!(floor.estimated_power_watts(), 4.5);
+        assert_eq!(ceiling.estimated_power_watts(), 14.0);
+        assert_eq!(off.estimated_power_watts(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn correctly_connects() -> anyhow::Result<()> {
+        // given
+        let map = get_map();
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13454);
+        let _fake_listener = TcpListener::bind(addr)?;
+
+        // when
+        let mut light = Light::from_fields(&map)?;
+        light.init()?;
+
+        // then
+        assert!(light.read.is_some());
+        assert!(light.write.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_only_changed_fields() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13460");
+        map.insert("support", "set_power set_bright set_ct_abx set_rgb set_hsv");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13460), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+        light.init()?;
+        let mut target = light.state();
+        target.bright = 80;
+
+        // when
+        light.apply_state_diff(&target, Transition::sudden())?;
+        light.apply_state_diff(&target, Transition::sudden())?;
+        target.color_mode = ColorMode::Color;
+        light.apply_state_diff(&target, Transition::sudden())?;
+        drop(light);
+
+        // then
+        let methods: Vec<String> = bulb.join().unwrap().into_iter().map(|r| r.method).collect();
+        assert_eq!(methods, vec!["set_bright", "set_rgb"]);
+        Ok(())
+    }
+
+    #[test]
+    fn apply_off_state_only_turns_off() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13461");
+        map.insert("support", "set_power set_bright set_ct_abx set_rgb set_hsv");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13461), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+        light.init()?;
+        let mut target = light.state();
+        target.power = PowerStatus::Off;
+        target.bright = 1;
+
+        // when
+        light.apply_state_diff(&target, Transition::sudden())?;
+        drop(light);
+
+        // then
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].method, "set_power");
+        assert_eq!(reqs[0].params[0], json!("off"));
+        Ok(())
+    }
+
+    #[test]
+    fn get_correct_active_mode() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        let without_mode = Light::from_fields(&map)?;
+        map.insert("active_mode", "1");
+
+        // when
+        let with_mode = Light::from_fields(&map)?;
+
+        // then
+        assert_eq!(without_mode.active_mode(), None);
+        assert_eq!(with_mode.active_mode(), Some(&ActiveMode::Moonlight));
+        Ok(())
+    }
+
+    #[test]
+    fn ct_range_depends_on_active_mode() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13462");
+        map.insert("support", "set_ct_abx");
+        let mut daylight = Light::from_fields(&map)?;
+        map.insert("active_mode", "1");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13462), |_| json!(["ok"]))?;
+        let mut moonlight = Light::from_fields(&map)?;
+        moonlight.init()?;
+
+        // when
+        let daylight_result = daylight.set_ct_abx(2000, Transition::sudden());
+        let moonlight_result = moonlight.set_ct_abx(2000, Transition::sudden());
+        drop(moonlight);
+
+        // then
+        assert!(daylight_result.is_err());
+        assert!(moonlight_result.is_ok());
+        assert_eq!(bulb.join().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn scene_ct_range_depends_on_active_mode() -> anyhow::Result<()> {
+        // given
+        let mut daylight = Light::mock(mock_state());
+        let mut moonlight = Light::mock(mock_state());
+        moonlight.active_mode = Some(ActiveMode::Moonlight);
+        let scene = Scene::Color { color: Color::Ct(2000), bright: 40 };
+
+        // when
+        let daylight_result = daylight.set_scene(&scene);
+        let bg_result = daylight.bg_set_scene(&scene);
+        let moonlight_result = moonlight.set_scene(&scene);
+
+        // then
+        assert!(matches!(daylight_result, Err(YeeError::InvalidValue { field_name: "ct", .. })));
+        assert!(matches!(bg_result, Err(YeeError::InvalidValue { field_name: "ct", .. })));
+        assert!(sent_reqs(&daylight).is_empty());
+        assert!(moonlight_result.is_ok());
+        assert_eq!(sent_reqs(&moonlight)[0].method, "set_scene");
+        Ok(())
+    }
+
+    #[test]
+    fn try_connect_makes_controllable() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13473");
+        let _fake_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13473))?;
+        let mut light = Light::from_fields(&map)?;
+        let before = light.is_controllable();
+
+        // when
+        light.try_connect()?;
+
+        // then
+        assert!(!before);
+        assert!(light.is_controllable());
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_when_connecting() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13500");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13500), |_| json!(["on"]))?;
+        let mut responsive = Light::from_fields(&map)?;
+        responsive.handshake_timeout = Some(Duration::from_millis(500));
+        map.insert("Location", "yeelight://127.0.0.1:13501");
+        let _zombie = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13501))?;
+        let mut unresponsive = Light::from_fields(&map)?;
+        unresponsive.handshake_timeout = Some(Duration::from_millis(200));
+
+        // when
+        let connected = responsive.try_connect();
+        let start = Instant::now();
+        let hung = unresponsive.try_connect();
+
+        // then
+        assert!(connected.is_ok());
+        assert!(responsive.is_controllable());
+        assert_eq!(hung, Err(YeeError::Unresponsive { timeout: Duration::from_millis(200) }));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(!unresponsive.is_controllable());
+        drop(responsive);
+        assert_eq!(bulb.join().unwrap()[0].method, "get_prop");
+        Ok(())
+    }
+
+    #[test]
+    fn drop_connection_when_handshake_fails() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13504");
+        let _bulb = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13504))?;
+        let mut light = Light::from_fields(&map)?;
+        // a zero read timeout is rejected by the socket
+        light.handshake_timeout = Some(Duration::from_secs(0));
+
+        // when
+        let first = light.try_connect();
+        let second = light.try_connect();
+
+        // then
+        assert!(first.is_err());
+        assert!(second.is_err());
+        assert!(!light.is_controllable());
+        Ok(())
+    }
+
+    #[test]
+    fn try_connect_unreachable() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13474");
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let result = light.try_connect();
+
+        // then
+        assert!(result.is_err());
+        assert!(!light.is_controllable());
+        Ok(())
+    }
+
+    #[test]
+    fn connects_on_first_command() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13463");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13463), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        light.set_power(PowerStatus::Off, Transition::sudden())?;
+
+        // then
+        assert!(light.read.is_some());
+        assert!(light.write.is_some());
+        drop(light);
+        assert_eq!(bulb.join().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn set_delay_off_rounds_up_and_cancels() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13464");
+        map.insert("support", "cron_add cron_get cron_del");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13464), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        light.set_delay_off(Duration::from_secs(61))?;
+        light.set_delay_off(Duration::from_secs(120))?;
+        light.set_delay_off(Duration::from_secs(0))?;
+        let too_long = light.set_delay_off(MAX_DELAY_OFF + Duration::from_secs(1));
+        drop(light);
+
+        // then
+        assert!(too_long.is_err());
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs.len(), 3);
+        assert_eq!(reqs[0].method, "cron_add");
+        assert_eq!(reqs[0].params, vec![json!(0), json!(2)]);
+        assert_eq!(reqs[1].params, vec![json!(0), json!(2)]);
+        assert_eq!(reqs[2].method, "cron_del");
+        assert_eq!(reqs[2].params, vec![json!(0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn get_delay_off() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13465");
+        map.insert("support", "cron_get");
+        let mut running = true;
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13465), move |_| {
+            let res = if running { json!([{ "type": 0, "delay": 15, "mix": 0 }]) } else { json!([]) };
+            running = false;
+            res
+        })?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let running_timer = light.get_delay_off()?;
+        let no_timer = light.get_delay_off()?;
+        drop(light);
+
+        // then
+        assert_eq!(running_timer, Some(Duration::from_secs(15 * 60)));
+        assert_eq!(no_timer, None);
+        bulb.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn get_prop_in_order() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13466");
+        map.insert("support", "get_prop");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13466), |_| json!(["on", "45", ""]))?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let props = light.get_prop(&["power", "bright", "music_on"])?;
+        drop(light);
+
+        // then
+        assert_eq!(props.get("power").unwrap(), "on");
+        assert_eq!(props.get("bright").unwrap(), "45");
+        assert_eq!(props.get("music_on").unwrap(), "");
+        assert_eq!(bulb.join().unwrap()[0].params, vec![json!("power"), json!("bright"), json!("music_on")]);
+        Ok(())
+    }
+
+    #[test]
+    fn set_bright_verified_detects_mismatch() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13467");
+        map.insert("support", "get_prop set_bright");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13467), |req| match req.method.as_str() {
+            "get_prop" => json!(["40"]),
+            _ => json!(["ok"])
+        })?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let close_enough = light.set_bright_verified(41, Transition::sudden());
+        let too_far = light.set_bright_verified(50, Transition::sudden());
+        drop(light);
+
+        // then
+        assert_eq!(close_enough?, 40);
+        let not_applied = YeeError::NotApplied { field_name: "bright", expected: "50".into(), actual: "40".into() };
+        assert_eq!(too_far, Err(not_applied));
+        assert_eq!(bulb.join().unwrap().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn crossfade_with_color_flow() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13468");
+        map.insert("support", "start_cf set_rgb");
+        map.insert("rgb", "0");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13468), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        light.crossfade_rgb(Rgb::new(100, 200, 0), Duration::from_millis(200), 2, None)?;
+        let too_fast = light.crossfade_rgb(Rgb::new(0, 0, 0), Duration::from_millis(200), 5, None);
+        drop(light);
+
+        // then
+        assert!(too_fast.is_err());
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].method, "start_cf");
+        let half = Rgb::new(50, 100, 0).get_num();
+        let full = Rgb::new(100, 200, 0).get_num();
+        assert_eq!(reqs[0].params, vec![json!(2), json!(1), json!(format!("100,1,{},-1,100,1,{},-1", half, full))]);
+        Ok(())
+    }
+
+    #[test]
+    fn crossfade_with_set_rgb() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13469");
+        map.insert("support", "set_rgb");
+        map.insert("rgb", "0");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13469), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let finished = light.crossfade_rgb(Rgb::new(90, 0, 30), Duration::from_millis(150), 3, None)?;
+
+        // then
+        assert!(finished);
+        assert_eq!(light.rgb(), &Rgb::new(90, 0, 30));
+        drop(light);
+        let colors: Vec<Value> = bulb.join().unwrap().into_iter().map(|r| r.params[0].clone()).collect();
+        let expected: Vec<Value> = vec![Rgb::new(30, 0, 10), Rgb::new(60, 0, 20), Rgb::new(90, 0, 30)]
+            .into_iter().map(|c| json!(c.get_num())).collect();
+        assert_eq!(colors, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn ramp_bright_in_steps() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(LightState { bright: 1, ct: 2700, ..mock_state() });
+
+        // when
+        let finished = light.ramp_bright(1, 100, Duration::from_millis(150), Duration::from_millis(50),
+                                         Some((2700, 6000)), None)?;
+        let invalid = light.ramp_bright(0, 100, Duration::from_secs(1), Duration::from_millis(50), None, None);
+        let too_fast = light.ramp_bright(1, 100, Duration::from_secs(1), Duration::from_millis(10), None, None);
+
+        // then
+        assert!(finished);
+        assert!(matches!(invalid, Err(YeeError::InvalidValue { field_name: "bright", .. })));
+        assert!(matches!(too_fast, Err(YeeError::InvalidValue { field_name: "step_interval", .. })));
+        assert_eq!(light.bright(), 100);
+        assert_eq!(light.ct(), 6000);
+        let reqs = sent_reqs(&light);
+        let brights: Vec<Value> = reqs.iter().filter(|r| r.method == "set_bright")
+            .map(|r| r.params[0].clone()).collect();
+        assert_eq!(brights, vec![json!(1), json!(34), json!(67), json!(100)]);
+        assert_eq!(reqs[0].params, vec![json!(2700), json!("sudden"), json!(0)]);
+        assert_eq!(reqs[3].params, vec![json!(34), json!("smooth"), json!(50)]);
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_bright_ramp() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(LightState { bright: 1, ct: 2700, ..mock_state() });
+        let cancel = AtomicBool::new(true);
+
+        // when
+        let finished = light.ramp_bright(1, 100, Duration::from_secs(30), Duration::from_secs(1), None, Some(&cancel))?;
+
+        // then
+        assert!(!finished);
+        assert!(light.sent_bytes().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_ramp_and_crossfade_midway() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13502");
+        map.insert("support", "set_bright set_rgb");
+        map.insert("rgb", "0");
+        let cancel = Arc::new(AtomicBool::new(false));
+        let bulb_cancel = Arc::clone(&cancel);
+        let mut received = 0;
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13502), move |_| {
+            received += 1;
+            // cancel each effect after its third step
+            bulb_cancel.store(received % 3 == 0, Ordering::Relaxed);
+            json!(["ok"])
+        })?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let ramped = light.ramp_bright(10, 100, Duration::from_secs(1), Duration::from_millis(50), None,
+                                       Some(&cancel))?;
+        cancel.store(false, Ordering::Relaxed);
+        let faded = light.crossfade_rgb(Rgb::new(100, 0, 0), Duration::from_secs(1), 10, Some(&cancel))?;
+        drop(light);
+
+        // then
+        assert!(!ramped);
+        assert!(!faded);
+        let params: Vec<Value> = bulb.join().unwrap().into_iter().map(|r| r.params[0].clone()).collect();
+        assert_eq!(params, vec![json!(10), json!(14), json!(19), json!(Rgb::new(10, 0, 0).get_num()),
+                                json!(Rgb::new(20, 0, 0).get_num()), json!(Rgb::new(30, 0, 0).get_num())]);
+        Ok(())
+    }
+
+    #[test]
+    fn set_rgb_through_hsv() -> anyhow::Result<()> {
+        // given
+        let state = LightState {
+            power: PowerStatus::On,
+            bright: 50,
+            color_mode: ColorMode::Color,
+            ct: 2700,
+            rgb: Rgb::new(255, 0, 0),
+            hue: 0,
+            sat: 0,
+        };
+        let mut light = Light::mock(state);
+
+        // when
+        light.set_rgb_path(Rgb::new(0, 0, 255), Transition::try_smooth_ms(1000)?, ColorPath::ViaHsv)?;
+        light.set_rgb_path(Rgb::new(0, 255, 0), Transition::try_smooth_ms(1000)?, ColorPath::Direct)?;
+        light.set_rgb_path(Rgb::new(255, 0, 0), Transition::try_smooth_ms(300)?, ColorPath::ViaHsv)?;
+
+        // then
+        assert_eq!(light.rgb(), &Rgb::new(255, 0, 0));
+        let reqs = sent_reqs(&light);
+        let methods: Vec<&str> = reqs.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, vec!["start_cf", "set_rgb", "set_rgb"]);
+        // red to blue the short way, through magenta
+        let colors: Vec<u32> = reqs[0].params[2].as_str().unwrap().split(',').skip(2).step_by(4)
+            .map(str::parse).collect::<Result<_, _>>()?;
+        let expected: Vec<u32> = [(255, 0, 128), (255, 0, 255), (128, 0, 255), (0, 0, 255)].iter()
+            .map(|(red, green, blue)| Rgb::new(*red, *green, *blue).get_num())
+            .collect();
+        assert_eq!(colors, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_from_all_props() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13470");
+        map.insert("support", "get_prop");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13470), |_| {
+            json!(["off", "75", "1", "4000", "65280", "120", "50", "desk", "1", "0"])
+        })?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        light.refresh()?;
+        drop(light.read.take());
+        drop(light.write.take());
+
+        // then
+        let expected = LightState {
+            power: PowerStatus::Off,
+            bright: 75,
+            color_mode: ColorMode::Color,
+            ct: 4000,
+            rgb: Rgb::new(0, 255, 0),
+            hue: 120,
+            sat: 50,
+        };
+        assert_eq!(light.state(), expected);
+        assert_eq!(light.name(), "desk");
+        assert_eq!(light.flowing(), Some(true));
+        assert_eq!(light.active_mode(), Some(&ActiveMode::Daylight));
+        let expected_params: Vec<Value> = REFRESH_PROPS.iter().map(|p| json!(p)).collect();
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].params, expected_params);
+        Ok(())
+    }
+
+    #[test]
+    fn connect_by_host_name() -> anyhow::Result<()> {
+        // given
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13498), |_| {
+            json!(["on", "40", "2", "3500", "0", "0", "0", "porch", "0", "0"])
+        })?;
+
+        let location = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13498);
+        let responder = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41274))?;
+        let searched = thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            let (len, from) = responder.recv_from(&mut buf).unwrap();
+            responder.send_to(fake_advertisement(location, "0x00000000deadbeef").as_bytes(), from).unwrap();
+            buf[..len].to_vec()
+        });
+
+        // when
+        let light = Light::connect_host_searching("localhost", 13498, 41274)?;
+        let unresolved = Light::connect_host("::1", 13498);
+
+        // then
+        assert_eq!(searched.join().unwrap(), crate::SEARCH_MSG.as_bytes());
+        assert!(light.is_controllable());
+        assert_eq!(light.id(), "0x00000000deadbeef");
+        assert_eq!(light.model(), "color");
+        assert_eq!(light.location(), &SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13498));
+        assert_eq!(light.ct(), 3500);
+        assert_eq!(light.name(), "porch");
+        assert!(matches!(unresolved, Err(YeeError::InvalidValue { field_name: "host", .. })));
+        drop(light);
+        assert_eq!(bulb.join().unwrap()[0].method, "get_prop");
+        Ok(())
+    }
+
+    #[test]
+    fn accept_response_without_newline() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13471");
+        map.insert("support", "set_power");
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13471))?;
+        let bulb = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            let req: Req = serde_json::from_str(&line).unwrap();
+            // notification and response, neither terminated
+            write!(writer, r#"{{"method":"props","params":{{"name":"}}"}}}}"#).unwrap();
+            write!(writer, r#"{{"id":{},"result":["ok"]}}"#, req.id).unwrap();
+            thread::sleep(Duration::from_secs(2));
+        });
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let start = std::time::Instant::now();
+        light.set_power(PowerStatus::Off, Transition::sudden())?;
+
+        // then
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(light);
+        bulb.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn scan_complete_json() {
+        // given
+        let mut scanner = JsonScanner::default();
+
+        // when
+        let complete_at: Vec<bool> = br#"{"a":"}\"{","b":{}}"#.iter()
+            .map(|&b| {
+                scanner.feed(b);
+                scanner.is_complete()
+            })
+            .collect();
+
+        // then
+        assert!(complete_at[..complete_at.len() - 1].iter().all(|c| !c));
+        assert!(complete_at[complete_at.len() - 1]);
+    }
+
+    #[test]
+    fn surface_bulb_errors() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13472");
+        map.insert("support", "set_power");
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13472))?;
+        let bulb = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            let req: Req = serde_json::from_str(&line).unwrap();
+            let res = json!({ "id": req.id, "error": { "code": -5001, "message": "invalid params" } });
+            write!(writer, "{}\r\n", res).unwrap();
+        });
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let result = light.set_power(PowerStatus::Off, Transition::sudden());
+        bulb.join().unwrap();
+
+        // then
+        match result {
+            Err(YeeError::ChangeFailed { code, message }) => {
+                assert_eq!(code, BulbErrorCode::ValueOutOfRange);
+                assert_eq!(message, "invalid params");
+            }
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert_eq!(light.power(), &PowerStatus::On);
+        Ok(())
+    }
+
+    #[test]
+    fn identify_with_color_flow() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13475");
+        map.insert("support", "start_cf toggle");
+        map.insert("ct", "3000");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13475), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        light.identify()?;
+        drop(light);
+
+        // then
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].method, "start_cf");
+        assert_eq!(reqs[0].params, vec![json!(4), json!(0), json!("250,2,3000,100,250,2,3000,1")]);
+        Ok(())
+    }
+
+    #[test]
+    fn identify_with_toggle() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13476");
+        map.insert("support", "toggle");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13476), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+        let mut unsupported = Light::from_fields(&get_map())?;
+
+        // when
+        light.identify()?;
+        let unsupported_result = unsupported.identify();
+
+        // then
+        assert!(unsupported_result.is_err());
+        assert_eq!(light.power(), &PowerStatus::On);
+        drop(light);
+        let methods: Vec<String> = bulb.join().unwrap().into_iter().map(|r| r.method).collect();
+        assert_eq!(methods, vec!["toggle", "toggle"]);
+        Ok(())
+    }
+
+    #[test]
+    fn count_metrics() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13477");
+        map.insert("support", "set_power toggle");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13477), |req| match req.method.as_str() {
+            "toggle" => json!({ "error": { "code": -5000, "message": "general error" } }),
+            _ => json!(["ok"])
+        })?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        light.set_power(PowerStatus::Off, Transition::sudden())?;
+        let failed = light.toggle();
+        let unsupported = light.set_bright(50, Transition::sudden());
+        let metrics = light.metrics();
+        drop(light);
+
+        // then
+        assert!(failed.is_err());
+        assert!(unsupported.is_err());
+        let reqs = bulb.join().unwrap();
+        let bytes: usize = reqs.iter().map(|r| serde_json::to_string(r).unwrap().len() + 2).sum();
+        assert_eq!(metrics, LightMetrics { commands_sent: 2, errors: 1, connections: 1, bytes_written: bytes as u64 });
+        Ok(())
+    }
+
+    #[test]
+    fn mock_captures_commands() -> anyhow::Result<()> {
+        // given
+        let state = mock_state();
+        let mut light = Light::mock(state.clone());
+
+        // when
+        light.set_bright(80, Transition::sudden())?;
+        light.refresh()?;
+
+        // then
+        assert!(!light.is_controllable());
+        assert_eq!(light.state(), LightState { bright: 80, ..state });
+        let reqs = sent_reqs(&light);
+        assert_eq!(reqs.len(), 2);
+        assert_eq!(reqs[0].method, "set_bright");
+        assert_eq!(reqs[0].params, vec![json!(80), json!("sudden"), json!(0)]);
+        assert_eq!(reqs[1].method, "get_prop");
+        Ok(())
+    }
+
+    #[test]
+    fn explain_command_caveats() {
+        // given
+        let mut state = mock_state();
+        let on = Light::mock(state.clone());
+        state.power = PowerStatus::Off;
+        let off = Light::mock(state);
+
+        // when
+        let dim = on.explain_command("set_bright", &[json!(0), json!("sudden"), json!(0)]);
+        let rgb = on.explain_command("set_rgb", &[json!(255), json!("smooth"), json!(10)]);
+        let bright = on.explain_command("set_bright", &[json!(50), json!("smooth"), json!(500)]);
+        let while_off = off.explain_command("set_ct_abx", &[json!(3000), json!("sudden"), json!(0)]);
+
+        // then
+        assert_eq!(dim.as_deref(), Some("brightness below 1 will turn the bulb off"));
+        assert_eq!(rgb.as_deref(), Some("set_rgb switches the light out of color temperature mode; \
+            smooth transitions shorter than 30ms are rejected"));
+        assert_eq!(bright, None);
+        assert_eq!(while_off.as_deref(), Some("set_ct_abx is rejected while the light is off"));
+    }
+
+    #[test]
+    fn bg_flow_and_scene() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13478");
+        map.insert("support", "set_scene bg_start_cf bg_stop_cf bg_set_scene");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13478), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+        let flow = ColorFlow::new(0, FlowEnd::Recover, vec![FlowTuple::rgb(500, Rgb::new(0, 0, 255), 50)]);
+        let scene = Scene::Color { color: Color::Ct(2700), bright: 40 };
+
+        // when
+        light.bg_start_cf(&flow)?;
+        light.bg_stop_cf()?;
+        light.bg_set_scene(&scene)?;
+        light.set_scene(&scene)?;
+        drop(light);
+
+        // then
+        let reqs = bulb.join().unwrap();
+        let sent: Vec<(&str, &Vec<Value>)> = reqs.iter().map(|r| (r.method.as_str(), &r.params)).collect();
+        assert_eq!(sent, vec![
+            ("bg_start_cf", &vec![json!(0), json!(0), json!("500,1,255,50")]),
+            ("bg_stop_cf", &vec![]),
+            ("bg_set_scene", &vec![json!("ct"), json!(2700), json!(40)]),
+            ("set_scene", &vec![json!("ct"), json!(2700), json!(40)]),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn wait_for_last_smooth_transition() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(mock_state());
+        let smooth = Transition::smooth(Duration::from_millis(300)).unwrap();
+
+        // when
+        let start = Instant::now();
+        light.wait_for_last_transition();
+        let idle = start.elapsed();
+        light.set_ct_abx(4000, smooth)?;
+        light.set_bright(50, Transition::sudden())?;
+        light.wait_for_last_transition();
+        let waited = start.elapsed();
+
+        // then
+        assert!(idle < Duration::from_millis(50));
+        assert!(waited >= Duration::from_millis(300));
+        assert!(waited < Duration::from_secs(2));
+        Ok(())
+    }
+
+    #[test]
+    fn apply_preset_with_and_without_scene() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13479");
+        map.insert("support", "set_power set_bright set_ct_abx set_scene");
+        map.insert("power", "off");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13479), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+        let smooth = Transition::smooth(Duration::from_millis(300)).unwrap();
+
+        // when
+        light.apply_preset(Preset::Relax, Transition::sudden())?;
+        light.apply_preset(Preset::Reading, smooth)?;
+        let state = light.state();
+        drop(light);
+
+        // then
+        let methods: Vec<String> = bulb.join().unwrap().into_iter().map(|r| r.method).collect();
+        assert_eq!(methods, vec!["set_scene", "set_ct_abx", "set_bright"]);
+        assert_eq!((state.power, state.ct, state.bright), (PowerStatus::On, 4000, 100));
+        Ok(())
+    }
+
+    #[test]
+    fn send_raw_json_validates_request() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13482");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13482), |req| json!([req.params.len()]))?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let malformed = light.send_raw_json(r#"{"id":1,"method":"set_power""#);
+        let missing_params = light.send_raw_json(r#"{"id":1,"method":"set_power"}"#);
+        let result = light.send_raw_json(r#"{"id":7,"method":"set_power","params":["on","smooth",500]}"#)?;
+        drop(light);
+
+        // then
+        assert!(matches!(malformed, Err(YeeError::JsonError { .. })));
+        assert!(matches!(missing_params, Err(YeeError::JsonError { .. })));
+        assert_eq!(result, json!([3]));
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs.len(), 1);
+        assert_eq!((reqs[0].id, reqs[0].method.as_str()), (7, "set_power"));
+        Ok(())
+    }
+
+    #[test]
+    fn set_complement_by_color_mode() -> anyhow::Result<()> {
+        // given
+        let mut state = LightState {
+            power: PowerStatus::On,
+            bright: 20,
+            color_mode: ColorMode::Color,
+            ct: 3000,
+            rgb: Rgb::new(255, 0, 0),
+            hue: 300,
+            sat: 50,
+        };
+        let mut rgb = Light::mock(state.clone());
+        state.color_mode = ColorMode::Hsv;
+        let mut hsv = Light::mock(state.clone());
+        state.color_mode = ColorMode::ColorTemperature;
+        let mut ct = Light::mock(state);
+
+        // when
+        rgb.set_complement(Transition::sudden())?;
+        hsv.set_complement(Transition::sudden())?;
+        let ct_result = ct.set_complement(Transition::sudden());
+
+        // then
+        assert_eq!(rgb.rgb(), &Rgb::new(0, 255, 255));
+        assert_eq!((hsv.hue(), hsv.sat()), (120, 50));
+        assert!(matches!(ct_result, Err(YeeError::InvalidValue { field_name: "color_mode", .. })));
+        assert!(ct.sent_bytes().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn start_cf_from_baseline() -> anyhow::Result<()> {
+        // given
+        let state = mock_state();
+        let mut light = Light::mock(state.clone());
+        let flow = ColorFlow::new(2, FlowEnd::Recover, vec![FlowTuple::rgb(500, Rgb::new(0, 0, 255), 50)]);
+        let baseline = LightState { power: PowerStatus::Off, ct: 4000, ..state };
+
+        // when
+        light.start_cf_with_baseline(&flow, baseline)?;
+
+        // then
+        let reqs = sent_reqs(&light);
+        let methods: Vec<&str> = reqs.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, vec!["set_ct_abx", "start_cf"]);
+        assert_eq!(reqs[1].params, vec![json!(2), json!(2), json!("500,1,255,50")]);
+        assert_eq!(light.ct(), 4000);
+        Ok(())
+    }
+
+    #[test]
+    fn set_power_with_default_transitions() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(LightState { power: PowerStatus::Off, ..mock_state() });
+        light.set_power_transitions(Transition::sudden(), Transition::smooth(Duration::from_secs(2)).unwrap());
+
+        // when
+        light.set_power_default(PowerStatus::On)?;
+        light.set_power_default(PowerStatus::Off)?;
+
+        // then
+        let reqs = sent_reqs(&light);
+        assert_eq!(reqs[0].params, vec![json!("on"), json!("sudden"), json!(0)]);
+        assert_eq!(reqs[1].params, vec![json!("off"), json!("smooth"), json!(2000)]);
+        Ok(())
+    }
+
+    #[test]
+    fn play_scene_file_in_order() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(mock_state());
+        let scene = SceneFile::from_json(r#"{"steps": [
+            {"color": {"ct": 2700}, "bright": 40, "transition": "smooth:100"},
+            {"bright": 100, "delay_ms": 100}
+        ]}"#)?;
+
+        // when
+        let start = Instant::now();
+        light.play_scene_file(&scene)?;
+
+        // then
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let methods: Vec<String> = sent_reqs(&light).into_iter().map(|r| r.method).collect();
+        assert_eq!(methods, vec!["set_ct_abx", "set_bright", "set_bright"]);
+        assert_eq!(light.bright(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_stats_when_partly_reported() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13485");
+        map.insert("support", "get_prop");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13485), |_| json!(["12", ""]))?;
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        let stats = light.runtime_stats()?;
+        drop(light);
+
+        // then
+        assert_eq!(stats, RuntimeStats { power_on_count: Some(12), runtime: None });
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs[0].params, vec![json!("power_on_count"), json!("runtime")]);
+        Ok(())
+    }
+
+    #[test]
+    fn retry_transient_failures() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13486");
+        map.insert("support", "set_power toggle");
+        let mut quota_left = 2;
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13486), move |req| match req.method.as_str() {
+            "set_power" if quota_left > 0 => {
+                quota_left -= 1;
+                json!({ "error": { "code": -1, "message": "client quota exceeded" } })
+            }
+            "toggle" => json!({ "error": { "code": -5000, "message": "general error" } }),
+            _ => json!(["ok"])
+        })?;
+        let mut light = Light::from_fields(&map)?;
+        light.set_retry_policy(3, Duration::from_millis(10));
+
+        // when
+        let retried = light.set_power(PowerStatus::Off, Transition::sudden());
+        let failed = light.toggle();
+        drop(light);
+
+        // then
+        assert!(retried.is_ok());
+        assert!(failed.is_err());
+        let methods: Vec<String> = bulb.join().unwrap().into_iter().map(|r| r.method).collect();
+        assert_eq!(methods, vec!["set_power", "set_power", "set_power", "toggle"]);
+        Ok(())
+    }
+
+    #[test]
+    fn only_resend_idempotent_commands_without_response() {
+        // given
+        let timed_out = YeeError::IoError { source: io::Error::from(io::ErrorKind::TimedOut) };
+        let quota = YeeError::ChangeFailed { code: BulbErrorCode::QuotaExceeded, message: String::new() };
+
+        // then
+        assert!(can_resend("set_power", &timed_out));
+        assert!(can_resend("get_prop", &timed_out));
+        assert!(!can_resend("toggle", &timed_out));
+        assert!(!can_resend("adjust_bright", &timed_out));
+        assert!(!can_resend("start_cf", &timed_out));
+        assert!(can_resend("toggle", &quota));
+    }
+
+    #[test]
+    fn set_xy_validates_coordinates() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(mock_state());
+
+        // when
+        let out_of_range = light.set_xy(1.5, 0.3, Transition::sudden());
+        let no_luminance = light.set_xy(0.3, 0.0, Transition::sudden());
+        light.set_xy(0.64, 0.33, Transition::sudden())?;
+
+        // then
+        assert!(matches!(out_of_range, Err(YeeError::InvalidValue { field_name: "x", .. })));
+        assert!(matches!(no_luminance, Err(YeeError::InvalidValue { field_name: "y", .. })));
+        assert_eq!(light.rgb(), &Rgb::new(255, 0, 0));
+        assert_eq!(light.bright(), 20);
+        Ok(())
+    }
+
+    #[test]
+    fn restore_from_saved_state() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13487");
+        map.insert("support", "set_power");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13487), |_| json!(["ok"]))?;
+        let original = Light::from_fields(&map)?;
+        let saved = serde_json::to_string(&original.snapshot())?;
+
+        // when
+        let snapshot: LightSnapshot = serde_json::from_str(&saved)?;
+        let mut light = Light::from_state(snapshot.clone())?;
+        let restored = light.snapshot();
+        let same_light = light == original;
+        let controllable = light.is_controllable();
+        let unsupported = light.set_rgb(Rgb::new(255, 0, 0), Transition::sudden());
+        light.set_power(PowerStatus::Off, Transition::sudden())?;
+        let location = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 55443);
+        let unspecified = Light::from_state(LightSnapshot { location, ..snapshot.clone() });
+        drop(light);
+
+        // then
+        assert!(!controllable);
+        assert_eq!(restored, snapshot);
+        assert!(same_light);
+        assert!(matches!(unsupported, Err(YeeError::MethodNotSupported { .. })));
+        assert_eq!(bulb.join().unwrap().len(), 1);
+        assert!(unspecified.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ensure_power_skips_redundant() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(mock_state());
+
+        // when
+        let already_on = light.ensure_power(PowerStatus::On, Transition::sudden())?;
+        let turned_off = light.ensure_power(PowerStatus::Off, Transition::sudden())?;
+
+        // then
+        assert!(!already_on);
+        assert!(turned_off);
+        assert_eq!(light.power(), &PowerStatus::Off);
+        let reqs = sent_reqs(&light);
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].method, "set_power");
+        assert_eq!(reqs[0].params[0], json!("off"));
+        Ok(())
+    }
+
+    #[test]
+    fn supported_methods_are_known_methods() -> anyhow::Result<()> {
+        // given
+        let known: HashSet<&str> = KNOWN_METHODS.iter().copied().collect();
+
+        // when
+        let supported: HashSet<&str> = supported_methods().iter().copied().collect();
+        let mock = Light::mock(mock_state());
+
+        // then
+        assert_eq!(supported.len(), supported_methods().len());
+        assert_eq!(supported, known);
+        assert!(known.iter().all(|method| mock.support().contains(*method)));
+        Ok(())
+    }
+
+    #[test]
+    fn apply_white_calibration() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(LightState { bright: 50, ct: 4000, ..mock_state() });
+        light.set_white_calibration(-150, 5);
+
+        // when
+        light.set_ct_abx(3000, Transition::sudden())?;
+        light.set_ct_abx(2750, Transition::sudden())?;
+        light.set_bright(97, Transition::sudden())?;
+        let invalid = light.set_ct_abx(2000, Transition::sudden());
+
+        // then
+        assert_eq!(light.white_calibration(), (-150, 5));
+        assert_eq!(light.ct(), 2750);
+        assert_eq!(light.bright(), 97);
+        assert!(matches!(invalid, Err(YeeError::InvalidValue { field_name: "ct", .. })));
+        let reqs = sent_reqs(&light);
+        let values: Vec<Value> = reqs.iter().map(|r| r.params[0].clone()).collect();
+        assert_eq!(values, vec![json!(2850), json!(2700), json!(100)]);
+        Ok(())
+    }
+
+    #[test]
+    fn set_bright_on_perceptual_curve() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(LightState { bright: 50, ct: 4000, ..mock_state() });
 
-    pub fn hue(&self) -> u16 {
-        self.hue
-    }
+        // when
+        for perceived in &[1, 8, 50, 75, 100] {
+            light.set_bright_perceptual(*perceived, Transition::sudden())?;
+        }
+        let invalid = light.set_bright_perceptual(0, Transition::sudden());
 
-    pub fn sat(&self) -> u8 {
-        self.sat
+        // then
+        assert_eq!(light.bright(), 100);
+        assert!(matches!(invalid, Err(YeeError::InvalidValue { field_name: "bright", .. })));
+        let reqs = sent_reqs(&light);
+        let values: Vec<Value> = reqs.iter().map(|r| r.params[0].clone()).collect();
+        assert_eq!(values, vec![json!(1), json!(1), json!(18), json!(48), json!(100)]);
+        assert!((1..=100).map(perceived_to_linear).collect::<Vec<_>>().windows(2).all(|w| w[0] <= w[1]));
+        Ok(())
     }
 
-    pub fn name(&self) -> &str {
-        &self.name
+    #[test]
+    fn send_over_music_channel() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13499");
+        map.insert("support", "get_prop set_power set_music");
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13499))?;
+        let bulb = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let read_req = |reader: &mut dyn BufRead| {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                serde_json::from_str::<Req>(&line).unwrap()
+            };
+            let set_music = read_req(&mut reader);
+            write!(writer, "{{\"id\":{},\"result\":[\"ok\"]}}\r\n", set_music.id).unwrap();
+            let host = set_music.params[1].as_str().unwrap();
+            let port = set_music.params[2].as_u64().unwrap();
+            let mut music = BufReader::new(TcpStream::connect(format!("{}:{}", host, port)).unwrap());
+            let over_music = read_req(&mut music);
+            let get_prop = read_req(&mut reader);
+            write!(writer, "{{\"id\":{},\"result\":[\"off\"]}}\r\n", get_prop.id).unwrap();
+            (over_music, get_prop)
+        });
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        light.start_music()?;
+        light.prefer_music_channel(true);
+        light.set_power(PowerStatus::Off, Transition::sudden())?;
+        let props = light.get_prop(&["power"])?;
+
+        // then
+        assert!(light.is_music_on());
+        assert_eq!(props.get("power").map(String::as_str), Some("off"));
+        let (over_music, get_prop) = bulb.join().unwrap();
+        assert_eq!(over_music.method, "set_power");
+        assert_eq!(get_prop.method, "get_prop");
+        Ok(())
     }
-}
 
-impl Hash for Light {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        state.write(self.id.as_bytes());
+    #[test]
+    fn keep_command_history() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(LightState { bright: 50, ct: 2700, ..mock_state() });
+        light.set_bright(10, Transition::sudden())?;
+        light.set_history_len(2);
+
+        // when
+        light.set_bright(20, Transition::sudden())?;
+        light.set_ct_abx(3000, Transition::sudden())?;
+        light.toggle()?;
+
+        // then
+        let methods: Vec<&str> = light.history().iter().map(|entry| entry.method.as_str()).collect();
+        assert_eq!(methods, vec!["set_ct_abx", "toggle"]);
+        assert_eq!(light.history()[0].params, r#"[3000,"sudden",0]"#);
+        assert!(light.history().iter().all(|entry| entry.error.is_none()));
+        light.set_history_len(1);
+        assert_eq!(light.history().len(), 1);
+        assert_eq!(light.history()[0].method, "toggle");
+        Ok(())
     }
-}
 
-impl PartialEq for Light {
-    fn eq(&self, other: &Self) -> bool {
-        self.id.eq(&other.id)
+    #[test]
+    fn swap_states_of_two_lights() -> anyhow::Result<()> {
+        // given
+        let red = LightState {
+            power: PowerStatus::On,
+            bright: 80,
+            color_mode: ColorMode::Color,
+            ct: 4000,
+            rgb: Rgb::new(255, 0, 0),
+            hue: 0,
+            sat: 0,
+        };
+        let warm = LightState { bright: 20, color_mode: ColorMode::ColorTemperature, ct: 2700, ..red.clone() };
+        let mut color = Light::mock(red.clone());
+        let mut white = Light::mock(warm.clone());
+        white.support.remove("set_rgb");
+
+        // when
+        color.swap_state_with(&mut white, Transition::sudden())?;
+
+        // then
+        assert_eq!(color.state(), LightState { rgb: red.rgb, ..warm.clone() });
+        assert_eq!(white.state(), LightState { bright: 80, ..warm });
+        let reqs = sent_reqs(&white);
+        let methods: Vec<&str> = reqs.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, vec!["get_prop", "set_bright"]);
+        Ok(())
     }
-}
 
-impl Eq for Light {}
+    #[test]
+    fn restore_state_when_swap_fails() -> anyhow::Result<()> {
+        // given
+        let on = LightState { bright: 80, ct: 4000, ..mock_state() };
+        let off = LightState { power: PowerStatus::Off, bright: 20, ..on.clone() };
+        let mut light = Light::mock(on.clone());
+        let mut stuck = Light::mock(off);
+        stuck.support.remove("set_power");
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
-    use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener};
+        // when
+        let result = light.swap_state_with(&mut stuck, Transition::sudden());
 
-    use super::*;
+        // then
+        assert_eq!(result, Err(YeeError::MethodNotSupported { method_name: "set_power" }));
+        assert_eq!(light.state(), on);
+        let reqs = sent_reqs(&light);
+        let methods: Vec<&str> = reqs.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, vec!["get_prop", "set_power", "set_power"]);
+        Ok(())
+    }
 
-    macro_rules! map {
-        ($($key:expr => $value: expr), *) => {{
-            let mut map = HashMap::new();
-            $(map.insert($key,$value);)*
-            map
-        }};
+    #[test]
+    fn normalize_and_save_default() -> anyhow::Result<()> {
+        // given
+        let state = LightState {
+            power: PowerStatus::Off,
+            bright: 100,
+            color_mode: ColorMode::Color,
+            ct: 6500,
+            rgb: Rgb::new(255, 0, 0),
+            hue: 0,
+            sat: 0,
+        };
+        let mut light = Light::mock(state);
+
+        // when
+        let invalid = light.normalize(2000, 50);
+        light.normalize(2700, 50)?;
+
+        // then
+        assert!(matches!(invalid, Err(YeeError::InvalidValue { field_name: "ct", .. })));
+        assert_eq!(light.power(), &PowerStatus::On);
+        assert_eq!(light.ct(), 2700);
+        assert_eq!(light.bright(), 50);
+        let reqs = sent_reqs(&light);
+        let methods: Vec<&str> = reqs.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, vec!["set_power", "set_ct_abx", "set_bright", "set_default"]);
+        Ok(())
     }
 
-    pub(crate) fn get_map() -> HashMap<&'static str, &'static str> {
-        let mut m: HashMap<&str, &str> =
-            map!(
-            "id" => "0x1234",
-            "model" => "floor",
-            "fw_ver" => "40", // can fail
-            "power" => "on", // can fail
-            "bright" => "34", // can fail
-            "color_mode" => "2", // can fail
-            "ct" => "0", // can fail
-            "rgb" => "657930", // 0A0A0A, can fail
-            "hue" => "314", // can fail
-            "sat" => "12", // can fail
-            "name" => "room_light",
-            "Location" => "yeelight://127.0.0.1:13454"
-            );
-        let support = "get_power set_power get_rgb set_rgb";
-        m.insert("support", support);
-        m
+    #[test]
+    fn set_rgb_from_hex() -> anyhow::Result<()> {
+        // given
+        let mut light = Light::mock(LightState { bright: 50, ct: 2700, ..mock_state() });
+
+        // when
+        light.set_rgb_hex(0xFF8800, Transition::sudden())?;
+        let too_big = light.set_rgb_hex(0x1000000, Transition::sudden());
+
+        // then
+        assert_eq!(light.rgb(), &Rgb::new(255, 136, 0));
+        assert!(matches!(too_big, Err(YeeError::InvalidValue { field_name: "rgb", .. })));
+        let reqs = sent_reqs(&light);
+        assert_eq!(reqs.len(), 1);
+        assert_eq!(reqs[0].params[0], json!(0xFF8800));
+        Ok(())
     }
 
     #[test]
-    fn send_correct_req() -> anyhow::Result<()> {
+    fn capability_flags_from_support() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("support", "get_prop set_rgb start_cf set_music cron_add bg_set_power");
+        let light = Light::from_fields(&map)?;
+
+        // when
+        let caps = light.capability_flags();
+
+        // then
+        assert_eq!(caps, Capabilities::SET_RGB | Capabilities::COLOR_FLOW | Capabilities::MUSIC | Capabilities::CRON
+            | Capabilities::BACKGROUND);
+        assert_eq!(caps & supported_capabilities(), caps);
+        assert_eq!(supported_capabilities(), Capabilities::all());
+        assert!(light.support().contains("get_prop"));
         Ok(())
     }
 
     #[test]
-    fn get_correct_location() -> anyhow::Result<()> {
+    fn read_and_set_save_state() -> anyhow::Result<()> {
         // given
-        let map = get_map();
-        let expected_addr = match SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), 13454) {
-            SocketAddr::V4(v4) => v4,
-            _ => unreachable!()
-        };
+        let mut map = get_map();
+        map.insert("save_state", "1");
+        let discovered = Light::from_fields(&map)?;
+        let unsupported = Light::from_fields(&get_map())?.set_save_state_on_change(true);
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13488");
+        map.insert("support", "get_prop set_ps");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13488), |req| match req.method.as_str() {
+            "get_prop" => json!(["1"]),
+            _ => json!(["ok"])
+        })?;
+        let mut light = Light::from_fields(&map)?;
 
         // when
-        let light = Light::from_fields(&map)?;
+        let before = light.saves_state();
+        light.set_save_state_on_change(false)?;
+        let after_set = light.saves_state();
+        light.get_prop(&["save_state"])?;
+        let after_get = light.saves_state();
+        drop(light);
 
         // then
-        assert_eq!(*light.location(), expected_addr);
+        assert_eq!(discovered.saves_state(), Some(true));
+        assert!(matches!(unsupported, Err(YeeError::MethodNotSupported { .. })));
+        assert_eq!(before, None);
+        assert_eq!(after_set, Some(false));
+        assert_eq!(after_get, Some(true));
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs[0].method, "set_ps");
+        assert_eq!(reqs[0].params, vec![json!("cfg_save_state"), json!("0")]);
         Ok(())
     }
 
-    macro_rules! generate_getter_tests {
-        () => {};
-        ($field:ident, $($tail: tt)*) => {
-            #[test]
-            fn $field() -> anyhow::Result<()> {
+    #[test]
+    fn detect_truncated_name() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13491");
+        map.insert("support", "get_prop set_name");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13491), |req| match req.method.as_str() {
+            "get_prop" => json!(["living_room_lam"]),
+            _ => json!(["ok"])
+        })?;
+        let mut light = Light::from_fields(&map)?;
 
-                // given
-                let map = get_map();
+        // when
+        let result = light.set_name_verified("living_room_lamp");
+        drop(light.read.take());
+        drop(light.write.take());
 
-                // when
-                let light = Light::from_fields(&map)?;
+        // then
+        assert!(matches!(result, Err(YeeError::NotApplied { field_name: "name", .. })));
+        assert_eq!(light.name(), "living_room_lam");
+        let reqs = bulb.join().unwrap();
+        assert_eq!(reqs[0].method, "set_name");
+        assert_eq!(reqs[0].params, vec![json!("living_room_lamp")]);
+        assert_eq!(reqs[1].method, "get_prop");
+        Ok(())
+    }
 
-                // then
-                assert_eq!(map.get(stringify!($field)).unwrap(), &light.$field().to_string());
-                Ok(())
-            }
-            generate_getter_tests!($($tail)*);
-        };
-        ($field:ident => $expected: expr, $($tail: tt)*) => {
-            #[test]
-            fn $field() -> anyhow::Result<()> {
+    #[test]
+    fn sample_brightness_during_transition() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13492");
+        map.insert("support", "get_prop");
+        let mut bright = 0;
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13492), move |_| {
+            bright += 25;
+            json!([bright.to_string()])
+        })?;
+        let mut light = Light::from_fields(&map)?;
 
-                // given
-                let map = get_map();
+        // when
+        let start = Instant::now();
+        let samples = light.sample_during_transition(Duration::from_millis(20), 4)?;
+        let elapsed = start.elapsed();
+        drop(light.read.take());
+        drop(light.write.take());
 
-                // when
-                let light = Light::from_fields(&map)?;
+        // then
+        assert_eq!(samples, vec![25, 50, 75, 100]);
+        assert!(elapsed >= Duration::from_millis(60));
+        assert_eq!(light.bright(), 34);
+        assert_eq!(bulb.join().unwrap().len(), 4);
+        Ok(())
+    }
 
-                // then
-                assert_eq!(&$expected, light.$field());
-                Ok(())
+    #[test]
+    fn send_without_waiting() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13493");
+        map.insert("support", "set_power");
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13493))?;
+        let bulb = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            for _ in 0..2 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let req: Req = serde_json::from_str(&line).unwrap();
+                write!(writer, "{{\"id\":{},\"result\":[\"ok\"]}}\r\n", req.id).unwrap();
+                write!(writer, "{{\"method\":\"props\",\"params\":{{\"power\":\"off\"}}}}\r\n").unwrap();
             }
-            generate_getter_tests!($($tail)*);
-        };
+        });
+        let mut light = Light::from_fields(&map)?;
+        let req = Req::new("set_power".to_string(), vec![json!("off"), json!("sudden"), json!(0)]);
+
+        // when
+        light.send_req_no_wait(&req)?;
+        thread::sleep(Duration::from_millis(200));
+        let notifications = light.drain_notifications()?;
+        let nothing_more = light.drain_notifications()?;
+        let kept = light.read_result(req.id)?;
+        light.set_power(PowerStatus::Off, Transition::sudden())?;
 
+        // then
+        assert_eq!(notifications, vec![json!({"power": "off"})]);
+        assert_eq!(kept, json!(["ok"]));
+        assert!(nothing_more.is_empty());
+        assert_eq!(light.power(), &PowerStatus::Off);
+        drop(light);
+        bulb.join().unwrap();
+        Ok(())
     }
 
-    mod test_get_parse {
-        use super::*;
+    #[test]
+    fn parse_and_set_background_light() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13497");
+        map.insert("support", "bg_set_scene");
+        for (name, value) in [("bg_power", "off"), ("bg_bright", "30"), ("bg_lmode", "2"), ("bg_ct", "4000"),
+            ("bg_rgb", "255"), ("bg_hue", "0"), ("bg_sat", "0")] {
+            map.insert(name, value);
+        }
+        let single = Light::from_fields(&get_map())?;
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13497), |_| json!(["ok"]))?;
+        let mut light = Light::from_fields(&map)?;
+        let discovered = light.background().cloned();
 
-        generate_getter_tests!(
-            id,
-            model,
-            fw_ver,
-            power,
-            bright,
-            color_mode => ColorMode::ColorTemperature,
-            ct,
-            rgb => Rgb { red: 10, green: 10, blue: 10 },
-            hue,
-            sat,
-            name, );
+        // when
+        light.bg_set_scene(&Scene::Color { color: Color::Rgb(Rgb::new(255, 0, 0)), bright: 60 })?;
+
+        // then
+        assert!(single.background().is_none());
+        assert_eq!(discovered, Some(BackgroundLight {
+            power: PowerStatus::Off,
+            bright: 30,
+            color_mode: ColorMode::ColorTemperature,
+            ct: 4000,
+            rgb: Rgb::new(0, 0, 255),
+            hue: 0,
+            sat: 0,
+        }));
+        let background = light.background().unwrap();
+        assert_eq!(background.power, PowerStatus::On);
+        assert_eq!(background.bright, 60);
+        assert_eq!(background.color_mode, ColorMode::Color);
+        assert_eq!(background.rgb, Rgb::new(255, 0, 0));
+        assert_eq!(light.bright(), single.bright());
+        drop(light);
+        assert_eq!(bulb.join().unwrap().len(), 1);
+        Ok(())
     }
 
-    macro_rules! generate_parse_fail_tests {
-        ($($field:ident), *) => {
-            $(
-                #[test]
-                fn $field() {
+    #[test]
+    fn apply_mode_change_notification() -> anyhow::Result<()> {
+        // given
+        let state = LightState { bright: 50, ct: 2700, ..mock_state() };
+        let mut light = Light::mock(state.clone());
 
-                    // given
-                    let mut map = get_map();
-                    map.remove(stringify!($field)).unwrap();
+        // when
+        let notification = json!({"color_mode": 1, "rgb": "65280", "flowing": 1, "bg_power": "on"});
+        let change = light.apply_notification(&notification);
+        let repeated = light.apply_notification(&json!({"color_mode": "1", "flowing": "1"}));
 
-                    // when
-                    let fail = Light::from_fields(&map);
+        // then
+        assert_eq!(light.color_mode(), &ColorMode::Color);
+        assert_eq!(light.rgb(), &Rgb::new(0, 255, 0));
+        assert_eq!(light.flowing(), Some(true));
+        assert_eq!(change.mode_change(), Some(ColorMode::Color));
+        assert_eq!(change.flowing, Some(true));
+        assert_eq!(change.before, state);
+        assert!(repeated.is_empty());
+        assert_eq!(repeated.mode_change(), None);
+        Ok(())
+    }
 
-                    // then
-                    assert!(fail.is_err());
-                }
-            )*
-        };
+    #[test]
+    fn apply_notifications_while_waiting_for_response() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13503");
+        map.insert("support", "set_bright");
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13503))?;
+        let bulb = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let req: Req = serde_json::from_str(&line).unwrap();
+            write!(writer, "{{\"method\":\"props\",\"params\":{{\"color_mode\":1,\"flowing\":1}}}}\r\n").unwrap();
+            write!(writer, "{{\"id\":{},\"result\":[\"ok\"]}}\r\n", req.id).unwrap();
+        });
+        let mut light = Light::from_fields(&map)?;
+
+        // when
+        light.set_bright(80, Transition::sudden())?;
+
+        // then
+        assert_eq!(light.color_mode(), &ColorMode::Color);
+        assert_eq!(light.flowing(), Some(true));
+        assert_eq!(light.bright(), 80);
+        drop(light);
+        bulb.join().unwrap();
+        Ok(())
     }
 
-    mod test_parse_fail {
-        use super::*;
+    #[test]
+    fn set_hsv_and_bright_together() -> anyhow::Result<()> {
+        // given
+        let state = mock_state();
+        let mut sudden = Light::mock(state.clone());
+        let mut smooth = Light::mock(state.clone());
+        let mut stepped = Light::mock(state);
+        stepped.support.remove("start_cf");
+        let transition = Transition::try_smooth_ms(500)?;
 
-        generate_parse_fail_tests!(
-            id,
-            model,
-            fw_ver,
-            support,
-            power,
-            bright,
-            color_mode,
-            ct,
-            rgb,
-            hue,
-            sat,
-            name);
+        // when
+        sudden.set_hsv_bright(120, 50, 80, Transition::sudden())?;
+        smooth.set_hsv_bright(120, 50, 80, transition)?;
+        stepped.set_hsv_bright(120, 50, 80, transition)?;
+        let invalid = smooth.set_hsv_bright(120, 101, 80, transition);
+
+        // then
+        assert!(matches!(invalid, Err(YeeError::InvalidValue { field_name: "sat", .. })));
+        for light in [&sudden, &smooth, &stepped] {
+            assert_eq!((light.hue(), light.sat(), light.bright()), (120, 50, 80));
+        }
+        assert_eq!(sudden.color_mode(), &ColorMode::Hsv);
+        assert_eq!(stepped.color_mode(), &ColorMode::Hsv);
+        assert_eq!(smooth.color_mode(), &ColorMode::Color);
+        assert_eq!(smooth.rgb(), &Rgb::from_hsv(120, 50));
+        let methods = |light: &Light| -> Vec<String> { sent_reqs(light).into_iter().map(|r| r.method).collect() };
+        assert_eq!(methods(&sudden), ["set_scene"]);
+        assert_eq!(methods(&smooth), ["start_cf"]);
+        let flow = format!("500,1,{},80", Rgb::from_hsv(120, 50).get_num());
+        assert_eq!(sent_reqs(&smooth)[0].params, vec![json!(1), json!(1), json!(flow)]);
+        assert_eq!(methods(&stepped), ["set_hsv", "set_bright"]);
+        Ok(())
     }
 
     #[test]
-    fn get_correct_support() -> anyhow::Result<()> {
+    fn match_out_of_order_responses() -> anyhow::Result<()> {
         // given
-        let map = get_map();
-        let expected_fields: HashSet<String> = map.get("support").unwrap().split_whitespace().map(|s| s.to_string()).collect();
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13494");
+        map.insert("support", "set_power");
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13494))?;
+        let bulb = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                ids.push(serde_json::from_str::<Req>(&line).unwrap().id);
+            }
+            // respond in reverse, with an id that merely contains the first one in between
+            write!(writer, "{{\"id\":{},\"result\":[\"second\"]}}\r\n", ids[1]).unwrap();
+            write!(writer, "{{\"id\":{}0,\"result\":[\"other\"]}}\r\n", ids[0]).unwrap();
+            write!(writer, "{{\"id\":{},\"result\":[\"first\"]}}\r\n", ids[0]).unwrap();
+        });
+        let mut light = Light::from_fields(&map)?;
+        let first = Req::with_id(1, "set_power".to_string(), vec![json!("on"), json!("sudden"), json!(0)]);
+        let second = Req::with_id(2, "set_power".to_string(), vec![json!("off"), json!("sudden"), json!(0)]);
 
         // when
-        let light = Light::from_fields(&map)?;
+        light.write_req(&first)?;
+        light.write_req(&second)?;
+        let first_result = light.read_result(first.id)?;
+        let second_result = light.read_result(second.id)?;
 
         // then
-        let support = light.support();
-        assert_eq!(&expected_fields, support);
+        assert_eq!(first_result, json!(["first"]));
+        assert_eq!(second_result, json!(["second"]));
+        drop(light);
+        bulb.join().unwrap();
         Ok(())
     }
 
     #[test]
-    fn correctly_connects() -> anyhow::Result<()> {
+    fn set_single_rgb_channels() -> anyhow::Result<()> {
         // given
-        let map = get_map();
-        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13454);
-        let _fake_listener = TcpListener::bind(addr)?;
+        let mut light = Light::mock(LightState { rgb: Rgb::new(10, 20, 30), ..mock_state() });
 
         // when
-        let mut light = Light::from_fields(&map)?;
-        light.init()?;
+        light.set_red(255, Transition::sudden())?;
+        light.set_green(0, Transition::sudden())?;
+        light.set_blue(128, Transition::sudden())?;
 
         // then
-        assert!(light.read.is_some());
-        assert!(light.write.is_some());
+        assert_eq!(light.rgb(), &Rgb::new(255, 0, 128));
+        assert_eq!(light.color_mode(), &ColorMode::Color);
+        let reqs = sent_reqs(&light);
+        let colors: Vec<Value> = reqs.iter().map(|r| r.params[0].clone()).collect();
+        assert_eq!(colors, vec![
+            json!(Rgb::new(255, 20, 30).get_num()),
+            json!(Rgb::new(255, 0, 30).get_num()),
+            json!(Rgb::new(255, 0, 128).get_num()),
+        ]);
         Ok(())
     }
+
+    #[test]
+    fn reject_invalid_transition_in_setters() {
+        // given
+        let mut light = Light::mock(mock_state());
+        let too_short = Transition::Smooth { duration: Duration::from_millis(10) };
+
+        // when
+        let results = [
+            light.set_ct_abx(3500, too_short),
+            light.set_rgb(Rgb::new(255, 0, 0), too_short),
+            light.set_hsv(120, 50, too_short),
+            light.set_bright(50, too_short),
+            light.set_power(PowerStatus::Off, too_short),
+            light.adjust_bright(10, too_short),
+            light.adjust_ct(10, too_short),
+        ];
+
+        // then
+        for result in results {
+            assert!(matches!(result, Err(YeeError::InvalidValue { field_name: "duration", .. })));
+        }
+        assert!(light.sent_bytes().is_empty());
+    }
 }
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::err::YeeError;
+use crate::light::Light;
+use crate::{parse_advertisement, SEARCH_MSG};
+
+/// How often the listener searches again, for lights that don't advertise on their own.
+const SEARCH_INTERVAL: Duration = Duration::from_secs(60);
+/// How long the listener waits when there is nothing to receive.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long an advertisement without a `Cache-Control` header is valid, the interval lights advertise at.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// A change in the lights on the network seen by a [`DiscoveryListener`].
+#[derive(Debug)]
+pub enum DiscoveryEvent {
+    /// A light seen for the first time.
+    Found(Light),
+    /// A light seen before advertised again, with its current state.
+    Updated(Light),
+    /// A light seen before didn't advertise again within its [`max_age`](Light::max_age).
+    /// It is reported as found again if it comes back.
+    Lost { id: String },
+}
+
+/// Keeps discovering lights in a background thread, see
+/// [`YeeClient::listen`](crate::YeeClient::listen). Dropping it stops the thread.
+///
+/// Lights in events are not connected, and connect on their first command.
+#[derive(Debug)]
+pub struct DiscoveryListener {
+    events: Receiver<DiscoveryEvent>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DiscoveryListener {
+    /// Start listening for responses on `seeker`, and for advertisements on the multicast group if it can
+    /// be joined.
+    pub(crate) fn start(seeker: UdpSocket, multicast_addr: SocketAddrV4) -> Result<DiscoveryListener, YeeError> {
+        let bind_addr = match seeker.local_addr()? {
+            SocketAddr::V4(addr) => *addr.ip(),
+            _ => Ipv4Addr::UNSPECIFIED
+        };
+        // another program may already listen on the multicast port, then only responses are seen
+        let notify = UdpSocket::bind(SocketAddrV4::new(bind_addr, multicast_addr.port()))
+            .and_then(|socket| {
+                socket.join_multicast_v4(multicast_addr.ip(), &bind_addr)?;
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            })
+            .ok();
+        seeker.set_nonblocking(true)?;
+
+        let (sender, events) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || listen(seeker, notify, multicast_addr, sender, &stop))
+        };
+        Ok(DiscoveryListener { events, stop, thread: Some(thread) })
+    }
+
+    pub fn events(&self) -> &Receiver<DiscoveryEvent> {
+        &self.events
+    }
+}
+
+impl Drop for DiscoveryListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn listen(seeker: UdpSocket, notify: Option<UdpSocket>, multicast_addr: SocketAddrV4,
+          sender: Sender<DiscoveryEvent>, stop: &AtomicBool) {
+    // id -> when the light's last advertisement expires
+    let mut seen: HashMap<String, Instant> = HashMap::new();
+    let mut last_search: Option<Instant> = None;
+    while !stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        let lost: Vec<String> = seen.iter().filter(|(_, expiry)| **expiry <= now).map(|(id, _)| id.clone()).collect();
+        for id in lost {
+            seen.remove(&id);
+            if sender.send(DiscoveryEvent::Lost { id }).is_err() {
+                return;
+            }
+        }
+        if !matches!(last_search, Some(t) if t.elapsed() < SEARCH_INTERVAL) {
+            // a failed search is retried on the next interval
+            let _ = seeker.send_to(SEARCH_MSG.as_bytes(), multicast_addr);
+            last_search = Some(Instant::now());
+        }
+        let mut received = false;
+        for socket in std::iter::once(&seeker).chain(notify.iter()) {
+            let mut buf = [0u8; 1024];
+            match socket.recv_from(&mut buf) {
+                Ok((size, _)) => {
+                    received = true;
+                    let light = match parse_advertisement(&buf[..size]) {
+                        Ok(light) => light,
+                        Err(_) => continue
+                    };
+                    // any host can advertise, so a max-age too long to add up falls back to the default
+                    let expiry = light.max_age()
+                        .and_then(|max_age| Instant::now().checked_add(max_age))
+                        .unwrap_or_else(|| Instant::now() + DEFAULT_MAX_AGE);
+                    let event = if seen.insert(light.id().to_string(), expiry).is_none() {
+                        DiscoveryEvent::Found(light)
+                    } else {
+                        DiscoveryEvent::Updated(light)
+                    };
+                    if sender.send(event).is_err() {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                // e.g. the search bounced off a closed port, keep listening
+                Err(_) => received = true
+            }
+        }
+        if !received {
+            sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::fake_advertisement;
+
+    use super::*;
+
+    /// An advertisement a light at `location` sends on its own, valid for `max_age` seconds.
+    fn fake_notify(location: SocketAddrV4, max_age: &str) -> String {
+        let header = format!("NOTIFY * HTTP/1.1\r\nCache-Control: max-age={}\r\n", max_age);
+        fake_advertisement(location, "0x12345abcde").replacen("HTTP/1.1 200 OK\r\n", &header, 1)
+    }
+
+    #[test]
+    fn send_found_then_updated() -> anyhow::Result<()> {
+        // given
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41234);
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41235);
+        let seeker = UdpSocket::bind(client_addr)?;
+        let listener = DiscoveryListener::start(seeker, fake_multicast_addr)?;
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41236))?;
+        let fake_msg = |power: &str| format!("NOTIFY * HTTP/1.1\r
+Host: 239.255.255.250:1982\r
+Location: yeelight://127.0.0.1:41236\r
+NTS: ssdp:alive\r
+id: 0x12345abcde\r
+model: color\r
+fw_ver: 20\r
+support: get_prop set_power\r
+power: {}\r
+bright: 40\r
+color_mode: 2\r
+ct: 3300\r
+rgb: 2\r
+hue: 4\r
+sat: 100\r
+name: light_one\r\n", power);
+
+        // when
+        fake_light.send_to(fake_msg("on").as_bytes(), client_addr)?;
+        let found = listener.events().recv_timeout(Duration::from_secs(2))?;
+        fake_light.send_to(fake_msg("off").as_bytes(), client_addr)?;
+        let updated = listener.events().recv_timeout(Duration::from_secs(2))?;
+        drop(listener);
+
+        // then
+        match (found, updated) {
+            (DiscoveryEvent::Found(found), DiscoveryEvent::Updated(updated)) => {
+                assert_eq!(found.id(), "0x12345abcde");
+                assert_eq!(updated.power().to_string(), "off");
+            }
+            events => panic!("unexpected events: {:?}", events)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn send_lost_after_max_age() -> anyhow::Result<()> {
+        // given
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41265);
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41266);
+        let seeker = UdpSocket::bind(client_addr)?;
+        let listener = DiscoveryListener::start(seeker, fake_multicast_addr)?;
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41267))?;
+        let fake_msg = fake_notify(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41267), "1");
+
+        // when
+        fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        let found = listener.events().recv_timeout(Duration::from_secs(2))?;
+        let lost = listener.events().recv_timeout(Duration::from_secs(3))?;
+        fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        let found_again = listener.events().recv_timeout(Duration::from_secs(2))?;
+        drop(listener);
+
+        // then
+        match (found, lost, found_again) {
+            (DiscoveryEvent::Found(found), DiscoveryEvent::Lost { id }, DiscoveryEvent::Found(_)) => {
+                assert_eq!(found.max_age(), Some(Duration::from_secs(1)));
+                assert_eq!(id, "0x12345abcde");
+            }
+            events => panic!("unexpected events: {:?}", events)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn survive_huge_max_age() -> anyhow::Result<()> {
+        // given
+        let fake_multicast_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41268);
+        // listener just needs to exist, don't need to use
+        let _multicast_listener = UdpSocket::bind(fake_multicast_addr)?;
+        let client_addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41269);
+        let seeker = UdpSocket::bind(client_addr)?;
+        let listener = DiscoveryListener::start(seeker, fake_multicast_addr)?;
+
+        let fake_light = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41270))?;
+        let fake_msg = fake_notify(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 41270), "18446744073709551615");
+
+        // when
+        fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        let found = listener.events().recv_timeout(Duration::from_secs(2))?;
+        fake_light.send_to(fake_msg.as_bytes(), client_addr)?;
+        let updated = listener.events().recv_timeout(Duration::from_secs(2))?;
+        drop(listener);
+
+        // then
+        assert!(matches!(found, DiscoveryEvent::Found(_)));
+        assert!(matches!(updated, DiscoveryEvent::Updated(_)));
+        Ok(())
+    }
+}
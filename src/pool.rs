@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use crate::light::Light;
+
+/// [`Light`]s kept across discovery rounds by id, so lights found again keep their connection,
+/// see [`YeeClient::find_lights_pooled`](crate::YeeClient::find_lights_pooled).
+///
+/// Lights can also be given aliases, since their own names are often empty or shared.
+#[derive(Debug, Default)]
+pub struct LightPool {
+    lights: HashMap<String, Light>,
+    // alias -> id, kept for lights that aren't in the pool too
+    aliases: HashMap<String, String>,
+}
+
+impl LightPool {
+    pub fn new() -> LightPool {
+        LightPool { lights: HashMap::new(), aliases: HashMap::new() }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Light> {
+        self.lights.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Light> {
+        self.lights.get_mut(id)
+    }
+
+    pub fn lights(&self) -> impl Iterator<Item=&Light> {
+        self.lights.values()
+    }
+
+    pub fn lights_mut(&mut self) -> impl Iterator<Item=&mut Light> {
+        self.lights.values_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    /// Add a newly found `light`, replacing the pooled light with the same id.
+    ///
+    /// If the pooled light is connected at the same location, `light` takes over its connection.
+    pub fn insert(&mut self, mut light: Light) {
+        if let Some(old) = self.lights.remove(light.id()) {
+            if light.location() == old.location() && old.is_controllable() && !light.is_controllable() {
+                light.adopt_connection(old);
+            }
+        }
+        self.lights.insert(light.id().to_string(), light);
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Light> {
+        self.lights.remove(id)
+    }
+
+    /// Give the light with `id` the alias `alias`, replacing whichever light had it.
+    ///
+    /// The light doesn't have to be in the pool yet.
+    pub fn set_alias(&mut self, id: &str, alias: &str) {
+        self.aliases.insert(alias.to_string(), id.to_string());
+    }
+
+    /// Remove `alias`, returning the id it was given to.
+    pub fn remove_alias(&mut self, alias: &str) -> Option<String> {
+        self.aliases.remove(alias)
+    }
+
+    /// The pooled light with `alias`.
+    pub fn by_alias(&mut self, alias: &str) -> Option<&mut Light> {
+        let id = self.aliases.get(alias)?;
+        self.lights.get_mut(id)
+    }
+
+    /// Every alias and the id it was given to, e.g. to serialize and restore later with
+    /// [`restore_aliases`](Self::restore_aliases).
+    pub fn aliases(&self) -> &HashMap<String, String> {
+        &self.aliases
+    }
+
+    /// Replace every alias with `aliases`, mapping aliases to ids, as returned by [`aliases`](Self::aliases).
+    pub fn restore_aliases(&mut self, aliases: HashMap<String, String>) {
+        self.aliases = aliases;
+    }
+
+    /// Whether `light` would take over a pooled connection when inserted.
+    pub(crate) fn can_reuse(&self, light: &Light) -> bool {
+        matches!(self.lights.get(light.id()), Some(old) if old.location() == light.location() && old.is_controllable())
+    }
+
+    /// Drop the connections the lights have closed, so they connect again when next used.
+    pub(crate) fn check_connections(&mut self) {
+        for light in self.lights.values_mut() {
+            light.check_connection();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+
+    use serde_json::json;
+
+    use crate::light::tests::{fake_bulb, get_map};
+    use crate::fields::PowerStatus;
+    use crate::req::Transition;
+
+    use super::*;
+
+    #[test]
+    fn reuse_pooled_connection() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13483");
+        let bulb = fake_bulb(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13483), |_| json!(["ok"]))?;
+        let mut pool = LightPool::new();
+        let mut first = Light::from_fields(&map)?;
+        first.try_connect()?;
+        pool.insert(first);
+
+        // when
+        map.insert("power", "on");
+        pool.insert(Light::from_fields(&map)?);
+        pool.check_connections();
+        let light = pool.get_mut("0x1234").unwrap();
+        let controllable = light.is_controllable();
+        light.set_power(PowerStatus::Off, Transition::sudden())?;
+        let connections = light.metrics().connections;
+        drop(pool);
+
+        // then
+        assert!(controllable);
+        assert_eq!(connections, 1);
+        assert_eq!(bulb.join().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn drop_closed_connection() -> anyhow::Result<()> {
+        // given
+        let mut map = get_map();
+        map.insert("Location", "yeelight://127.0.0.1:13484");
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13484))?;
+        let mut light = Light::from_fields(&map)?;
+        light.try_connect()?;
+        drop(listener.accept()?);
+        let mut pool = LightPool::new();
+        pool.insert(light);
+
+        // when
+        pool.check_connections();
+
+        // then
+        assert!(!pool.get("0x1234").unwrap().is_controllable());
+        Ok(())
+    }
+
+    #[test]
+    fn find_light_by_alias() -> anyhow::Result<()> {
+        // given
+        let mut pool = LightPool::new();
+        pool.insert(Light::from_fields(&get_map())?);
+        pool.set_alias("0x1234", "kitchen");
+        pool.set_alias("0xabcd", "hallway");
+
+        // when
+        let saved = serde_json::to_string(pool.aliases())?;
+        let mut restored = LightPool::new();
+        restored.restore_aliases(serde_json::from_str(&saved)?);
+        restored.insert(Light::from_fields(&get_map())?);
+
+        // then
+        assert_eq!(pool.by_alias("kitchen").map(|light| light.id().to_string()).as_deref(), Some("0x1234"));
+        assert!(pool.by_alias("hallway").is_none());
+        assert!(restored.by_alias("kitchen").is_some());
+        assert_eq!(restored.remove_alias("hallway").as_deref(), Some("0xabcd"));
+        assert!(pool.by_alias("bedroom").is_none());
+        Ok(())
+    }
+}
@@ -6,10 +6,20 @@
 //! asdfsad
 //! # examples
 //! asdfjklasdf
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
 use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+#[cfg(any(test, feature = "serde"))]
+use serde::{Deserializer, Serializer};
+#[cfg(any(test, feature = "serde"))]
+use serde::de::Error as _;
+use serde_json::{json, Value};
+
+use crate::err::YeeError;
+use crate::fields::{Color, MOONLIGHT_CT_RANGE, Rgb};
 
 /// this is a req
 #[derive(Serialize, Deserialize, Debug)]
@@ -29,6 +39,22 @@ impl Req {
     }
 }
 
+/// Shortest smooth transition lights accept.
+pub const MIN_SMOOTH_DURATION: Duration = Duration::from_millis(30);
+
+/// Shortest step of a [`ColorFlow`] lights accept.
+pub const MIN_FLOW_STEP_DURATION: Duration = Duration::from_millis(50);
+
+/// Methods that take an effect and a duration, and so take either kind of [`Transition`].
+const EFFECT_METHODS: [&str; 10] = [
+    "set_ct_abx", "set_rgb", "set_hsv", "set_bright", "set_power",
+    "bg_set_ct_abx", "bg_set_rgb", "bg_set_hsv", "bg_set_bright", "bg_set_power",
+];
+/// Methods that only take a duration, where a sudden [`Transition`] is sent as a duration of 0.
+const DURATION_METHODS: [&str; 6] = [
+    "adjust_bright", "adjust_ct", "adjust_color", "bg_adjust_bright", "bg_adjust_ct", "bg_adjust_color",
+];
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Transition {
     Sudden,
@@ -43,13 +69,43 @@ impl Transition {
         Self::Sudden
     }
     pub fn smooth(duration: Duration) -> Option<Transition> {
-        if duration < Duration::from_millis(30) || duration.as_millis() > u64::MAX as u128 {
+        if duration < MIN_SMOOTH_DURATION || duration.as_millis() > u64::MAX as u128 {
             None
         } else {
             Some(Self::Smooth { duration })
         }
     }
 
+    /// A smooth transition over `ms` milliseconds, like [`smooth`](Self::smooth) but returning
+    /// [`YeeError::InvalidValue`] if it is shorter than [`MIN_SMOOTH_DURATION`], to use with `?`.
+    pub fn try_smooth_ms(ms: u64) -> Result<Transition, YeeError> {
+        Self::smooth(Duration::from_millis(ms))
+            .ok_or(YeeError::InvalidValue { field_name: "duration", value: ms.to_string() })
+    }
+
+    /// Rebuild a transition from the effect and duration in milliseconds the light uses, the inverse of
+    /// [`text`](Self::text) and [`value`](Self::value), e.g. to read one back from a light.
+    ///
+    /// The duration of a sudden transition is ignored. Returns [`YeeError::InvalidValue`] for an unknown effect
+    /// or a smooth transition shorter than [`MIN_SMOOTH_DURATION`].
+    pub fn from_parts(mode: &str, value_ms: u64) -> Result<Transition, YeeError> {
+        match mode {
+            "sudden" => Ok(Self::Sudden),
+            "smooth" => Self::try_smooth_ms(value_ms),
+            _ => Err(YeeError::InvalidValue { field_name: "effect", value: mode.to_string() })
+        }
+    }
+
+    /// Check a transition that may have been made without [`smooth`](Self::smooth),
+    /// returning [`YeeError::InvalidValue`] for a smooth one shorter than [`MIN_SMOOTH_DURATION`].
+    pub fn validate(&self) -> Result<(), YeeError> {
+        match self {
+            Self::Smooth { duration } if *duration < MIN_SMOOTH_DURATION =>
+                Err(YeeError::InvalidValue { field_name: "duration", value: duration.as_millis().to_string() }),
+            _ => Ok(())
+        }
+    }
+
     pub fn text(&self) -> &'static str {
         match self {
             Self::Sudden => "sudden",
@@ -64,8 +120,495 @@ impl Transition {
             Self::Smooth { duration } => duration.as_millis() as u64
         }
     }
+
+    /// Whether the light accepts this transition for `method`, e.g. to disable invalid choices in a UI.
+    ///
+    /// Methods that don't take a transition only accept [`Sudden`](Self::Sudden).
+    pub fn is_valid_for(&self, method: &str) -> bool {
+        match self {
+            Self::Sudden => true,
+            Self::Smooth { duration } => *duration >= MIN_SMOOTH_DURATION
+                && (EFFECT_METHODS.contains(&method) || DURATION_METHODS.contains(&method))
+        }
+    }
+}
+
+/// Formats as `sudden` or `smooth:<milliseconds>`, e.g. `smooth:500`.
+impl Display for Transition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sudden => write!(f, "sudden"),
+            Self::Smooth { duration } => write!(f, "smooth:{}", duration.as_millis())
+        }
+    }
+}
+
+/// Parses the format of `Display`, rejecting smooth transitions shorter than 30ms.
+impl FromStr for Transition {
+    type Err = YeeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || YeeError::ParseFieldFailed { field_name: "transition", source: None };
+        match s.split_once(':') {
+            None if s == "sudden" => Ok(Self::Sudden),
+            Some(("smooth", ms)) => {
+                let ms = ms.parse::<u64>()
+                    .map_err(|e| YeeError::ParseFieldFailed { field_name: "transition", source: Some(e) })?;
+                Self::smooth(Duration::from_millis(ms)).ok_or_else(invalid)
+            }
+            _ => Err(invalid())
+        }
+    }
+}
+
+#[cfg(any(test, feature = "serde"))]
+impl Serialize for Transition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(any(test, feature = "serde"))]
+impl<'de> Deserialize<'de> for Transition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+/// The way through color space of a fade between two colors, see
+/// [`Light::set_rgb_path`](crate::Light::set_rgb_path).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ColorPath {
+    /// The light's own smooth transition, which may take an unexpected way between very different colors.
+    Direct,
+    /// Sweep the hue the short way around the color wheel, in steps sent by this library.
+    ViaHsv,
+}
+
+/// How long the step into the final state of [`FlowEnd::SetColor`] takes.
+const SET_COLOR_STEP_MS: u32 = 1000;
+
+/// The largest `count` of a flow ending with [`FlowEnd::SetColor`], whose steps are written out `count` times.
+///
+/// Not a limit of the light, just keeps the expression from growing without bound.
+pub const MAX_SET_COLOR_COUNT: u32 = 1024;
+
+/// What the light does once a [`ColorFlow`] is done.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlowEnd {
+    /// Go back to the state before the flow started.
+    Recover,
+    /// Stay at the last step of the flow.
+    Stay,
+    /// Turn the light off.
+    TurnOff,
+    /// Settle to a color at a brightness of 1-100, which needs a flow with a count of 1 to [`MAX_SET_COLOR_COUNT`].
+    SetColor(Color, u8),
+}
+
+impl FlowEnd {
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Recover => 0,
+            // the final color is the last step of the flow
+            Self::Stay | Self::SetColor(..) => 1,
+            Self::TurnOff => 2
+        }
+    }
+
+    /// The step appended to the flow to end in the final color, if any.
+    fn final_step(&self) -> Option<FlowTuple> {
+        match *self {
+            Self::SetColor(Color::Rgb(rgb), bright) => Some(FlowTuple::rgb(SET_COLOR_STEP_MS, rgb, bright as i8)),
+            Self::SetColor(Color::Ct(ct), bright) => Some(FlowTuple::ct(SET_COLOR_STEP_MS, ct, bright as i8)),
+            // flows only take rgb and color temperature steps
+            Self::SetColor(Color::Hsv { hue, sat }, bright) =>
+                Some(FlowTuple::rgb(SET_COLOR_STEP_MS, Rgb::from_hsv(hue, sat), bright as i8)),
+            _ => None
+        }
+    }
+
+    fn validate(&self) -> Result<(), YeeError> {
+        match *self {
+            Self::SetColor(color, bright) => validate_color(color, bright),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Kind of timer started by `cron_add`.
+///
+/// Only [`PowerOff`](Self::PowerOff) is in the spec so far, more may be added as lights support them.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CronType {
+    /// Turn the light off when the timer fires.
+    PowerOff,
+}
+
+impl CronType {
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::PowerOff => 0
+        }
+    }
+}
+
+impl TryFrom<u8> for CronType {
+    type Error = YeeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::PowerOff),
+            _ => Err(YeeError::InvalidValue { field_name: "cron_type", value: value.to_string() })
+        }
+    }
+}
+
+/// What a [`FlowTuple`] changes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlowMode {
+    Color,
+    ColorTemperature,
+    Sleep,
+}
+
+impl FlowMode {
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Color => 1,
+            Self::ColorTemperature => 2,
+            Self::Sleep => 7
+        }
+    }
+}
+
+/// A single step of a [`ColorFlow`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FlowTuple {
+    // minimum 50ms
+    pub duration_ms: u32,
+    pub mode: FlowMode,
+    // rgb number, color temperature, or ignored for sleep
+    pub value: u32,
+    // 1-100, or -1 to keep the current brightness
+    pub brightness: i8,
+}
+
+impl FlowTuple {
+    pub fn rgb(duration_ms: u32, rgb: Rgb, brightness: i8) -> FlowTuple {
+        FlowTuple { duration_ms, mode: FlowMode::Color, value: rgb.get_num(), brightness }
+    }
+
+    pub fn ct(duration_ms: u32, ct: u16, brightness: i8) -> FlowTuple {
+        FlowTuple { duration_ms, mode: FlowMode::ColorTemperature, value: ct as u32, brightness }
+    }
+
+    pub fn sleep(duration_ms: u32) -> FlowTuple {
+        FlowTuple { duration_ms, mode: FlowMode::Sleep, value: 0, brightness: 0 }
+    }
+
+    fn validate(&self) -> Result<(), YeeError> {
+        if (self.duration_ms as u128) < MIN_FLOW_STEP_DURATION.as_millis() {
+            return Err(YeeError::InvalidValue { field_name: "duration", value: self.duration_ms.to_string() });
+        }
+        match self.mode {
+            FlowMode::Sleep => Ok(()),
+            _ if self.brightness != -1 && !(1..=100).contains(&self.brightness) =>
+                Err(YeeError::InvalidValue { field_name: "bright", value: self.brightness.to_string() }),
+            FlowMode::Color if self.value > 0xFFFFFF =>
+                Err(YeeError::InvalidValue { field_name: "rgb", value: self.value.to_string() }),
+            FlowMode::ColorTemperature
+                if !matches!(u16::try_from(self.value), Ok(ct) if MOONLIGHT_CT_RANGE.contains(&ct)) =>
+                Err(YeeError::InvalidValue { field_name: "ct", value: self.value.to_string() }),
+            _ => Ok(())
+        }
+    }
+}
+
+/// A sequence of steps the light runs through on its own, see `start_cf` in the spec.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ColorFlow {
+    /// Total number of steps to run before ending, 0 to run forever.
+    pub count: u32,
+    pub end: FlowEnd,
+    pub tuples: Vec<FlowTuple>,
+}
+
+impl ColorFlow {
+    pub fn new(count: u32, end: FlowEnd, tuples: Vec<FlowTuple>) -> ColorFlow {
+        ColorFlow { count, end, tuples }
+    }
+
+    pub fn builder() -> ColorFlowBuilder {
+        ColorFlowBuilder::new()
+    }
+
+    pub fn validate(&self) -> Result<(), YeeError> {
+        if self.tuples.is_empty() {
+            return Err(YeeError::InvalidValue { field_name: "flow_expression", value: String::new() });
+        }
+        // a flow running forever never gets to the final color, and the steps are repeated up to count
+        if self.end.final_step().is_some() && !(1..=MAX_SET_COLOR_COUNT).contains(&self.count) {
+            return Err(YeeError::InvalidValue { field_name: "count", value: self.count.to_string() });
+        }
+        self.end.validate()?;
+        self.tuples.iter().try_for_each(FlowTuple::validate)
+    }
+
+    /// The params of `start_cf`, also used by `bg_start_cf` and flow scenes.
+    pub(crate) fn params(&self) -> Vec<Value> {
+        vec![json!(self.count_value()), json!(self.end.value()), json!(self.expression())]
+    }
+
+    /// The count as sent to the light, which includes the final step of [`FlowEnd::SetColor`].
+    pub fn count_value(&self) -> u32 {
+        match self.end.final_step() {
+            Some(_) => self.count + 1,
+            None => self.count
+        }
+    }
+
+    /// The flow expression as sent to the light.
+    ///
+    /// With [`FlowEnd::SetColor`] the steps are written out `count` times over, followed by the final step.
+    pub fn expression(&self) -> String {
+        let steps: Vec<FlowTuple> = match self.end.final_step() {
+            Some(last) => self.tuples.iter().copied()
+                .cycle()
+                .take(self.count as usize)
+                .chain(std::iter::once(last))
+                .collect(),
+            None => self.tuples.clone()
+        };
+        steps.iter()
+            .map(|t| format!("{},{},{},{}", t.duration_ms, t.mode.value(), t.value, t.brightness))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+/// Builds a [`ColorFlow`] step by step, with durations instead of the milliseconds of [`FlowTuple`].
+///
+/// Runs forever and recovers by default.
+#[derive(Debug, Clone)]
+pub struct ColorFlowBuilder {
+    count: u32,
+    end: FlowEnd,
+    // the durations of the tuples are filled in by `build`
+    steps: Vec<(Duration, FlowTuple)>,
+}
+
+impl ColorFlowBuilder {
+    pub fn new() -> ColorFlowBuilder {
+        ColorFlowBuilder { count: 0, end: FlowEnd::Recover, steps: Vec::new() }
+    }
+
+    /// Change to `rgb` at a brightness of 1-100, or -1 to keep the current brightness, over `duration`.
+    pub fn rgb_step(mut self, rgb: Rgb, duration: Duration, brightness: i8) -> ColorFlowBuilder {
+        self.steps.push((duration, FlowTuple::rgb(0, rgb, brightness)));
+        self
+    }
+
+    /// Change to the color temperature `ct` at a brightness of 1-100, or -1 to keep the current brightness,
+    /// over `duration`.
+    pub fn ct_step(mut self, ct: u16, duration: Duration, brightness: i8) -> ColorFlowBuilder {
+        self.steps.push((duration, FlowTuple::ct(0, ct, brightness)));
+        self
+    }
+
+    /// Stay at the previous step for `duration`.
+    pub fn sleep_step(mut self, duration: Duration) -> ColorFlowBuilder {
+        self.steps.push((duration, FlowTuple::sleep(0)));
+        self
+    }
+
+    /// Total number of steps to run before ending, 0 to run forever.
+    pub fn count(mut self, count: u32) -> ColorFlowBuilder {
+        self.count = count;
+        self
+    }
+
+    pub fn end(mut self, end: FlowEnd) -> ColorFlowBuilder {
+        self.end = end;
+        self
+    }
+
+    /// Make the flow, or [`YeeError::InvalidValue`] if it isn't valid,
+    /// e.g. has a step shorter than [`MIN_FLOW_STEP_DURATION`].
+    pub fn build(self) -> Result<ColorFlow, YeeError> {
+        let tuples = self.steps.into_iter()
+            .map(|(duration, tuple)| {
+                let invalid = || YeeError::InvalidValue { field_name: "duration", value: format!("{:?}", duration) };
+                if duration < MIN_FLOW_STEP_DURATION {
+                    return Err(invalid());
+                }
+                let duration_ms = u32::try_from(duration.as_millis()).map_err(|_| invalid())?;
+                Ok(FlowTuple { duration_ms, ..tuple })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let flow = ColorFlow::new(self.count, self.end, tuples);
+        flow.validate()?;
+        Ok(flow)
+    }
+}
+
+impl Default for ColorFlowBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Check a color and a brightness of 1-100 the light is set to in one go, e.g. by a scene.
+fn validate_color(color: Color, bright: u8) -> Result<(), YeeError> {
+    match color {
+        _ if !(1..=100).contains(&bright) => Err(YeeError::InvalidValue { field_name: "bright", value: bright.to_string() }),
+        Color::Ct(ct) if !MOONLIGHT_CT_RANGE.contains(&ct) =>
+            Err(YeeError::InvalidValue { field_name: "ct", value: ct.to_string() }),
+        Color::Hsv { hue, .. } if hue > 359 => Err(YeeError::InvalidValue { field_name: "hue", value: hue.to_string() }),
+        Color::Hsv { sat, .. } if sat > 100 => Err(YeeError::InvalidValue { field_name: "sat", value: sat.to_string() }),
+        _ => Ok(())
+    }
+}
+
+/// A state the light jumps to directly, turning on if it was off, see `set_scene` in the spec.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Scene {
+    /// A color at a brightness of 1-100.
+    Color { color: Color, bright: u8 },
+    Flow(ColorFlow),
+    /// Turn on at a brightness of 1-100, then off after `minutes`.
+    AutoDelayOff { bright: u8, minutes: u32 },
+}
+
+impl Scene {
+    pub fn validate(&self) -> Result<(), YeeError> {
+        match self {
+            Self::Color { color, bright } => validate_color(*color, *bright),
+            Self::Flow(flow) => flow.validate(),
+            Self::AutoDelayOff { bright, .. } if !(1..=100).contains(bright) =>
+                Err(YeeError::InvalidValue { field_name: "bright", value: bright.to_string() }),
+            Self::AutoDelayOff { minutes: 0, .. } =>
+                Err(YeeError::InvalidValue { field_name: "minutes", value: 0.to_string() }),
+            Self::AutoDelayOff { .. } => Ok(())
+        }
+    }
+
+    /// The params of `set_scene`, also used by `bg_set_scene`.
+    pub(crate) fn params(&self) -> Vec<Value> {
+        match self {
+            Self::Color { color: Color::Rgb(rgb), bright } => vec![json!("color"), json!(rgb.get_num()), json!(bright)],
+            Self::Color { color: Color::Ct(ct), bright } => vec![json!("ct"), json!(ct), json!(bright)],
+            Self::Color { color: Color::Hsv { hue, sat }, bright } => vec![json!("hsv"), json!(hue), json!(sat), json!(bright)],
+            Self::Flow(flow) => std::iter::once(json!("cf")).chain(flow.params()).collect(),
+            Self::AutoDelayOff { bright, minutes } => vec![json!("auto_delay_off"), json!(bright), json!(minutes)],
+        }
+    }
+}
+
+
+/// Ready-made states for common uses, to apply with `apply_preset` without picking the values by hand.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Preset {
+    /// Neutral white at 4000K, full brightness.
+    Reading,
+    /// Warm white at 2700K, 40% brightness.
+    Relax,
+    /// Dim blue, rgb (40, 40, 160) at 15% brightness.
+    Movie,
+    /// Cool white at 6500K, full brightness.
+    Daylight,
+    /// Warm white at 2700K, lowest brightness.
+    Night,
+}
+
+impl Preset {
+    pub fn color(&self) -> Color {
+        match self {
+            Self::Reading => Color::Ct(4000),
+            Self::Relax | Self::Night => Color::Ct(2700),
+            Self::Movie => Color::Rgb(Rgb::new(40, 40, 160)),
+            Self::Daylight => Color::Ct(6500)
+        }
+    }
+
+    pub fn bright(&self) -> u8 {
+        match self {
+            Self::Reading | Self::Daylight => 100,
+            Self::Relax => 40,
+            Self::Movie => 15,
+            Self::Night => 1
+        }
+    }
+
+    /// The preset as a scene, to set it in one command.
+    pub fn scene(&self) -> Scene {
+        Scene::Color { color: self.color(), bright: self.bright() }
+    }
+}
+
+/// A routine of steps for a light to run through, e.g. loaded from a config file, see `play_scene_file`.
+///
+/// With the `serde` feature, deserializes from e.g. JSON:
+/// `{"steps": [{"color": {"ct": 2700}, "bright": 40, "transition": "smooth:1000", "delay_ms": 500}]}`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+pub struct SceneFile {
+    pub steps: Vec<SceneStep>,
+}
+
+/// A step of a [`SceneFile`], changing the color and/or brightness, then waiting for `delay_ms`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(any(test, feature = "serde"), derive(Serialize, Deserialize))]
+pub struct SceneStep {
+    #[cfg_attr(any(test, feature = "serde"), serde(default))]
+    pub color: Option<Color>,
+    /// 1-100.
+    #[cfg_attr(any(test, feature = "serde"), serde(default))]
+    pub bright: Option<u8>,
+    #[cfg_attr(any(test, feature = "serde"), serde(default = "Transition::sudden"))]
+    pub transition: Transition,
+    /// How long to wait once the transition is done, before the next step.
+    #[cfg_attr(any(test, feature = "serde"), serde(default))]
+    pub delay_ms: u64,
+}
+
+impl SceneFile {
+    /// Parse and validate a scene file written in JSON, with the `serde` feature.
+    #[cfg(any(test, feature = "serde"))]
+    pub fn from_json(json: &str) -> Result<SceneFile, YeeError> {
+        let scene: SceneFile = serde_json::from_str(json).map_err(|e| YeeError::JsonError { source: e })?;
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    /// Check every step, returning [`YeeError::InvalidStep`] with the index of the first invalid one.
+    pub fn validate(&self) -> Result<(), YeeError> {
+        self.steps.iter()
+            .enumerate()
+            .try_for_each(|(index, step)| step.validate()
+                .map_err(|e| YeeError::InvalidStep { index, source: Box::new(e) }))
+    }
 }
 
+impl SceneStep {
+    fn validate(&self) -> Result<(), YeeError> {
+        match (self.color, self.bright) {
+            (_, Some(bright)) if !(1..=100).contains(&bright) =>
+                return Err(YeeError::InvalidValue { field_name: "bright", value: bright.to_string() }),
+            // the brightness is checked above, or not changed
+            (Some(color), _) => validate_color(color, 100)?,
+            _ => {}
+        }
+        if let Transition::Smooth { duration } = self.transition {
+            if duration < MIN_SMOOTH_DURATION {
+                return Err(YeeError::InvalidValue { field_name: "transition", value: self.transition.to_string() });
+            }
+        }
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -73,4 +616,331 @@ mod tests {
 
     #[test]
     fn test() {}
-}
\ No newline at end of file
+
+    #[test]
+    fn reject_short_smooth_transition() {
+        // given
+        let too_short = Duration::from_millis(29);
+        let shortest = Duration::from_millis(30);
+
+        // when
+        let rejected = Transition::smooth(too_short);
+        let accepted = Transition::smooth(shortest);
+
+        // then
+        assert!(rejected.is_none());
+        assert_eq!(accepted, Some(Transition::Smooth { duration: shortest }));
+    }
+
+    #[test]
+    fn validate_transitions_made_directly() {
+        // given
+        let too_short = Transition::Smooth { duration: Duration::from_millis(29) };
+        let shortest = Transition::Smooth { duration: MIN_SMOOTH_DURATION };
+
+        // then
+        assert!(too_short.validate().is_err());
+        assert!(shortest.validate().is_ok());
+        assert!(Transition::sudden().validate().is_ok());
+    }
+
+    #[test]
+    fn try_smooth_transition_in_ms() {
+        // when
+        let rejected = Transition::try_smooth_ms(29);
+        let accepted = Transition::try_smooth_ms(30);
+
+        // then
+        assert!(matches!(rejected, Err(YeeError::InvalidValue { field_name: "duration", .. })));
+        assert_eq!(accepted.ok(), Some(Transition::Smooth { duration: Duration::from_millis(30) }));
+    }
+
+    #[test]
+    fn correct_flow_expression() {
+        // given
+        let flow = ColorFlow::new(4, FlowEnd::Stay, vec![
+            FlowTuple::rgb(1000, Rgb::new(255, 0, 0), 50),
+            FlowTuple::ct(500, 2700, -1),
+            FlowTuple::sleep(50),
+        ]);
+
+        // when
+        let expression = flow.expression();
+
+        // then
+        assert!(flow.validate().is_ok());
+        assert_eq!(expression, "1000,1,16711680,50,500,2,2700,-1,50,7,0,0");
+    }
+
+    #[test]
+    fn reject_invalid_flows() {
+        // given
+        let empty = ColorFlow::new(0, FlowEnd::Recover, vec![]);
+        let too_short = ColorFlow::new(0, FlowEnd::Recover, vec![FlowTuple::sleep(49)]);
+        let too_bright = ColorFlow::new(0, FlowEnd::Recover, vec![FlowTuple::ct(50, 2700, 101)]);
+        let bad_ct = ColorFlow::new(0, FlowEnd::Recover, vec![FlowTuple::ct(50, 9000, 100)]);
+
+        // then
+        assert!(empty.validate().is_err());
+        assert!(too_short.validate().is_err());
+        assert!(too_bright.validate().is_err());
+        assert!(bad_ct.validate().is_err());
+    }
+
+    #[test]
+    fn build_flow_from_durations() -> anyhow::Result<()> {
+        // given
+        let builder = ColorFlow::builder()
+            .rgb_step(Rgb::new(255, 0, 0), Duration::from_secs(1), 50)
+            .ct_step(2700, Duration::from_millis(500), -1)
+            .sleep_step(Duration::from_millis(50))
+            .count(4)
+            .end(FlowEnd::Stay);
+
+        // when
+        let flow = builder.build()?;
+
+        // then
+        assert_eq!(flow, ColorFlow::new(4, FlowEnd::Stay, vec![
+            FlowTuple::rgb(1000, Rgb::new(255, 0, 0), 50),
+            FlowTuple::ct(500, 2700, -1),
+            FlowTuple::sleep(50),
+        ]));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_built_flows() {
+        // given
+        let empty = ColorFlow::builder();
+        let too_short = ColorFlow::builder().sleep_step(Duration::from_micros(49_999));
+        let too_long = ColorFlow::builder().sleep_step(Duration::from_secs(u64::MAX));
+        let bad_ct = ColorFlow::builder().ct_step(9000, Duration::from_secs(1), 100);
+
+        // then
+        assert!(empty.build().is_err());
+        assert!(matches!(too_short.build(), Err(YeeError::InvalidValue { field_name: "duration", .. })));
+        assert!(matches!(too_long.build(), Err(YeeError::InvalidValue { field_name: "duration", .. })));
+        assert!(bad_ct.build().is_err());
+    }
+
+    #[test]
+    fn correct_set_color_flow_expression() {
+        // given
+        let flow = ColorFlow::new(3, FlowEnd::SetColor(Color::Ct(2700), 40), vec![
+            FlowTuple::rgb(500, Rgb::new(255, 0, 0), 100),
+            FlowTuple::rgb(500, Rgb::new(0, 0, 255), 100),
+        ]);
+
+        // when
+        let expression = flow.expression();
+
+        // then
+        assert!(flow.validate().is_ok());
+        assert_eq!(flow.count_value(), 4);
+        assert_eq!(flow.end.value(), 1);
+        assert_eq!(expression, "500,1,16711680,100,500,1,255,100,500,1,16711680,100,1000,2,2700,40");
+    }
+
+    #[test]
+    fn reject_invalid_set_color_flows() {
+        // given
+        let steps = vec![FlowTuple::sleep(50)];
+        let forever = ColorFlow::new(0, FlowEnd::SetColor(Color::Ct(2700), 40), steps.clone());
+        let too_dark = ColorFlow::new(1, FlowEnd::SetColor(Color::Ct(2700), 0), steps.clone());
+        let bad_ct = ColorFlow::new(1, FlowEnd::SetColor(Color::Ct(9000), 40), steps.clone());
+        let bad_hue = ColorFlow::new(1, FlowEnd::SetColor(Color::Hsv { hue: 360, sat: 100 }, 40), steps.clone());
+        let too_long = ColorFlow::new(MAX_SET_COLOR_COUNT + 1, FlowEnd::SetColor(Color::Ct(2700), 40), steps.clone());
+        let longest = ColorFlow::new(MAX_SET_COLOR_COUNT, FlowEnd::SetColor(Color::Ct(2700), 40), steps);
+
+        // then
+        assert!(forever.validate().is_err());
+        assert!(too_dark.validate().is_err());
+        assert!(bad_ct.validate().is_err());
+        assert!(bad_hue.validate().is_err());
+        assert!(too_long.validate().is_err());
+        assert!(longest.validate().is_ok());
+    }
+
+    #[test]
+    fn correct_scene_params() {
+        // given
+        let color = Scene::Color { color: Color::Hsv { hue: 120, sat: 50 }, bright: 30 };
+        let flow = Scene::Flow(ColorFlow::new(2, FlowEnd::TurnOff, vec![FlowTuple::ct(500, 2700, 10)]));
+        let delay_off = Scene::AutoDelayOff { bright: 50, minutes: 5 };
+
+        // when
+        let color = color.params();
+        let flow = flow.params();
+        let delay_off = delay_off.params();
+
+        // then
+        assert_eq!(color, vec![json!("hsv"), json!(120), json!(50), json!(30)]);
+        assert_eq!(flow, vec![json!("cf"), json!(2), json!(2), json!("500,2,2700,10")]);
+        assert_eq!(delay_off, vec![json!("auto_delay_off"), json!(50), json!(5)]);
+    }
+
+    #[test]
+    fn reject_invalid_scenes() {
+        // given
+        let too_dark = Scene::Color { color: Color::Rgb(Rgb::new(255, 0, 0)), bright: 0 };
+        let bad_sat = Scene::Color { color: Color::Hsv { hue: 0, sat: 101 }, bright: 50 };
+        let empty_flow = Scene::Flow(ColorFlow::new(0, FlowEnd::Stay, vec![]));
+        let no_delay = Scene::AutoDelayOff { bright: 50, minutes: 0 };
+
+        // then
+        assert!(too_dark.validate().is_err());
+        assert!(bad_sat.validate().is_err());
+        assert!(empty_flow.validate().is_err());
+        assert!(no_delay.validate().is_err());
+    }
+
+    #[test]
+    fn presets_are_valid_scenes() {
+        // given
+        let presets = [Preset::Reading, Preset::Relax, Preset::Movie, Preset::Daylight, Preset::Night];
+
+        // then
+        for preset in presets.iter() {
+            assert!(preset.scene().validate().is_ok(), "{:?}", preset);
+        }
+        assert_eq!(Preset::Relax.scene().params(), vec![json!("ct"), json!(2700), json!(40)]);
+    }
+
+    #[test]
+    fn cron_type_from_value() {
+        // given
+        let power_off = 0;
+        let unknown = 1;
+
+        // when
+        let power_off = CronType::try_from(power_off);
+        let unknown = CronType::try_from(unknown);
+
+        // then
+        assert_eq!(power_off.unwrap(), CronType::PowerOff);
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn transition_valid_per_method() {
+        // given
+        let sudden = Transition::sudden();
+        let smooth = Transition::smooth(Duration::from_millis(500)).unwrap();
+        let too_short = Transition::Smooth { duration: Duration::from_millis(10) };
+
+        // then
+        assert!(sudden.is_valid_for("set_rgb"));
+        assert!(sudden.is_valid_for("toggle"));
+        assert!(smooth.is_valid_for("set_rgb"));
+        assert!(smooth.is_valid_for("bg_set_power"));
+        assert!(smooth.is_valid_for("adjust_ct"));
+        assert!(!smooth.is_valid_for("toggle"));
+        assert!(!smooth.is_valid_for("set_scene"));
+        assert!(!too_short.is_valid_for("set_rgb"));
+    }
+
+    #[test]
+    fn transition_from_parts() {
+        // given
+        let smooth = Transition::smooth(Duration::from_millis(500)).unwrap();
+
+        // when
+        let sudden_parts = Transition::from_parts("sudden", 0);
+        let smooth_parts = Transition::from_parts(smooth.text(), smooth.value());
+        let too_short = Transition::from_parts("smooth", 29);
+        let unknown = Transition::from_parts("gradual", 500);
+
+        // then
+        assert_eq!(sudden_parts, Ok(Transition::Sudden));
+        assert_eq!(smooth_parts, Ok(smooth));
+        assert_eq!(too_short, Err(YeeError::InvalidValue { field_name: "duration", value: "29".to_string() }));
+        assert_eq!(unknown, Err(YeeError::InvalidValue { field_name: "effect", value: "gradual".to_string() }));
+    }
+
+    #[test]
+    fn load_scene_file() -> anyhow::Result<()> {
+        // given
+        let json = r#"{"steps": [
+            {"color": {"ct": 2700}, "bright": 40, "transition": "smooth:1000", "delay_ms": 500},
+            {"color": {"rgb": {"red": 255, "green": 0, "blue": 0}}},
+            {"bright": 100}
+        ]}"#;
+
+        // when
+        let scene = SceneFile::from_json(json)?;
+
+        // then
+        assert_eq!(scene.steps.len(), 3);
+        assert_eq!(scene.steps[0].color, Some(Color::Ct(2700)));
+        assert_eq!(scene.steps[0].transition, Transition::Smooth { duration: Duration::from_millis(1000) });
+        assert_eq!(scene.steps[1].color, Some(Color::Rgb(Rgb::new(255, 0, 0))));
+        assert_eq!(scene.steps[1].transition, Transition::Sudden);
+        assert_eq!(scene.steps[2].bright, Some(100));
+        Ok(())
+    }
+
+    #[test]
+    fn report_invalid_scene_step() {
+        // given
+        let bad_bright = r#"{"steps": [{"bright": 50}, {"bright": 0}]}"#;
+        let bad_hue = r#"{"steps": [{"color": {"hsv": {"hue": 400, "sat": 50}}}]}"#;
+        let malformed = r#"{"steps": [{"color": "red"}]}"#;
+
+        // when
+        let bad_bright = SceneFile::from_json(bad_bright);
+        let bad_hue = SceneFile::from_json(bad_hue);
+        let malformed = SceneFile::from_json(malformed);
+
+        // then
+        assert!(matches!(bad_bright, Err(YeeError::InvalidStep { index: 1, .. })));
+        assert!(matches!(bad_hue, Err(YeeError::InvalidStep { index: 0, .. })));
+        assert!(matches!(malformed, Err(YeeError::JsonError { .. })));
+    }
+
+    #[test]
+    fn correct_parse_transition() -> anyhow::Result<()> {
+        // given
+        let sudden = "sudden";
+        let smooth = "smooth:500";
+
+        // when
+        let parsed_sudden = sudden.parse::<Transition>()?;
+        let parsed_smooth = smooth.parse::<Transition>()?;
+
+        // then
+        assert_eq!(parsed_sudden, Transition::Sudden);
+        assert_eq!(parsed_smooth, Transition::Smooth { duration: Duration::from_millis(500) });
+        assert_eq!(parsed_sudden.to_string(), sudden);
+        assert_eq!(parsed_smooth.to_string(), smooth);
+        Ok(())
+    }
+
+    #[test]
+    fn incorrect_parse_transition() {
+        // given
+        let incorrect = ["smooth", "smooth:", "smooth:29", "smooth:abc", "sudden:30", "fast"];
+
+        // when
+        let parsed: Vec<Result<Transition, YeeError>> = incorrect.iter().map(|s| s.parse()).collect();
+
+        // then
+        assert!(parsed.iter().all(|p| p.is_err()));
+    }
+
+    #[test]
+    fn transition_serde_round_trip() -> anyhow::Result<()> {
+        // given
+        let transitions = vec![Transition::sudden(), Transition::smooth(Duration::from_millis(400)).unwrap()];
+
+        // when
+        let json = serde_json::to_string(&transitions)?;
+        let parsed: Vec<Transition> = serde_json::from_str(&json)?;
+
+        // then
+        assert_eq!(json, r#"["sudden","smooth:400"]"#);
+        assert_eq!(parsed, transitions);
+        assert!(serde_json::from_str::<Transition>(r#""smooth:10""#).is_err());
+        Ok(())
+    }
+}
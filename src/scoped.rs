@@ -0,0 +1,83 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::light::{Light, LightState};
+use crate::req::Transition;
+
+/// Borrows a [`Light`] and puts it back in the state it was in once dropped, e.g. for a program that takes
+/// over the lights for a while.
+///
+/// The state saved is the cached one, so [`refresh`](Light::refresh) first if the light may have been
+/// changed by another client. Restoring is best effort: `Drop` can't return errors, so failures are logged
+/// with the `log` crate instead.
+#[derive(Debug)]
+pub struct ScopedLightState<'a> {
+    light: &'a mut Light,
+    saved: LightState,
+}
+
+impl<'a> ScopedLightState<'a> {
+    pub fn new(light: &'a mut Light) -> ScopedLightState<'a> {
+        let saved = light.state();
+        ScopedLightState { light, saved }
+    }
+
+    /// The state the light is restored to.
+    pub fn saved(&self) -> &LightState {
+        &self.saved
+    }
+}
+
+impl Deref for ScopedLightState<'_> {
+    type Target = Light;
+
+    fn deref(&self) -> &Light {
+        self.light
+    }
+}
+
+impl DerefMut for ScopedLightState<'_> {
+    fn deref_mut(&mut self) -> &mut Light {
+        self.light
+    }
+}
+
+impl Drop for ScopedLightState<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.light.apply_state_diff(&self.saved, Transition::sudden()) {
+            log::warn!("failed to restore the state of light {}: {}", self.light.id(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::fields::Rgb;
+    use crate::light::tests::{mock_state, sent_reqs};
+
+    use super::*;
+
+    #[test]
+    fn restore_state_on_drop() -> anyhow::Result<()> {
+        // given
+        let state = mock_state();
+        let mut light = Light::mock(state.clone());
+
+        // when
+        {
+            let mut scoped = ScopedLightState::new(&mut light);
+            scoped.set_bright(80, Transition::sudden())?;
+            scoped.set_rgb(Rgb::new(255, 0, 0), Transition::sudden())?;
+        }
+
+        // then
+        // only the color of the saved mode is restored
+        assert_eq!(light.state(), LightState { rgb: Rgb::new(255, 0, 0), ..state });
+        let reqs = sent_reqs(&light);
+        let methods: Vec<&str> = reqs.iter().map(|r| r.method.as_str()).collect();
+        assert_eq!(methods, ["set_bright", "set_rgb", "set_bright", "set_ct_abx"]);
+        assert_eq!(reqs[3].params[0], json!(3000));
+        Ok(())
+    }
+}